@@ -1,120 +1,382 @@
-//! This crate implements provides traits and implementations for algorithms assigning coordinates to nodes.
-//! Adapted from the Vivaldi Protocol: https://pdos.csail.mit.edu/papers/vivaldi:sigcomm/paper.pdf
+//! This module provides the trait and implementations for algorithms assigning coordinates to nodes.
+//! `NCSystem` is generic over `NodeCoordinator`, so each node can choose how it calculates its own coordinates.
 
-use std::ops::{Sub};
+use std::{time::{Duration, Instant}, marker::PhantomData};
 
-use num::{Float};
+use bevy_ecs::system::Resource;
+use bytecheck::CheckBytes;
+use num::Float;
+use rkyv::{Archive, Serialize, Deserialize};
 
-/// Trait for a CoordinateSystem as defined in the Vivaldi paper
-pub trait CoordinateType: Clone + Default {
-    type Scalar: Float;
-    fn subtract(self, other: &Self) -> Self;
-    fn add(self, other: &Self) -> Self;
-    fn length(&self) -> Self::Scalar;
-    fn multiply(self, scalar: Self::Scalar) -> Self;
-    fn distance(&self, other: &Self) -> Self::Scalar {
-        let mut out = self.clone();
-        out.subtract(other);
-        out.length()
-    }
-    fn normalized(mut self) -> Self {
-        self.multiply(self.length().recip());
-        self
-    }
+use crate::{Coordinates, LatencyMetrics, NCConfig, NetworkCoord, COORDINATE_DIMENSIONS};
+
+/// Algorithm used to calculate a set of `Coordinates`. Sent along with them, coordinates calculated by a different algorithm than our own are ignored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum CoordinateAlgorithm {
+	/// Decentralized matrix factorization, see `DmfCoordinator`.
+	#[default]
+	Dmf,
+	/// Euclidean coordinates with a height, see `VivaldiCoordinator`.
+	Vivaldi,
+	/// Weighted non-negative matrix factorization, see `PhoenixCoordinator`.
+	Phoenix,
 }
-/// Trait for a Coordinator.
-pub trait NodeCoordinator: Default {
-    type Coord;
-    type RemoteState;
-    /// Update the system 
-    fn update(&mut self, remotes: impl Iterator<Item = &mut Self::RemoteState>);
-
-    fn own_coord(&self) -> Self::Coord;
+
+/// Measurement to a remote used to update own coordinates.
+pub struct RemoteSample<'a> {
+	pub coords: &'a Coordinates,
+	pub metrics: &'a LatencyMetrics,
+	/// How much this remote contributes to the update, see `CoordinateWeight`. Used by DMF and Phoenix, Vivaldi uses each measurement once and weighs it by error itself.
+	pub weight: f64,
+}
+impl<'a> RemoteSample<'a> {
+	/// Latest measured round-trip time in milliseconds.
+	pub fn rtt(&self) -> f64 {
+		Duration::from_micros(self.metrics.latest_latency()).as_secs_f64() * 1000.0
+	}
+	/// Whether the remote was measured after the previous update at `last_update`, coordinators only count new measurements in `Coordinates::samples`.
+	pub fn is_new(&self, last_update: Option<Instant>) -> bool {
+		last_update.map_or(true, |last|self.metrics.last_update() > last)
+	}
 }
 
-/// Coordinate with format Distance-Distance-Height using f64 as a scalar
-#[derive(Clone)]
-struct CoordDDHf64 {
-    position: nalgebra::Vector2<f64>,
-    height: f64,
+/// Mean relative error of the latencies `coords` predicts to the remotes, capped at 1.0. Used as the error estimate of coordinators that don't track one themselves.
+pub fn prediction_error<'a>(coords: &Coordinates, remotes: impl Iterator<Item = &'a RemoteSample<'a>>) -> f64 {
+	let (sum, count) = remotes.filter(|remote|remote.rtt() > 0.0).fold((0.0, 0), |(sum, count), remote| {
+		let (outgoing, incoming) = coords.predict_latencies(remote.coords);
+		let rtt = remote.rtt();
+		let error = ((outgoing as f64 / 1000.0 - rtt).abs() + (incoming as f64 / 1000.0 - rtt).abs()) / (2.0 * rtt);
+		(sum + error, count + 1)
+	});
+	if count == 0 { return coords.error }
+	(sum / count as f64).min(1.0)
 }
-impl CoordinateType for CoordDDHf64 {
-    type Scalar = f64;
-
-    fn subtract(&mut self, other: &Self) {
-        self.position -= other.position;
-        self.height += other.height;
-    }
-
-    fn add(&mut self, other: &Self) {
-        self.position += other.position;
-        self.height += other.height;
-    }
-
-    fn length(&self) -> Self::Scalar {
-        self.position.magnitude() + self.height
-    }
-
-    fn multiply(&mut self, scalar: Self::Scalar) {
-        self.position *= scalar;
-        self.height *= scalar;
-    }
+
+/// Algorithm that calculates a node's own coordinates from its latency measurements and remotes' coordinates.
+pub trait NodeCoordinator: Resource + Sized {
+	/// Tag sent with coordinates calculated by this algorithm.
+	const ALGORITHM: CoordinateAlgorithm;
+
+	fn new(config: &NCConfig) -> Self;
+
+	/// Update own coordinates using the measurements to remotes, returns the new coordinates or `None` if they didn't change.
+	fn update(&mut self, own: &Coordinates, remotes: &[RemoteSample]) -> Option<Coordinates>;
 }
 
-#[derive(Debug, Default)]
-struct VivaldiAdaptiveCoordinator<Coord: CoordinateType, const ERROR_CONST: f64, const MOVE_CONST: f64 = 0.25> {
-    coord: Coord,
-    error: f64,
+/// Trait for a CoordinateSystem as defined in the Vivaldi paper
+pub trait CoordinateType: Clone + Default + Send + Sync + 'static {
+	type Scalar: Float;
+	fn subtract(&mut self, other: &Self);
+	fn add(&mut self, other: &Self);
+	fn length(&self) -> Self::Scalar;
+	fn multiply(&mut self, scalar: Self::Scalar);
+	/// Random unit vector, the direction used between two nodes at the same position.
+	fn random_unit() -> Self;
+	/// Read the coordinate from shared `Coordinates`.
+	fn from_coordinates(coords: &Coordinates) -> Self;
+	/// Store the coordinate in shared `Coordinates`.
+	fn to_coordinates(&self, coords: &mut Coordinates);
+
+	fn distance(&self, other: &Self) -> Self::Scalar {
+		let mut out = self.clone();
+		out.subtract(other);
+		out.length()
+	}
+	fn normalized(mut self) -> Self {
+		let length = self.length();
+		if length <= Self::Scalar::epsilon() { return Self::random_unit() }
+		self.multiply(length.recip());
+		self
+	}
+}
+
+/// Coordinate with format Distance-Distance-Height using f64 as a scalar.
+/// Stored in `Coordinates` as the first two dimensions of `out_coord` and the first dimension of `in_coord`.
+#[derive(Debug, Clone, Default)]
+pub struct CoordDDHf64 {
+	pub position: nalgebra::Vector2<f64>,
+	/// Models the latency of a node's access link, never negative.
+	pub height: f64,
+}
+impl CoordinateType for CoordDDHf64 {
+	type Scalar = f64;
+
+	// Heights add up, the path between two nodes goes through both access links
+	fn subtract(&mut self, other: &Self) {
+		self.position -= other.position;
+		self.height += other.height;
+	}
+
+	fn add(&mut self, other: &Self) {
+		self.position += other.position;
+		self.height = (self.height + other.height).max(0.0);
+	}
+
+	fn length(&self) -> Self::Scalar {
+		self.position.magnitude() + self.height
+	}
+
+	fn multiply(&mut self, scalar: Self::Scalar) {
+		self.position *= scalar;
+		self.height *= scalar;
+	}
+
+	fn random_unit() -> Self {
+		let angle = rand::random::<f64>() * std::f64::consts::TAU;
+		Self { position: nalgebra::Vector2::new(angle.cos(), angle.sin()), height: 0.0 }
+	}
+
+	fn from_coordinates(coords: &Coordinates) -> Self {
+		Self {
+			position: nalgebra::Vector2::new(coords.out_coord[0], coords.out_coord[1]),
+			height: coords.in_coord[0].max(0.0),
+		}
+	}
+
+	fn to_coordinates(&self, coords: &mut Coordinates) {
+		coords.out_coord = NetworkCoord::zeros();
+		coords.in_coord = NetworkCoord::zeros();
+		coords.out_coord[0] = self.position.x;
+		coords.out_coord[1] = self.position.y;
+		coords.in_coord[0] = self.height;
+	}
 }
-struct VivaldiAdaptiveCoordinatorRemoteState<Coord: CoordinateType> {
-    coord: Coord,
-    error: f64,
-    measured_distance: f64,
+
+/// Vivaldi with adaptive timestep, adapted from the Vivaldi Protocol: https://pdos.csail.mit.edu/papers/vivaldi:sigcomm/paper.pdf
+/// The coordinate and this node's error estimate are kept in the shared `Coordinates`, so remotes can weigh our coordinate by how accurate it is.
+#[derive(Debug, Resource)]
+pub struct VivaldiAdaptiveCoordinator<Coord: CoordinateType<Scalar = f64> = CoordDDHf64> {
+	/// c_e, how fast the error estimate adapts to new samples.
+	error_const: f64,
+	/// c_c, fraction of the way towards the position a sample suggests that a node with a high error moves.
+	move_const: f64,
+	/// Only samples measured after this are used in the next update.
+	last_update: Option<Instant>,
+	_coord: PhantomData<Coord>,
 }
-impl<Coord: CoordinateType, const ADAPT_CONST: f64, const ERROR_CONST: f64> NodeCoordinator for VivaldiAdaptiveCoordinator<Coord, ERROR_CONST, ADAPT_CONST> {
-    type Coord = Coord;
+pub type VivaldiCoordinator = VivaldiAdaptiveCoordinator<CoordDDHf64>;
+
+impl<Coord: CoordinateType<Scalar = f64>> NodeCoordinator for VivaldiAdaptiveCoordinator<Coord> {
+	const ALGORITHM: CoordinateAlgorithm = CoordinateAlgorithm::Vivaldi;
+
+	fn new(config: &NCConfig) -> Self {
+		Self {
+			error_const: config.vivaldi_error_const,
+			move_const: config.vivaldi_move_const,
+			last_update: None,
+			_coord: PhantomData,
+		}
+	}
 
-    type RemoteState = VivaldiAdaptiveCoordinatorRemoteState<Coord>;
+	fn update(&mut self, own: &Coordinates, remotes: &[RemoteSample]) -> Option<Coordinates> {
+		let mut coord = Coord::from_coordinates(own);
+		let mut error = own.error;
+		let mut samples = 0u32;
+		// Each latency measurement is used once
+		let new_samples = remotes.iter().filter(|remote|remote.is_new(self.last_update));
+		for remote in new_samples {
+			// Algorithm as found here: https://pdos.csail.mit.edu/papers/vivaldi:sigcomm/paper.pdf#page=4
+			let rtt = remote.rtt();
+			if rtt <= 0.0 { continue }
+			let remote_coord = Coord::from_coordinates(remote.coords);
 
-    fn update<'a>(&mut self, remotes: impl Iterator<Item = &'a mut Self::RemoteState>) {
-        for remote in remotes {
-            // Algorithm as found here: https://pdos.csail.mit.edu/papers/vivaldi:sigcomm/paper.pdf#page=4
+			// Coordinate space relative displacement of the remote node
+			let mut displacement = coord.clone();
+			displacement.subtract(&remote_coord); // x_i - x_j
 
-            // Coordinate space relative displacement of the remote node
-            let mut displacement = self.coord.clone().subtract(&remote.coord); // x_i - x_j
-            
-            // Estimate of distance of the remote node
-            let distance_estimate = displacement.length(); // ||x_i - x_j||
+			// Estimate of distance of the remote node
+			let distance_estimate = displacement.length(); // ||x_i - x_j||
 
-            // Error is the absolute difference between the measured and estimated distances to the remote node.
-            let sample_error: f64 = remote.measured_distance.sub(distance_estimate); // rtt - ||x_j - x_j||
-            let relative_sample_error = sample_error.abs() / remote.measured_distance; // e_s = | ||x_j - x_j|| - rtt | / rtt
+			// Error is the absolute difference between the measured and estimated distances to the remote node.
+			let sample_error = rtt - distance_estimate; // rtt - ||x_i - x_j||
+			let relative_sample_error = sample_error.abs() / rtt; // e_s = | ||x_i - x_j|| - rtt | / rtt
 
-            // Relative direction of the remote node
-            let dir = displacement.normalized(); // u(x_i - x_j)
+			// Relative direction of the remote node
+			let mut dir = displacement.normalized(); // u(x_i - x_j)
 
-            // The higher our error is, the more remotes with lower errors effect our coordinates.
-            let sample_error_weight = self.error / (self.error + remote.error); // w = e_i / (e_i + e_j)
+			// The higher our error is, the more remotes with lower errors effect our coordinates.
+			let total_error = error + remote.coords.effective_error();
+			let sample_error_weight = if total_error > 0.0 { error / total_error } else { 0.5 }; // w = e_i / (e_i + e_j)
 
-            // Update moving average of this node's error
-            self.error = relative_sample_error * ERROR_CONST * sample_error_weight + self.error * (1 - ERROR_CONST * sample_error_weight); // e_i = ...
+			// Update moving average of this node's error
+			error = relative_sample_error * self.error_const * sample_error_weight + error * (1.0 - self.error_const * sample_error_weight); // e_i = e_s * c_e * w + e_i * (1 - c_e * w)
 
-            // Timestep scales with error
-            let delta = ADAPT_CONST * sample_error_weight; // δ = c_e * w
-            // Move coord
-            self.coord.add((delta * sample_error) * dir);
-        }
-    }
+			// Timestep scales with error
+			let delta = self.move_const * sample_error_weight; // δ = c_c * w
+			// Move coord
+			dir.multiply(delta * sample_error);
+			coord.add(&dir); // x_i = x_i + δ * (rtt - ||x_i - x_j||) * u(x_i - x_j)
+			samples += 1;
+		}
+		self.last_update = Some(Instant::now());
+		if samples == 0 { return None }
+
+		let mut coords = own.clone();
+		coord.to_coordinates(&mut coords);
+		coords.error = error;
+		coords.samples = coords.samples.saturating_add(samples);
+		log::debug!("Updating coordinates: {:?} -> {:?}", own, coords);
+		Some(coords)
+	}
 }
 
-#[derive(Debug, Default)]
-struct PhoenixCoordinator<const EARLY_HOST_THRESHOLD: usize, const LAMBDA: f64> {
-    
+/// Phoenix, weighted matrix factorization: https://user.informatik.uni-goettingen.de/~ychen/papers/Phoenix_TNSM.pdf
+/// Like DMF, `out_coord` dot `in_coord` predicts latency, but both vectors are kept non-negative so predictions are never negative.
+/// A node with more than `EARLY_HOST_THRESHOLD` remotes uses the `EARLY_HOST_THRESHOLD` remotes it has measured the longest as references. This takes the place of the paper's early hosts (the nodes that joined first), it is based on local measurements so remotes can't make themselves references.
+/// Each reference is weighted by the error it reports, `w_j = 1 - e_j / Σ e_k`, times its `RemoteSample::weight` relative to the mean sample weight. `NCConfig::regularization_coeff` is used as λ.
+#[derive(Debug, Resource)]
+pub struct PhoenixCoordinator<const EARLY_HOST_THRESHOLD: usize = 16> {
+	lambda: f64,
+	last_update: Option<Instant>,
 }
-struct PhoenixCoordinatorRemoteState {
+impl<const EARLY_HOST_THRESHOLD: usize> PhoenixCoordinator<EARLY_HOST_THRESHOLD> {
+	/// Multiplicative updates per coordinate update, each one moves the vectors closer to the weighted non-negative least squares solution.
+	const ITERATIONS: usize = 20;
 
+	// Solve min Σ w_j (d_j - x · r_j)² + λ|x|² for x >= 0, starting from x
+	fn solve(&self, mut x: NetworkCoord, references: &[(f64, f64, NetworkCoord)]) -> NetworkCoord {
+		// Weighted normal equations: A^T W A x = A^T W d
+		let mut ata = nalgebra::SMatrix::<f64, COORDINATE_DIMENSIONS, COORDINATE_DIMENSIONS>::zeros();
+		let mut atd = NetworkCoord::zeros();
+		for (weight, rtt, reference) in references {
+			ata += *weight * reference * reference.transpose();
+			atd += *weight * *rtt * reference;
+		}
+		// Multiplicative updates keep every component non-negative (Lee & Seung)
+		for _ in 0..Self::ITERATIONS {
+			let denominator = ata * x + self.lambda * x;
+			x = x.zip_map(&atd.zip_map(&denominator, |n, d|n / d.max(f64::EPSILON)), |x, factor|x * factor);
+		}
+		x
+	}
 }
+impl<const EARLY_HOST_THRESHOLD: usize> NodeCoordinator for PhoenixCoordinator<EARLY_HOST_THRESHOLD> {
+	const ALGORITHM: CoordinateAlgorithm = CoordinateAlgorithm::Phoenix;
+
+	fn new(config: &NCConfig) -> Self {
+		Self { lambda: config.regularization_coeff, last_update: None }
+	}
+
+	fn update(&mut self, own: &Coordinates, remotes: &[RemoteSample]) -> Option<Coordinates> {
+		let mut references = remotes.iter().filter(|remote|remote.rtt() > 0.0).collect::<Vec<&RemoteSample>>();
+		if references.is_empty() { return None }
+		// While there are at most EARLY_HOST_THRESHOLD remotes this node is one of the early hosts, which factorize their latencies among themselves with equal error weights.
+		// Afterwards the remotes measured the longest become references and are weighed by their error.
+		let early_host = references.len() <= EARLY_HOST_THRESHOLD;
+		references.sort_by_key(|remote|remote.metrics.first_update());
+		references.truncate(EARLY_HOST_THRESHOLD);
+
+		let error_sum = references.iter().map(|remote|remote.coords.effective_error()).sum::<f64>();
+		let error_weight = |remote: &RemoteSample| if early_host || error_sum <= 0.0 { 1.0 } else { 1.0 - remote.coords.effective_error() / error_sum };
+		// Sample weights sum to about 1 over all remotes, they are scaled to a mean of 1 so they don't change the effect of λ
+		let sample_weight_mean = references.iter().map(|remote|remote.weight).sum::<f64>() / references.len() as f64;
+		let weight = |remote: &RemoteSample| if sample_weight_mean > 0.0 { error_weight(remote) * remote.weight / sample_weight_mean } else { error_weight(remote) };
+
+		// Measurements are round trips, so they are used for both directions
+		let out_references = references.iter().map(|remote|(weight(remote), remote.rtt(), remote.coords.in_coord)).collect::<Vec<_>>();
+		let in_references = references.iter().map(|remote|(weight(remote), remote.rtt(), remote.coords.out_coord)).collect::<Vec<_>>();
+		let mut coords = own.clone();
+		coords.out_coord = self.solve(own.out_coord, &out_references);
+		coords.in_coord = self.solve(own.in_coord, &in_references);
+
+		coords.error = prediction_error(&coords, references.iter().copied());
+		coords.samples = coords.samples.saturating_add(references.iter().filter(|remote|remote.is_new(self.last_update)).count() as u32);
+		self.last_update = Some(Instant::now());
+		log::debug!("Updating coordinates: {:?} -> {:?}", own, coords);
+		Some(coords)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Coordinates, CoordinateAlgorithm, LatencyMetrics, NCConfig, NodeCoordinator, RemoteSample, VivaldiCoordinator, PhoenixCoordinator};
+
+	#[test]
+	fn vivaldi_converges() {
+		// Round-trip times in milliseconds between three nodes
+		let rtts = [[0.0, 10.0, 20.0], [10.0, 0.0, 25.0], [20.0, 25.0, 0.0]];
+		let config = NCConfig { algorithm: CoordinateAlgorithm::Vivaldi, ..Default::default() };
+		let mut coordinators = (0..3).map(|_|VivaldiCoordinator::new(&config)).collect::<Vec<_>>();
+		let mut coords = (0..3).map(|_|Coordinates::new(CoordinateAlgorithm::Vivaldi)).collect::<Vec<_>>();
+
+		for _ in 0..500 {
+			for i in 0..3 {
+				let metrics = (0..3).map(|j|LatencyMetrics::new((rtts[i][j] * 1000.0) as u64, 1)).collect::<Vec<_>>();
+				let remotes = (0..3).filter(|j|*j != i).map(|j|RemoteSample { coords: &coords[j], metrics: &metrics[j], weight: 1.0 }).collect::<Vec<_>>();
+				if let Some(new) = coordinators[i].update(&coords[i], &remotes) {
+					coords[i] = new;
+				}
+			}
+		}
+		for i in 0..3 {
+			for j in (0..3).filter(|j|*j != i) {
+				let (predicted, _) = coords[i].predict_latencies(&coords[j]);
+				assert!((predicted as f64 / 1000.0 - rtts[i][j]).abs() < 2.0, "predicted {predicted}µs between {i} and {j}, expected {}ms", rtts[i][j]);
+			}
+			assert!(coords[i].error < 0.2, "error of {i} is {}", coords[i].error);
+		}
+	}
+
+	#[test]
+	fn phoenix_converges() {
+		let rtts = [[0.0, 10.0, 20.0, 30.0], [10.0, 0.0, 25.0, 15.0], [20.0, 25.0, 0.0, 40.0], [30.0, 15.0, 40.0, 0.0]];
+		let config = NCConfig { algorithm: CoordinateAlgorithm::Phoenix, ..Default::default() };
+		let mut coordinators = (0..4).map(|_|PhoenixCoordinator::<16>::new(&config)).collect::<Vec<_>>();
+		let mut coords = (0..4).map(|_|Coordinates::new(CoordinateAlgorithm::Phoenix)).collect::<Vec<_>>();
+
+		for _ in 0..200 {
+			for i in 0..4 {
+				let metrics = (0..4).map(|j|LatencyMetrics::new((rtts[i][j] * 1000.0) as u64, 1)).collect::<Vec<_>>();
+				let remotes = (0..4).filter(|j|*j != i).map(|j|RemoteSample { coords: &coords[j], metrics: &metrics[j], weight: 1.0 }).collect::<Vec<_>>();
+				if let Some(new) = coordinators[i].update(&coords[i], &remotes) {
+					coords[i] = new;
+				}
+			}
+		}
+		for i in 0..4 {
+			assert!(coords[i].out_coord.iter().chain(coords[i].in_coord.iter()).all(|x|*x >= 0.0), "negative coordinate: {:?}", coords[i]);
+			for j in (0..4).filter(|j|*j != i) {
+				let (predicted, _) = coords[i].predict_latencies(&coords[j]);
+				assert!((predicted as f64 / 1000.0 - rtts[i][j]).abs() < 0.2 * rtts[i][j], "predicted {predicted}µs between {i} and {j}, expected {}ms", rtts[i][j]);
+			}
+		}
+	}
+
+	// Phoenix weighs references by their sample weight relative to the others
+	#[test]
+	fn phoenix_sample_weights() {
+		let config = NCConfig { algorithm: CoordinateAlgorithm::Phoenix, ..Default::default() };
+		let own = Coordinates::new(CoordinateAlgorithm::Phoenix);
+		let coords = (0..3).map(|_|Coordinates::new(CoordinateAlgorithm::Phoenix)).collect::<Vec<_>>();
+		let metrics = [10.0, 20.0, 500.0].map(|rtt: f64|LatencyMetrics::new((rtt * 1000.0) as u64, 1));
+		let update = |weights: [f64; 3]| {
+			let remotes = (0..3).map(|j|RemoteSample { coords: &coords[j], metrics: &metrics[j], weight: weights[j] }).collect::<Vec<_>>();
+			PhoenixCoordinator::<16>::new(&config).update(&own, &remotes).unwrap()
+		};
+		let close = |a: &Coordinates, b: &Coordinates| (a.out_coord - b.out_coord).norm() + (a.in_coord - b.in_coord).norm() < 1e-9;
+
+		// Only relative weights matter
+		assert!(close(&update([0.5, 0.5, 0.0]), &update([1.0, 1.0, 0.0])));
+		// The badly fitting remote only affects the result if it has a weight
+		assert!(!close(&update([1.0, 1.0, 0.0]), &update([1.0, 1.0, 1.0])));
+	}
 
-fn main() {
-    println!("Hello, world!");
+	#[test]
+	fn validate_coordinates() {
+		for algorithm in [CoordinateAlgorithm::Dmf, CoordinateAlgorithm::Vivaldi, CoordinateAlgorithm::Phoenix] {
+			assert!(Coordinates::new(algorithm).is_valid());
+			let mut coords = Coordinates::new(algorithm);
+			coords.out_coord[0] = f64::NAN;
+			assert!(!coords.is_valid());
+			let mut coords = Coordinates::new(algorithm);
+			coords.error = f64::INFINITY;
+			assert!(!coords.is_valid());
+		}
+		let mut coords = Coordinates::new(CoordinateAlgorithm::Phoenix);
+		coords.in_coord[1] = -1.0;
+		assert!(!coords.is_valid());
+		coords.algorithm = CoordinateAlgorithm::Dmf;
+		assert!(coords.is_valid());
+	}
 }
@@ -0,0 +1,177 @@
+//! Request/response interface to a running `Node`.
+//! A `NodeHandle` sends requests to the node through the `NodeAction` channel and awaits the reply on a oneshot channel, so replies can always be matched to the request that produced them.
+//! Unsolicited events (i.e. new connections or received data) are still sent through the `NodeEvent` stream.
+
+use std::time::Duration;
+
+use futures::channel::{mpsc::UnboundedSender, oneshot};
+use thiserror::Error;
+
+use crate::{Network, NodeID, NodeAction, Coordinates, Latency, NatType, Reachability};
+
+/// How long `NodeHandle::connect` waits for a session to be established.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests sent by a `NodeHandle`, the reply is sent back through the contained oneshot channel.
+#[derive(Debug)]
+pub enum NodeRequest<Net: Network> {
+	/// Connect to remote, reply once a session is established.
+	Connect(NodeID, Option<Net::Address>, Option<Net::NodePubKey>, oneshot::Sender<PeerInfo<Net>>),
+	Info(oneshot::Sender<NodeInfo<Net>>),
+	RemoteInfo(NodeID, oneshot::Sender<Option<RemoteInfo<Net>>>),
+	Snapshot(oneshot::Sender<NodeSnapshot<Net>>),
+	/// Send raw data to a remote with an active session.
+	Send(NodeID, Vec<u8>, oneshot::Sender<Result<(), HandleError>>),
+}
+
+#[derive(Debug, Error)]
+pub enum HandleError {
+	#[error("node is not running")]
+	NodeClosed,
+	#[error("timed out waiting for reply from node")]
+	Timeout,
+	#[error("unknown remote: {0:?}")]
+	UnknownRemote(NodeID),
+	#[error("no active session with remote: {0:?}")]
+	NoSession(NodeID),
+}
+
+/// Information about a remote with an established session.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct PeerInfo<Net: Network> {
+	pub node_id: NodeID,
+	pub address: Net::Address,
+}
+
+/// Information about the local node.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct NodeInfo<Net: Network> {
+	pub node_id: NodeID,
+	/// Public address of this node, if known.
+	pub public_address: Option<Net::Address>,
+	/// NAT this node is behind, only present if `DiscoverySystem` is registered.
+	pub nat_type: Option<NatType>,
+	/// Whether remotes could connect to `public_address`, only present if `DiscoverySystem` is registered.
+	pub reachability: Option<Reachability>,
+	/// Own network coordinates, only present if `NCSystem` is registered.
+	pub coordinates: Option<Coordinates>,
+	/// Confidence in own coordinates from 0.0 to 1.0, see `Coordinates::confidence`.
+	pub coordinate_confidence: Option<f64>,
+	/// All known remotes.
+	pub remotes: Vec<NodeID>,
+}
+
+/// Information about a remote node.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct RemoteInfo<Net: Network> {
+	pub node_id: NodeID,
+	/// Whether there is an active session with the remote.
+	pub connected: bool,
+	/// Address of the last session with the remote.
+	pub address: Option<Net::Address>,
+	pub latest_latency: Option<Latency>,
+	pub min_latency: Option<Latency>,
+	pub coordinates: Option<Coordinates>,
+	/// Predicted (outgoing, incoming) latency to the remote using own and remote coordinates.
+	pub predicted_latencies: Option<(Latency, Latency)>,
+}
+
+/// Full dump of the state of a node, returned by `NodeAction::Snapshot`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct NodeSnapshot<Net: Network> {
+	pub node_id: NodeID,
+	pub coordinates: Option<Coordinates>,
+	/// Own public address as calculated from addresses seen by remotes (`KnownPubAddr`).
+	pub public_address: Option<Net::Address>,
+	pub nat_type: Option<NatType>,
+	pub reachability: Option<Reachability>,
+	pub remotes: Vec<RemoteSnapshot<Net>>,
+}
+
+/// State of a single remote in a `NodeSnapshot`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct RemoteSnapshot<Net: Network> {
+	pub node_id: NodeID,
+	pub session: SessionState<Net>,
+	pub latency: Option<LatencyStats>,
+	pub coordinates: Option<Coordinates>,
+	/// Predicted (outgoing, incoming) latency to the remote using own and remote coordinates.
+	pub predicted_latencies: Option<(Latency, Latency)>,
+	/// Address the remote said it can be reached at.
+	pub public_address: Option<Net::Address>,
+	/// Address the remote sees this node's connection coming from.
+	pub seen_address: Option<Net::Address>,
+	/// Weight of the remote when calculating own coordinates.
+	pub coordinate_weight: Option<f64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub enum SessionState<Net: Network> {
+	/// Session is active with remote at address.
+	Connected(Net::Address),
+	/// No session for a given duration. Contains address of the previous session, if there was one.
+	Disconnected(Duration, Option<Net::Address>),
+}
+
+/// Summary of latency measurements with a remote.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LatencyStats {
+	pub latest: Latency,
+	pub min: Latency,
+	pub mean: Latency,
+	/// Recent measurements, oldest first.
+	pub measurements: Vec<Latency>,
+	/// Time since the last measurement.
+	pub last_update: Duration,
+}
+
+/// Cloneable handle to a running `Node`.
+pub struct NodeHandle<Net: Network> {
+	action_sender: UnboundedSender<NodeAction<Net>>,
+}
+impl<Net: Network> Clone for NodeHandle<Net> {
+	fn clone(&self) -> Self {
+		Self { action_sender: self.action_sender.clone() }
+	}
+}
+
+impl<Net: Network> NodeHandle<Net> {
+	/// Create handle from the sender half of the channel passed to `Node::run`.
+	pub fn new(action_sender: UnboundedSender<NodeAction<Net>>) -> Self {
+		Self { action_sender }
+	}
+	/// Send a `NodeAction` without waiting for a reply.
+	pub fn action(&self, action: NodeAction<Net>) -> Result<(), HandleError> {
+		self.action_sender.unbounded_send(action).map_err(|_|HandleError::NodeClosed)
+	}
+	async fn request<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> NodeRequest<Net>) -> Result<T, HandleError> {
+		let (sender, receiver) = oneshot::channel();
+		self.action(NodeAction::Request(request(sender)))?;
+		receiver.await.map_err(|_|HandleError::NodeClosed)
+	}
+
+	/// Connect to remote and wait until a session is established (or `CONNECT_TIMEOUT` passes). If no address is given, the addresses remembered in the `AddressBook` are tried before looking it up in the DHT.
+	pub async fn connect(&self, node_id: NodeID, address: Option<Net::Address>, pub_key: Option<Net::NodePubKey>) -> Result<PeerInfo<Net>, HandleError> {
+		let reply = self.request(|sender|NodeRequest::Connect(node_id, address, pub_key, sender));
+		async_std::future::timeout(CONNECT_TIMEOUT, reply).await.map_err(|_|HandleError::Timeout)?
+	}
+	pub async fn info(&self) -> Result<NodeInfo<Net>, HandleError> {
+		self.request(NodeRequest::Info).await
+	}
+	pub async fn remote_info(&self, node_id: NodeID) -> Result<RemoteInfo<Net>, HandleError> {
+		self.request(|sender|NodeRequest::RemoteInfo(node_id.clone(), sender)).await?.ok_or(HandleError::UnknownRemote(node_id))
+	}
+	pub async fn snapshot(&self) -> Result<NodeSnapshot<Net>, HandleError> {
+		self.request(NodeRequest::Snapshot).await
+	}
+	/// Send raw data to a remote with an active session.
+	pub async fn send(&self, node_id: NodeID, data: Vec<u8>) -> Result<(), HandleError> {
+		self.request(|sender|NodeRequest::Send(node_id, data, sender)).await?
+	}
+}
@@ -13,17 +13,25 @@ mod net;
 mod packet;
 mod systems;
 mod transport;
+mod handle;
+mod peer_record;
+mod coordinator;
+#[cfg(test)]
+mod test_net;
 use arc_swap::ArcSwap;
 pub use systems::*;
 
-use std::{collections::HashMap, marker::PhantomData, time::Duration, cmp::Ordering, sync::Arc};
+use std::{collections::{HashMap, HashSet, VecDeque}, marker::PhantomData, time::{Duration, Instant}, cmp::Ordering, sync::Arc, any::TypeId};
 
 use bevy_ecs::{prelude::*, world::EntityMut};
-use futures::{channel::mpsc::{unbounded, self, UnboundedSender, TrySendError}, StreamExt};
+use futures::{channel::{mpsc::{unbounded, self, UnboundedSender, TrySendError}, oneshot}, StreamExt};
 
 use session::*;
 pub use net::*;
 pub use packet::*;
+pub use handle::*;
+pub use peer_record::*;
+pub use coordinator::*;
 
 type Latency = u64;
 use thiserror::Error;
@@ -36,12 +44,55 @@ struct Remote {
 	id: NodeID,
 }
 
+/// Marks a remote that currently has no session. Inserted when a remote is registered without a session or when its session closes.
+/// Remotes that stay disconnected for longer than `NodeConfig::remote_timeout` are despawned.
+#[derive(Debug, Component)]
+pub struct Disconnected {
+	since: Instant,
+}
+impl Disconnected {
+	fn now() -> Self { Self { since: Instant::now() } }
+}
+
+/// Marks a remote whose current session was requested through `NodeHandle::connect`, systems don't close it on their own.
+#[derive(Debug, Component)]
+pub struct RequestedSession;
+
+/// Default time a remote without a session is kept around before being forgotten.
+pub const DEFAULT_REMOTE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Default interval between node timer ticks.
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(500);
+/// Default maximum number of active sessions.
+pub const DEFAULT_MAX_SESSIONS: usize = 64;
+/// How long a shutting down node waits for its sessions to close.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an address remembered in the `AddressBook` is given to connect before the next one is tried.
+pub const REMEMBERED_DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Actions that can be run by an external entity (either the internet implementation or the user)
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 #[serde(bound(serialize="", deserialize=""))]
 pub enum NodeAction<Net: Network> {
-	/// Connect to another node
-	Connect(NodeID, Net::Address, Option<Net::NodePubKey>),
+	/// Connect to another node. If no address is given, the addresses remembered in the `AddressBook` are tried before looking it up in the DHT
+	Connect(NodeID, Option<Net::Address>, Option<Net::NodePubKey>),
+	/// Connect to a node found outside of the network (i.e. on the LAN), only if fewer than `DiscoveryConfig::max_peers` sessions exist
+	AddPeer(NodeID, Net::Address),
+
+	/// Look up the addresses of a node in the DHT, returned as `NodeEvent::LookupResult`
+	Lookup(NodeID),
+
+	/// Connect to a node that can't be dialed directly (i.e. behind a NAT) by hole punching through a peer both nodes have a session with
+	HolePunch(NodeID),
+
+	/// Connect to a node through a peer both nodes have a session with that relays the connection, used when neither direct connections nor hole punching work
+	ConnectRelayed(NodeID),
+
+	/// Subscribe to a gossip topic, messages published on it are returned as `NodeEvent::GossipMessage`
+	Subscribe(String),
+	/// Unsubscribe from a gossip topic
+	Unsubscribe(String),
+	/// Publish data on a gossip topic
+	Publish(String, Vec<u8>),
 	
 	/// Send arbitrary packet to Remote
 	ForwardPacket(NodeID, NodePacket<Net>),
@@ -52,22 +103,40 @@ pub enum NodeAction<Net: Network> {
 	/// Establish Onion-route
 	EstablishRoute(Vec<NodeID>),
 
-	/// Print Node info to stdout
-	PrintNode,
+	/// Dump the full state of the node, returned as `NodeEvent::Snapshot`
+	Snapshot,
 
+	/// Get info about this node, returned as `NodeEvent::Info`
 	GetInfo,
-	GetRemoteInfo(Entity),
+	/// Get info about a remote, returned as `NodeEvent::RemoteInfo`
+	GetRemoteInfo(NodeID),
+
+	/// Close all sessions and stop the node, `Node::run` returns once done.
+	Shutdown,
+
+	/// Request sent by a `NodeHandle`, can't be serialized.
+	#[serde(skip)]
+	Request(NodeRequest<Net>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
 pub enum NodeEvent<Net: Network> {
 	// Event returned when new connection is established
 	NewConnection(NodeID, Net::Address),
+	/// Raw data received from a remote
+	Data(NodeID, Vec<u8>),
 	
-	// Event returned for GetRemoteList, return list of remotes.
-	Info(NodeID, Net::ListenerConfig, Coordinates, Vec<(NodeID, Entity)>),
+	// Event returned for GetInfo
+	Info(NodeInfo<Net>),
 	// Event returned for GetRemoteInfo
-	RemoteInfo(Entity, NodeID, LatencyMetrics, Option<Coordinates>, Option<(Latency, Latency)>)
+	RemoteInfo(RemoteInfo<Net>),
+	// Event returned for Snapshot
+	Snapshot(NodeSnapshot<Net>),
+	/// Event returned for Lookup, contains no addresses if the node wasn't found
+	LookupResult(NodeID, Vec<Net::Address>),
+	/// Message received on a subscribed gossip topic: (topic, origin, data), the origin's signature was verified
+	GossipMessage(String, NodeID, Vec<u8>),
 }
 
 #[derive(Debug, Error)]
@@ -79,9 +148,32 @@ pub enum NodeError<Net: Network> {
 /// Contains ECS and Network implementation
 pub struct Node<Net: Network> {
 	world: World,
+	schedule: Schedule,
+	systems: Vec<RegisteredSystem>,
+	/// `NodeHandle::connect` requests waiting for a session to be established.
+	pending_connects: HashMap<NodeID, Vec<oneshot::Sender<PeerInfo<Net>>>>,
+	/// Remotes connected to without an address that are being dialed at addresses from the `AddressBook`.
+	remembered_dials: HashMap<NodeID, RememberedDial<Net>>,
 	_net: PhantomData<Net>,
 }
 
+/// Addresses from the `AddressBook` left to try for a remote, each is dialed for `REMEMBERED_DIAL_TIMEOUT` before the next one is tried.
+struct RememberedDial<Net: Network> {
+	addresses: VecDeque<Net::Address>,
+	pub_key: Option<Net::NodePubKey>,
+	last_attempt: Instant,
+}
+
+/// Type-erased hooks of a `NodeSystem` that was registered through `NodeBuilder`.
+struct RegisteredSystem {
+	type_id: TypeId,
+	register_components: fn(&mut EntityMut),
+	on_session_open: fn(&mut World, Entity),
+	on_session_closed: fn(&mut World, Entity, &SessionCloseReason),
+	on_tick: fn(&mut World),
+	on_shutdown: fn(&mut World),
+}
+
 #[derive(Debug, Default, Resource)]
 pub struct RemoteIDMap {
 	map: HashMap<NodeID, Entity>,
@@ -92,6 +184,22 @@ pub struct NodeConfig<Net: Network> {
 	pub keys: EncryptionKeys<Net>,
 	pub node_id: NodeID,
 	pub listener_config: Net::ListenerConfig,
+	/// How long to remember a remote that has no session.
+	pub remote_timeout: Duration,
+	/// How often `NodeSystem::on_tick` is called.
+	pub tick_interval: Duration,
+	/// Maximum number of active sessions. Incoming connections are dropped and systems stop dialing new peers once reached, connections requested through `NodeAction::Connect` are always accepted.
+	pub max_sessions: usize,
+	pub session: SessionConfig,
+	pub latency_metrics: LatencyMetricsConfig,
+	pub nc: NCConfig,
+	pub bootstrap: BootstrapConfig<Net>,
+	pub address_book: AddressBookConfig,
+	pub discovery: DiscoveryConfig,
+	pub dht: DhtConfig,
+	pub hole_punch: HolePunchConfig,
+	pub relay: RelayConfig,
+	pub gossip: GossipConfig,
 }
 
 #[derive(Resource)]
@@ -99,10 +207,16 @@ pub struct EventSender<Net: Network> {
 	sender: UnboundedSender<NodeEvent<Net>>,
 }
 
-/// Public address of another node
+/// Public address of another node, taken from a verified `SignedPeerRecord`
 #[derive(Debug, Component)]
 pub struct PublicAddress<Net: Network> {
 	addr: Net::Address,
+	record: SignedPeerRecord<Net>,
+}
+impl<Net: Network> PublicAddress<Net> {
+	fn new(record: SignedPeerRecord<Net>) -> Self {
+		Self { addr: record.address().clone(), record }
+	}
 }
 
 #[derive(Resource)]
@@ -125,9 +239,27 @@ pub trait NodeSystem {
 	/// Entity passed must be valid in World and must contain components: `Session<Net>`, `SessionInfo<Net>` otherwise this function may panic.
 	#[allow(unused_variables)]
 	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {}
+
+	/// Called once a new session is established, after `register_components` was run on the entity. Entity contains `Session<Net>` and `SessionInfo<Net>`.
+	fn on_session_open(world: &mut World, entity: Entity) {}
+	/// Called once a session has closed. `Session<Net>` has already been removed from the entity.
+	fn on_session_closed(world: &mut World, entity: Entity, reason: &SessionCloseReason) {}
+	/// Called on every node timer tick (every `NodeConfig::tick_interval`).
+	fn on_tick(world: &mut World) {}
+	/// Called once when the node stops running.
+	fn on_shutdown(world: &mut World) {}
 }
 
-impl<Net: Network> Node<Net> {
+/// Composes which `NodeSystem`s a `Node` runs.
+/// 
+/// Each system added with `with_system` has its resources inserted into the world, its systems added to the schedule and its components inserted into every new session entity.
+pub struct NodeBuilder<Net: Network> {
+	world: World,
+	schedule: Schedule,
+	systems: Vec<RegisteredSystem>,
+	_net: PhantomData<Net>,
+}
+impl<Net: Network> NodeBuilder<Net> {
 	pub fn new(config: NodeConfig<Net>, event_sender: UnboundedSender<NodeEvent<Net>>) -> Self {
 		let mut world = World::new();
 		
@@ -135,6 +267,7 @@ impl<Net: Network> Node<Net> {
 		world.insert_resource(SharedSessionState {
 			state: Arc::new(ArcSwap::new(Arc::new(SessionSharedState::<Net> {
 				self_node_id: config.node_id.clone(),
+				config: config.session.clone(),
 				_net: Default::default(),
 			})))
 		});
@@ -144,43 +277,94 @@ impl<Net: Network> Node<Net> {
 		world.insert_resource::<NodeConfig<Net>>(config);
 		world.insert_resource::<EventSender<Net>>(EventSender { sender: event_sender });
 
-		// Setup resources defined by NodeSystems
-		DiscoverySystem::<Net>::register_resources(&mut world);
-		LatencyMetricsSystem::<Net>::register_resources(&mut world);
-		NCSystem::<Net>::register_resources(&mut world);
+		// Create a new Schedule, which defines an execution strategy for Systems
+		let mut schedule = Schedule::new();
+		schedule.set_executor_kind(bevy_ecs::schedule::ExecutorKind::SingleThreaded);
+		schedule.add_system(remove_stale_remotes::<Net>);
 
 		Self {
 			world,
+			schedule,
+			systems: Vec::new(),
 			_net: Default::default(),
 		}
 	}
+	/// Register a `NodeSystem` with the node. Registering the same system twice does nothing.
+	pub fn with_system<S: NodeSystem + 'static>(mut self) -> Self {
+		let type_id = TypeId::of::<S>();
+		if self.systems.iter().any(|system| system.type_id == type_id) {
+			log::warn!("NodeBuilder: {} was already registered", std::any::type_name::<S>());
+			return self;
+		}
+		S::register_resources(&mut self.world);
+		S::register_systems(&mut self.schedule);
+		self.systems.push(RegisteredSystem {
+			type_id,
+			register_components: S::register_components,
+			on_session_open: S::on_session_open,
+			on_session_closed: S::on_session_closed,
+			on_tick: S::on_tick,
+			on_shutdown: S::on_shutdown,
+		});
+		self
+	}
+	/// Register the systems a regular node runs: address book, bootstrap, discovery, DHT, hole punching, relaying, gossip, latency measurement, network coordinates (using the algorithm set in `NCConfig`) and logging.
+	pub fn with_default_systems(self) -> Self {
+		let algorithm = self.world.resource::<NodeConfig<Net>>().nc.algorithm;
+		let builder = self.with_system::<AddressBookSystem<Net>>()
+			.with_system::<BootstrapSystem<Net>>()
+			.with_system::<DiscoverySystem<Net>>()
+			.with_system::<DhtSystem<Net>>()
+			.with_system::<HolePunchSystem<Net>>()
+			.with_system::<RoutingSystem<Net>>()
+			.with_system::<GossipSystem<Net>>()
+			.with_system::<LatencyMetricsSystem<Net>>();
+		let builder = match algorithm {
+			CoordinateAlgorithm::Dmf => builder.with_system::<NCSystem<Net, DmfCoordinator>>(),
+			CoordinateAlgorithm::Vivaldi => builder.with_system::<NCSystem<Net, VivaldiCoordinator>>(),
+			CoordinateAlgorithm::Phoenix => builder.with_system::<NCSystem<Net, PhoenixCoordinator>>(),
+		};
+		builder.with_system::<LoggingSystem<Net>>()
+	}
+	pub fn build(self) -> Node<Net> {
+		Node {
+			world: self.world,
+			schedule: self.schedule,
+			systems: self.systems,
+			pending_connects: HashMap::new(),
+			remembered_dials: HashMap::new(),
+			_net: Default::default(),
+		}
+	}
+}
+
+impl<Net: Network> Node<Net> {
+	/// Create a node that runs the default set of systems, use `NodeBuilder` to choose which systems are run.
+	pub fn new(config: NodeConfig<Net>, event_sender: UnboundedSender<NodeEvent<Net>>) -> Self {
+		NodeBuilder::new(config, event_sender).with_default_systems().build()
+	}
+	/// Whether a given `NodeSystem` was registered with this node.
+	pub fn has_system<S: NodeSystem + 'static>(&self) -> bool {
+		let type_id = TypeId::of::<S>();
+		self.systems.iter().any(|system| system.type_id == type_id)
+	}
 	/// Runs the event loop of the node. This should be spawned in its own task.
 	pub async fn run(mut self, mut action_receiver: mpsc::UnboundedReceiver<NodeAction<Net>>) -> Result<Self, Net::ConnectionError> {
 		let config = self.world.resource::<NodeConfig<Net>>();
+		let (keys, listener_config, tick_interval) = (config.keys.clone(), config.listener_config.clone(), config.tick_interval);
 		
-		log::info!("listener config: {:?}", config.listener_config);
+		log::info!("listener config: {:?}", listener_config);
 
-		let (network, mut connection_stream) = Net::init(config.keys.clone(), &config.listener_config).await?;
-		self.world.insert_resource::<Net::ListenerConfig>(config.listener_config.clone());
+		let (network, mut connection_stream) = Net::init(keys, &listener_config).await?;
+		self.world.insert_resource::<Net::ListenerConfig>(listener_config.clone());
 		self.world.insert_resource(network);
 
-		// Create a new Schedule, which defines an execution strategy for Systems
-		let mut schedule = Schedule::new();
-		schedule.set_executor_kind(bevy_ecs::schedule::ExecutorKind::SingleThreaded);
-
-		DiscoverySystem::<Net>::register_systems(&mut schedule);
-		LatencyMetricsSystem::<Net>::register_systems(&mut schedule);
-		NCSystem::<Net>::register_systems(&mut schedule);
-		LoggingSystem::<Net>::register_systems(&mut schedule);
-
-		schedule.add_system(check_closed_session::<Net>);
-
 		// Session threads send events to main ECS thread through this channel
 		let (entity_event_sender, mut entity_event_receiver) = unbounded::<EntitySessionEvent<Net>>();
 
 		// self.world.insert_resource::<EntityEventSender<Net>>(EntityEventSender { sender: entity_event_sender.clone() });
 
-		let mut timer_500_millis = async_std::stream::interval(Duration::from_millis(500)).fuse();
+		let mut tick_timer = async_std::stream::interval(tick_interval).fuse();
 
 		// Main event loop, awaits multiple futures (timers, session events, etc.) and runs the ECS schedule once
 		loop {
@@ -189,15 +373,22 @@ impl<Net: Network> Node<Net> {
 				// Handle events from sessions (i.e. remote packets or latency measurements)
 				event = entity_event_receiver.next() => if let Some(event) = event {
 					// log::debug!("received from {:?}. SessionEvent: {:?}", event.entity, event.event);
-					Self::handle_session_events(&mut self.world, event);
+					self.handle_session_events(event);
 				},
 				// Handle actions
-				action = action_receiver.next() => if let Some(action) = action {
-					log::debug!("received NodeAction: {action:?}");
-					if let Err(err) = self.handle_node_action(action).await {
-						log::error!("Error: {err}");
+				action = action_receiver.next() => match action {
+					Some(NodeAction::Shutdown) => {
+						log::info!("received NodeAction: Shutdown");
 						break;
 					}
+					Some(action) => {
+						log::debug!("received NodeAction: {action:?}");
+						if let Err(err) = self.handle_node_action(action).await {
+							log::error!("Error: {err}");
+							break;
+						}
+					}
+					None => {}
 				},
 				// Handle new connections
 				conn = connection_stream.next() => {
@@ -208,21 +399,61 @@ impl<Net: Network> Node<Net> {
 						_ => { log::info!("Connection Stream closed."); break },
 					}	
 				}
-				_ = timer_500_millis.next() => {
+				_ = tick_timer.next() => {
 					self.handle_timer();
 				}
 				complete => break,
 			}
 
 			// Run schedule with updated world
-			schedule.run(&mut self.world);
+			self.schedule.run(&mut self.world);
 		}
 
 		log::info!("node: shutting down");
+		for system in &self.systems {
+			(system.on_shutdown)(&mut self.world);
+		}
+		self.close_all_sessions(&mut entity_event_receiver).await;
+
+		// Stop listening for connections
+		if let Some(network) = self.world.remove_resource::<Net>() {
+			network.shutdown();
+		}
 
 		Ok(self)
 	}
+	// Close all sessions and wait (up to `SHUTDOWN_TIMEOUT`) for the session tasks to close their connections.
+	async fn close_all_sessions(&mut self, entity_event_receiver: &mut mpsc::UnboundedReceiver<EntitySessionEvent<Net>>) {
+		let entities = self.world.query_filtered::<Entity, With<Session<Net>>>().iter(&self.world).collect::<Vec<Entity>>();
+		for entity in &entities {
+			self.close_session(*entity, SessionCloseReason::Closed);
+		}
+
+		let mut remaining = entities.into_iter().collect::<HashSet<Entity>>();
+		let wait_closed = async {
+			while !remaining.is_empty() {
+				match entity_event_receiver.next().await {
+					Some(EntitySessionEvent { entity, event: SessionEvent::Closed(_) }) => { remaining.remove(&entity); }
+					Some(_) => {}
+					None => break,
+				}
+			}
+		};
+		if async_std::future::timeout(SHUTDOWN_TIMEOUT, wait_closed).await.is_err() {
+			log::warn!("node: timed out waiting for {} sessions to close", remaining.len());
+		}
+	}
 	fn handle_timer(&mut self) {
+		for system in &self.systems {
+			(system.on_tick)(&mut self.world);
+		}
+		// Try the next remembered address of remotes that didn't connect in time
+		let timed_out = self.remembered_dials.iter()
+			.filter(|(_, dial)|dial.last_attempt.elapsed() >= REMEMBERED_DIAL_TIMEOUT)
+			.map(|(remote_id, _)|remote_id.clone()).collect::<Vec<NodeID>>();
+		for remote_id in timed_out {
+			self.dial_remembered(remote_id);
+		}
 		// change_should_update(&mut self.world);
 		// All entities that have an active connection
 		/* if let Some((rand_session, _rand_metrics)) =  {
@@ -233,21 +464,38 @@ impl<Net: Network> Node<Net> {
 		}); */
 	}
 	// Update the world based on events from active session threads.
-	fn handle_session_events(world: &mut World, session_event: EntitySessionEvent<Net>) {
+	fn handle_session_events(&mut self, session_event: EntitySessionEvent<Net>) {
 		let EntitySessionEvent { entity, event } = session_event;
 		match event {
 			SessionEvent::Packet(packet) => match packet {
-				NodePacket::DiscoveryPacket(packet) => DiscoverySystem::handle_packet(world, entity, packet),
-				NodePacket::NCSystemPacket(packet) => NCSystem::<Net>::handle_packet(world, entity, packet),
+				NodePacket::DiscoveryPacket(packet) if self.has_system::<DiscoverySystem<Net>>() => DiscoverySystem::handle_packet(&mut self.world, entity, packet),
+				// Own coordinates are only present if an `NCSystem` is registered, its packet handling doesn't depend on the coordinator
+				NodePacket::NCSystemPacket(packet) if self.world.contains_resource::<Coordinates>() => NCSystem::<Net>::handle_packet(&mut self.world, entity, packet),
+				NodePacket::DhtPacket(packet) if self.has_system::<DhtSystem<Net>>() => DhtSystem::<Net>::handle_packet(&mut self.world, entity, packet),
+				NodePacket::HolePunchPacket(packet) if self.has_system::<HolePunchSystem<Net>>() => HolePunchSystem::<Net>::handle_packet(&mut self.world, entity, packet),
+				NodePacket::RoutingSystemPacket(packet) if self.has_system::<RoutingSystem<Net>>() => RoutingSystem::<Net>::handle_packet(&mut self.world, entity, packet),
+				NodePacket::GossipPacket(packet) if self.has_system::<GossipSystem<Net>>() => GossipSystem::<Net>::handle_packet(&mut self.world, entity, packet),
 				NodePacket::Traversal(_) => panic!("Traversal Packet"),
+				NodePacket::DiscoveryPacket(_) | NodePacket::NCSystemPacket(_) | NodePacket::DhtPacket(_) | NodePacket::HolePunchPacket(_) | NodePacket::RoutingSystemPacket(_) | NodePacket::GossipPacket(_) => log::debug!("dropping packet from {entity:?} for unregistered system"),
+				NodePacket::Data(data) => if let Some(remote) = self.world.get::<Remote>(entity) {
+					if let Err(err) = self.send_event(NodeEvent::Data(remote.id.clone(), data)) {
+						log::error!("failed to send data event: {err}");
+					}
+				},
 				_ => unimplemented!(),
 			}
-			SessionEvent::LatencyMeasurement(measurement) => {
-				LatencyMetricsSystem::<Net>::handle_packet(world, entity, measurement);
+			SessionEvent::LatencyMeasurement(measurement) => if self.has_system::<LatencyMetricsSystem<Net>>() {
+				LatencyMetricsSystem::<Net>::handle_packet(&mut self.world, entity, measurement);
+			}
+			SessionEvent::Closed(reason) => {
+				// Only close if the session that sent this is still the one attached to the entity (it may have been replaced by a newer session)
+				if self.world.get::<Session<Net>>(entity).map_or(false, |sess| sess.action_sender.is_closed()) {
+					self.close_session(entity, reason);
+				}
 			}
 			// Packet that should be routed
 			SessionEvent::Traversal(packet) => {
-				if let Some((sess, _)) = world.query::<(&Session<Net>, &Coordinates)>().iter(&world)
+				if let Some((sess, _)) = self.world.query::<(&Session<Net>, &Coordinates)>().iter(&self.world)
 					.map(|(s, c)|(s, c.out_coord.dot(&packet.destination)))
 					.min_by(|(_, c1), (_, c2)| f64::partial_cmp(c1, c2).unwrap_or(Ordering::Equal)) {
 					// Send packet
@@ -259,70 +507,213 @@ impl<Net: Network> Node<Net> {
 	}
 	async fn handle_node_action(&mut self, action: NodeAction<Net>) -> Result<(), NodeError<Net>> {
 		match action {
-			NodeAction::Connect(remote_id, remote_addr, pub_key) => {
-				let entity = self.world.resource::<RemoteIDMap>().map.get(&remote_id).cloned();
-				// Check if NodeID already registered in world. (Using HashMap mapping NodeID to Entity)
-				let (pub_key, persistent_state) = if let Some(entity) = entity {
-					// Check if already connected, if so no need to connect again.
-					if self.world.get::<Session<Net>>(entity).is_some() {
-						log::info!("NodeAction: Connect: Already Connected to Remote: {remote_id:?}");
-						return Ok(());
-					}
-
-					// Check if Session exists and if so, also if the pub_key matches.
-					let persistent_state = if let Some(session) = self.world.get::<SessionInfo<Net>>(entity) {
-						if session.net_address != remote_addr {
-							log::info!("NodeAction: Connect: Connecting to a different remote address than from previous Session")
-						}
-						session.persistent_state.clone()
-					} else { None };
-					
-					(pub_key, persistent_state)
-				} else {
-					// If NodeID not registered, register it in RemoteIDMap
-					let entity = self.world.spawn(Remote { id : remote_id.clone() } ).id();
-					self.world.resource_mut::<RemoteIDMap>().map.insert(remote_id.clone(), entity);
-
-					(pub_key, None)
-				};
-				// Connect to it via Network
-				self.world.resource::<Net>().connect(remote_id, remote_addr, pub_key, persistent_state);
+			NodeAction::Connect(remote_id, remote_addr, pub_key) => self.connect(remote_id, remote_addr, pub_key),
+			NodeAction::AddPeer(remote_id, remote_addr) => if self.has_system::<DiscoverySystem<Net>>() {
+				DiscoverySystem::<Net>::add_peer(&mut self.world, remote_id, remote_addr);
+			} else {
+				log::error!("NodeAction: AddPeer: DiscoverySystem is not registered");
+			},
+			NodeAction::Lookup(remote_id) => if self.has_system::<DhtSystem<Net>>() {
+				DhtSystem::<Net>::lookup(&mut self.world, remote_id, false, true);
+			} else {
+				log::error!("NodeAction: Lookup: DhtSystem is not registered");
+				self.send_event(NodeEvent::LookupResult(remote_id, Vec::new()))?;
+			},
+			NodeAction::HolePunch(remote_id) => if self.has_system::<HolePunchSystem<Net>>() {
+				HolePunchSystem::<Net>::punch(&mut self.world, remote_id);
+			} else {
+				log::error!("NodeAction: HolePunch: HolePunchSystem is not registered");
+			},
+			NodeAction::ConnectRelayed(remote_id) => if self.has_system::<RoutingSystem<Net>>() {
+				RoutingSystem::<Net>::connect_relayed(&mut self.world, remote_id);
+			} else {
+				log::error!("NodeAction: ConnectRelayed: RoutingSystem is not registered");
 			},
-			NodeAction::PrintNode => todo!(),
-			NodeAction::ForwardPacket(_, _) => todo!(),
+			NodeAction::Subscribe(topic) | NodeAction::Unsubscribe(topic) | NodeAction::Publish(topic, _) if !self.has_system::<GossipSystem<Net>>() => {
+				log::error!("NodeAction: gossip topic {topic:?}: GossipSystem is not registered");
+			}
+			NodeAction::Subscribe(topic) => GossipSystem::<Net>::subscribe(&mut self.world, topic),
+			NodeAction::Unsubscribe(topic) => GossipSystem::<Net>::unsubscribe(&mut self.world, topic),
+			NodeAction::Publish(topic, data) => GossipSystem::<Net>::publish(&mut self.world, topic, data),
+			NodeAction::Snapshot => {
+				let snapshot = self.snapshot();
+				self.send_event(NodeEvent::Snapshot(snapshot))?
+			}
+			NodeAction::ForwardPacket(remote_id, packet) => if let Err(err) = self.send_packet(&remote_id, packet) {
+				log::error!("NodeAction: ForwardPacket: {err}");
+			},
+			NodeAction::Shutdown => unreachable!("Shutdown is handled by Node::run"),
 			NodeAction::EstablishRoute(_) => todo!(),
 			NodeAction::FindRouter(_) => todo!(),
-			NodeAction::GetInfo => {
-				let remote_map = &self.world.resource::<RemoteIDMap>().map;
-				let remotes = remote_map.into_iter().map(|(id, entity)|(id.clone(), entity.clone())).collect::<Vec<(NodeID, Entity)>>();
-				
-				let node_config = self.world.resource::<NodeConfig<Net>>();
-				let coords = self.world.resource::<Coordinates>();
-				self.send_event(NodeEvent::Info(node_config.node_id.clone(), node_config.listener_config.clone(), coords.clone(), remotes))?;
+			NodeAction::GetInfo => self.send_event(NodeEvent::Info(self.node_info()))?,
+			NodeAction::GetRemoteInfo(remote_id) => {
+				match self.world.resource::<RemoteIDMap>().map.get(&remote_id).and_then(|entity|self.remote_info(*entity)) {
+					Some(info) => self.send_event(NodeEvent::RemoteInfo(info))?,
+					None => log::error!("NodeAction: GetRemoteInfo: unknown remote: {remote_id:?}"),
+				}
 			},
-			NodeAction::GetRemoteInfo(entity) => {
-				if self.world.get_entity(entity).is_none() {
-					log::error!("unknown entity: {entity:?}");
-					return Ok(());
+			NodeAction::Request(request) => self.handle_request(request),
+		}
+		Ok(())
+	}
+	// Reply to requests from `NodeHandle`. Replies are ignored if the handle stopped waiting.
+	fn handle_request(&mut self, request: NodeRequest<Net>) {
+		match request {
+			NodeRequest::Connect(remote_id, remote_addr, pub_key, reply) => {
+				if let Some(peer_info) = self.peer_info(&remote_id) {
+					let _ = reply.send(peer_info);
+					return;
 				}
-				if let Ok((entity, remote, latency_metrics, coords)) = self.world.query::<(Entity, &Remote, &LatencyMetrics, Option<&Coordinates>)>().get(&self.world, entity) {
-					let own_coords = self.world.resource::<Coordinates>();
-					
-					self.send_event(NodeEvent::RemoteInfo(
-						entity,
-						remote.id.clone(),
-						latency_metrics.clone(),
-						coords.cloned(),
-						coords.map(|coords|own_coords.predict_latencies(coords))
-					))?;
-				} else {
-					log::error!("entity {entity:?} exists but has components: {:?}", self.world.inspect_entity(entity).iter().map(|info|info.name()).collect::<Vec<&str>>());
+				// Forget requests that have timed out
+				self.pending_connects.retain(|_, replies| { replies.retain(|reply|!reply.is_canceled()); !replies.is_empty() });
+				self.pending_connects.entry(remote_id.clone()).or_default().push(reply);
+				self.connect(remote_id, remote_addr, pub_key);
+			}
+			NodeRequest::Info(reply) => { let _ = reply.send(self.node_info()); }
+			NodeRequest::RemoteInfo(remote_id, reply) => {
+				let info = self.world.resource::<RemoteIDMap>().map.get(&remote_id).and_then(|entity|self.remote_info(*entity));
+				let _ = reply.send(info);
+			}
+			NodeRequest::Snapshot(reply) => { let _ = reply.send(self.snapshot()); }
+			NodeRequest::Send(remote_id, data, reply) => { let _ = reply.send(self.send_packet(&remote_id, NodePacket::Data(data))); }
+		}
+	}
+	// Connect to remote via Network, does nothing if already connected. If no address is given, the addresses in the `AddressBook` are tried first, then it is looked up in the DHT.
+	fn connect(&mut self, remote_id: NodeID, remote_addr: Option<Net::Address>, pub_key: Option<Net::NodePubKey>) {
+		let entity = self.world.resource::<RemoteIDMap>().map.get(&remote_id).cloned();
+		// Check if NodeID already registered in world. (Using HashMap mapping NodeID to Entity)
+		let (pub_key, persistent_state) = if let Some(entity) = entity {
+			// Check if already connected, if so no need to connect again.
+			if self.world.get::<Session<Net>>(entity).is_some() {
+				log::info!("NodeAction: Connect: Already Connected to Remote: {remote_id:?}");
+				return;
+			}
+			let Some(remote_addr) = &remote_addr else { return self.connect_remembered(remote_id, pub_key) };
+
+			// Check if Session exists and if so, also if the pub_key matches.
+			let persistent_state = if let Some(session) = self.world.get::<SessionInfo<Net>>(entity) {
+				if session.net_address != *remote_addr {
+					log::info!("NodeAction: Connect: Connecting to a different remote address than from previous Session")
 				}
-				
-			},
+				session.persistent_state.clone()
+			} else { None };
+			
+			(pub_key, persistent_state)
+		} else {
+			if remote_addr.is_none() { return self.connect_remembered(remote_id, pub_key) }
+			// If NodeID not registered, register it in RemoteIDMap
+			let entity = self.world.spawn((Remote { id : remote_id.clone() }, Disconnected::now())).id();
+			self.world.resource_mut::<RemoteIDMap>().map.insert(remote_id.clone(), entity);
+
+			(pub_key, None)
+		};
+		// Connect to it via Network
+		let remote_addr = remote_addr.expect("address was checked above");
+		self.world.resource::<Net>().connect(remote_id, remote_addr, pub_key, persistent_state);
+	}
+	fn connect_remembered(&mut self, remote_id: NodeID, pub_key: Option<Net::NodePubKey>) {
+		if self.remembered_dials.contains_key(&remote_id) { return }
+		let addresses = self.world.get_resource::<AddressBook<Net>>()
+			.and_then(|book|book.get(&remote_id))
+			.map(|record|record.addresses.iter().cloned().collect())
+			.unwrap_or_default();
+		self.remembered_dials.insert(remote_id.clone(), RememberedDial { addresses, pub_key, last_attempt: Instant::now() });
+		self.dial_remembered(remote_id);
+	}
+	// Dial the next remembered address of a remote, once there are none left it is looked up in the DHT
+	fn dial_remembered(&mut self, remote_id: NodeID) {
+		let Some(mut dial) = self.remembered_dials.remove(&remote_id) else { return };
+		if self.peer_info(&remote_id).is_some() { return }
+		match dial.addresses.pop_front() {
+			Some(addr) => {
+				log::info!("NodeAction: Connect: dialing {remote_id:?} at remembered address {addr}");
+				self.connect(remote_id.clone(), Some(addr), dial.pub_key.clone());
+				dial.last_attempt = Instant::now();
+				self.remembered_dials.insert(remote_id, dial);
+			}
+			None => self.lookup_and_connect(remote_id),
 		}
+	}
+	fn lookup_and_connect(&mut self, remote_id: NodeID) {
+		if self.has_system::<DhtSystem<Net>>() {
+			DhtSystem::<Net>::lookup(&mut self.world, remote_id, true, false);
+		} else {
+			log::error!("NodeAction: Connect: no address given for {remote_id:?} and DhtSystem is not registered");
+		}
+	}
+	fn send_packet(&self, remote_id: &NodeID, packet: NodePacket<Net>) -> Result<(), HandleError> {
+		let entity = self.world.resource::<RemoteIDMap>().map.get(remote_id).ok_or(HandleError::UnknownRemote(remote_id.clone()))?;
+		let session = self.world.get::<Session<Net>>(*entity).ok_or(HandleError::NoSession(remote_id.clone()))?;
+		session.send_packet(packet);
 		Ok(())
 	}
+	fn peer_info(&self, remote_id: &NodeID) -> Option<PeerInfo<Net>> {
+		let entity = self.world.get_entity(*self.world.resource::<RemoteIDMap>().map.get(remote_id)?)?;
+		entity.get::<Session<Net>>()?;
+		Some(PeerInfo { node_id: remote_id.clone(), address: entity.get::<SessionInfo<Net>>()?.net_address.clone() })
+	}
+	fn node_info(&self) -> NodeInfo<Net> {
+		NodeInfo {
+			node_id: self.world.resource::<NodeConfig<Net>>().node_id.clone(),
+			public_address: self.world.get_resource::<KnownPubAddr<Net>>().and_then(|known|known.addr().cloned()),
+			nat_type: self.world.get_resource::<NatDetection<Net>>().map(|nat|nat.nat_type()),
+			reachability: self.world.get_resource::<KnownPubAddr<Net>>().map(|known|known.reachability()),
+			coordinates: self.world.get_resource::<Coordinates>().cloned(),
+			coordinate_confidence: self.world.get_resource::<Coordinates>().map(|coords|coords.confidence()),
+			remotes: self.world.resource::<RemoteIDMap>().map.keys().cloned().collect(),
+		}
+	}
+	fn remote_info(&self, entity: Entity) -> Option<RemoteInfo<Net>> {
+		let entity = self.world.get_entity(entity)?;
+		let metrics = entity.get::<LatencyMetrics>();
+		let coords = entity.get::<Coordinates>();
+		let own_coords = self.world.get_resource::<Coordinates>();
+		Some(RemoteInfo {
+			node_id: entity.get::<Remote>()?.id.clone(),
+			connected: entity.contains::<Session<Net>>(),
+			address: entity.get::<SessionInfo<Net>>().map(|info|info.net_address.clone()),
+			latest_latency: metrics.map(|metrics|metrics.latest_latency()),
+			min_latency: metrics.map(|metrics|metrics.min_latency()),
+			coordinates: coords.cloned(),
+			predicted_latencies: coords.zip(own_coords).map(|(coords, own_coords)|own_coords.predict_latencies(coords)),
+		})
+	}
+	fn snapshot(&mut self) -> NodeSnapshot<Net> {
+		let own_coords = self.world.get_resource::<Coordinates>().cloned();
+		let remotes = self.world.query::<(&Remote, Option<&Session<Net>>, Option<&Disconnected>, Option<&SessionInfo<Net>>, Option<&LatencyMetrics>, Option<&Coordinates>, Option<&PublicAddress<Net>>, Option<&SeenAddr<Net>>, Option<&CoordinateWeight>)>()
+			.iter(&self.world)
+			.map(|(remote, session, disconnected, info, metrics, coords, public_addr, seen_addr, weight)| {
+				let address = info.map(|info|info.net_address.clone());
+				RemoteSnapshot {
+					node_id: remote.id.clone(),
+					session: match (session, address) {
+						(Some(_), Some(address)) => SessionState::Connected(address),
+						(_, address) => SessionState::Disconnected(disconnected.map(|d|d.since.elapsed()).unwrap_or_default(), address),
+					},
+					latency: metrics.map(|metrics| LatencyStats {
+						latest: metrics.latest_latency(),
+						min: metrics.min_latency(),
+						mean: metrics.mean_latency(),
+						measurements: metrics.latencies().collect(),
+						last_update: metrics.last_update().elapsed(),
+					}),
+					coordinates: coords.cloned(),
+					predicted_latencies: coords.zip(own_coords.as_ref()).map(|(coords, own_coords)|own_coords.predict_latencies(coords)),
+					public_address: public_addr.map(|public_addr|public_addr.addr.clone()),
+					seen_address: seen_addr.map(|seen_addr|seen_addr.addr().clone()),
+					coordinate_weight: weight.map(|weight|weight.value()),
+				}
+			}).collect();
+
+		NodeSnapshot {
+			node_id: self.world.resource::<NodeConfig<Net>>().node_id.clone(),
+			coordinates: own_coords,
+			public_address: self.world.get_resource::<KnownPubAddr<Net>>().and_then(|known|known.addr().cloned()),
+			nat_type: self.world.get_resource::<NatDetection<Net>>().map(|nat|nat.nat_type()),
+			reachability: self.world.get_resource::<KnownPubAddr<Net>>().map(|known|known.reachability()),
+			remotes,
+		}
+	}
 	fn send_event(&self, event: NodeEvent<Net>) -> Result<(), NodeError<Net>> {
 		self.world.resource::<EventSender<Net>>().sender.unbounded_send(event)?;
 		Ok(())
@@ -333,12 +724,26 @@ impl<Net: Network> Node<Net> {
 
 		log::info!("received connection from {remote_id:?} from address: {:?}", connection.incoming_address);
 
+		if !connection.requested {
+			let max_sessions = self.world.resource::<NodeConfig<Net>>().max_sessions;
+			if session_count::<Net>(&mut self.world) >= max_sessions {
+				log::info!("dropping connection from {remote_id:?}: reached maximum of {max_sessions} sessions");
+				return;
+			}
+		}
+
 		// Search RemoteIDMap for entity given NodeID
 		let entity = self.world.resource::<RemoteIDMap>().map.get(&remote_id).cloned();
 
+		// Systems must clean up the state of the old session before the new one is inserted over it
+		if let Some(entity) = entity {
+			self.close_session(entity, SessionCloseReason::Replaced);
+		}
+
 		// Create Session info
 		let session_info = SessionInfo::<Net> {
 			net_address: connection.incoming_address.clone(),
+			local_address: connection.local_address.clone(),
 			remote_pub_key: Some(connection.remote_pub_key.clone()),
 			persistent_state: Some(connection.persistent_state.clone()),
 		};
@@ -350,7 +755,7 @@ impl<Net: Network> Node<Net> {
 			entity_id
 		} else {
 			let entity = self.world.spawn((Remote { id: remote_id.clone() }, session_info)).id();
-			self.world.resource_mut::<RemoteIDMap>().map.insert(remote_id, entity);
+			self.world.resource_mut::<RemoteIDMap>().map.insert(remote_id.clone(), entity);
 			entity
 		};
 
@@ -363,22 +768,67 @@ impl<Net: Network> Node<Net> {
 		let session = Session::spawn(connection, shared, entity_id, session_event_sender);
 
 		entity_mut.insert(session);
-		LatencyMetricsSystem::<Net>::register_components(&mut entity_mut);
-		NCSystem::<Net>::register_components(&mut entity_mut);
+		entity_mut.remove::<Disconnected>();
+		for system in &self.systems {
+			(system.register_components)(&mut entity_mut);
+		}
+
+		if self.pending_connects.contains_key(&remote_id) {
+			entity_mut.insert(RequestedSession);
+		}
+		self.remembered_dials.remove(&remote_id);
 
 		// If I am the initiator of the connection, I should send a public address if possible
 		if connection_requested {
 			// Add component marking the entity that is the receiver of the connection.
 			entity_mut.insert(ConnReceiver);
 		}
+
+		for system in &self.systems {
+			(system.on_session_open)(&mut self.world, entity_id);
+		}
+
+		// Notify anyone waiting for this connection
+		if let Some(peer_info) = self.peer_info(&remote_id) {
+			for reply in self.pending_connects.remove(&remote_id).into_iter().flatten() {
+				let _ = reply.send(peer_info.clone());
+			}
+			if let Err(err) = self.send_event(NodeEvent::NewConnection(peer_info.node_id, peer_info.address)) {
+				log::error!("failed to send connection event: {err}");
+			}
+		}
+	}
+	/// Remove `Session` from entity and notify systems that the session has closed.
+	fn close_session(&mut self, entity: Entity, reason: SessionCloseReason) {
+		let Some(mut entity_mut) = self.world.get_entity_mut(entity) else { return };
+		if entity_mut.take::<Session<Net>>().is_none() { return }
+		entity_mut.remove::<RequestedSession>();
+		entity_mut.insert(Disconnected::now());
+
+		log::info!("session for {entity:?} closed: {reason}");
+		for system in &self.systems {
+			(system.on_session_closed)(&mut self.world, entity, &reason);
+		}
 	}
 }
 
-// remove closed sessions
-fn check_closed_session<Net: Network>(mut commands: Commands, sessions: Query<(Entity, &Session<Net>)>) {
-	for (entity, sess) in sessions.iter() {
-		if sess.action_sender.is_closed() {
-			commands.entity(entity).remove::<Session<Net>>();
+/// Number of remotes with an active session.
+pub(crate) fn session_count<Net: Network>(world: &mut World) -> usize {
+	world.query_filtered::<(), With<Session<Net>>>().iter(world).count()
+}
+
+// Forget remotes that have not had a session for longer than `NodeConfig::remote_timeout`
+fn remove_stale_remotes<Net: Network>(
+	mut commands: Commands,
+	config: Res<NodeConfig<Net>>,
+	mut remote_map: ResMut<RemoteIDMap>,
+	remotes: Query<(Entity, &Remote, &Disconnected), Without<Session<Net>>>,
+) {
+	for (entity, remote, disconnected) in &remotes {
+		if disconnected.since.elapsed() > config.remote_timeout {
+			log::debug!("forgetting remote {:?} ({entity:?}), no session for {:?}", remote.id, disconnected.since.elapsed());
+			remote_map.map.remove(&remote.id);
+			commands.entity(entity).despawn();
 		}
 	}
-}
\ No newline at end of file
+}
@@ -1,13 +1,13 @@
 //! Defines all the generic components of a node interacting with an internet structure.
 //! A Node should be able to work in any kind of network. simulated or not. This file provides the basic structures that any network implementation will use to interact with a Node, in addition to any structures a User will use to interact with the network implementation and by extension, the Node.
 
-use std::fmt;
+use std::{fmt, time::Duration};
 use bevy_ecs::{prelude::Component, system::Resource};
 use bytecheck::CheckBytes;
-use futures::{AsyncRead, AsyncWrite, Stream, stream::FusedStream};
+use futures::{AsyncRead, AsyncWrite, Stream, stream::FusedStream, channel::oneshot};
 use rkyv::{Serialize, Archive, ser::{serializers::{CompositeSerializer, AlignedSerializer, FallbackScratch, HeapScratch, AllocScratch, SharedSerializeMap}}, Deserialize, AlignedVec, validation::validators::DefaultValidator, Infallible};
 
-use crate::NodeID;
+use crate::{NodeID, RelayStream};
 
 /// Configures the encryption of the network.
 #[derive(Clone)]
@@ -54,17 +54,43 @@ pub trait Network: fmt::Debug + Resource + Clone + 'static {
 		persistent_state: Option<Self::PersistentState>,
 	);
 
+	/// Open a connection with a remote that is dialing us at the same time, used to get through NATs on both sides (hole punching).
+	/// Dials `net_address` after `delay` from the address remotes see this node at, the connection is passed to the node like with `connect()`.
+	fn punch(&self, remote_id: NodeID, net_address: Self::Address, delay: Duration);
+
+	/// Establish a connection over a circuit relayed by the node at `relay_address`, used when the remote can't be connected to directly.
+	/// The handshake is run over `stream` and the connection is passed to the node like with `connect()`, `requested` is set on the end that asked for the circuit.
+	fn relayed(&self, remote_id: NodeID, relay_address: Self::Address, stream: RelayStream<Self>, requested: bool);
+
 	/// Listen to some new set of addresses
 	fn listen(&self, addrs: impl Iterator<Item = Self::Address>);
 
+	/// Stop listening for and establishing connections. Connections already passed to the node are not affected.
+	fn shutdown(&self);
+
+	/// Check that the node `remote_id` accepts connections at `net_address`, replies with the result. Must not establish a session on either side.
+	/// Used to verify the public addresses of other nodes (dial-back).
+	fn probe(&self, remote_id: NodeID, net_address: Self::Address) -> oneshot::Receiver<bool>;
+
+	/// Whether both addresses belong to the same host (i.e. have the same IP), ports may differ.
+	fn same_host(a: &Self::Address, b: &Self::Address) -> bool;
+
 	/// Given a public address reported back by a connected node, try to figure out what addresses this node could be listening publically on.
 	fn predict_public_addresses<'a>(addr: &'a Self::Address, config: &'a Self::ListenerConfig) -> impl Iterator<Item = Self::Address> + 'a;
+
+	/// Sign some data with the local node's private key, used to create a `SignedPeerRecord`. Must be an asymmetric signature, anyone who can sign for a public key can forge records for its NodeID.
+	fn sign(keys: &EncryptionKeys<Self>, data: &[u8]) -> Vec<u8>;
+
+	/// Verify that `signature` over `data` was created by `sign` with the private key belonging to `public_key`.
+	fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool;
 }
 
 /// Represents an encrypted two-way bytestream to another computer, identified by its NodeID and arbitrary network address.
 #[derive(Component)]
 pub struct Connection<Net: Network> {
 	pub incoming_address: Net::Address,
+	/// Address of the local end of the connection if known, compared with the address the remote sees to detect NAT.
+	pub local_address: Option<Net::Address>,
 	pub remote_pub_key: Net::NodePubKey,
 	pub persistent_state: Net::PersistentState,
 	pub read: Net::Read,
@@ -8,7 +8,7 @@ use pin_project::pin_project;
 use rkyv::{AlignedVec, Archive, Archived, Deserialize, Infallible, Serialize};
 use rkyv_codec::{RkyvCodecError, RkyvWriter, archive_stream, length_codec::U32Length};
 
-use crate::{net::Network, NetworkCoord, NCSystemPacket, session::PingID, DiscoveryPacket, TraversalPacket};
+use crate::{net::Network, NetworkCoord, NCSystemPacket, session::PingID, DiscoveryPacket, DhtPacket, HolePunchPacket, RoutingSystemPacket, GossipPacket, TraversalPacket};
 
 /// Acknowledging node packet
 #[derive(Debug, Archive, Serialize, Deserialize, Clone)]
@@ -26,6 +26,14 @@ pub struct PingingNodePacket<Net: Network> {
 #[archive_attr(derive(CheckBytes), check_bytes(bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: bytecheck::Error"))]
 pub enum NodePacket<Net: Network> {
 	DiscoveryPacket(DiscoveryPacket<Net>),
+	// Subpacket for the distributed hash table
+	DhtPacket(DhtPacket<Net>),
+	// Subpacket for coordinating hole punches
+	HolePunchPacket(HolePunchPacket<Net>),
+	// Subpacket for relayed circuits
+	RoutingSystemPacket(RoutingSystemPacket),
+	// Subpacket for topic-based publish/subscribe
+	GossipPacket(GossipPacket),
 	// Subpacket for all things network-coordinate-system
 	NCSystemPacket(NCSystemPacket),
 
@@ -0,0 +1,67 @@
+//! Peer records are statements signed by a node of which addresses it can be reached at. Other nodes relay them (i.e. in a `PeerList`) but can't forge or alter them without the node's private key, because a node's NodeID is the hash of the public key the record is verified with.
+//! This relies on `Network::sign` being an asymmetric signature, `TcpNoenc` uses ed25519.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Serialize, Deserialize};
+use thiserror::Error;
+
+use crate::{Network, NodeID, EncryptionKeys};
+
+#[derive(Debug, Error)]
+pub enum PeerRecordError {
+	#[error("public key of peer record does not hash to {0:?}")]
+	WrongNodeID(NodeID),
+	#[error("invalid signature on peer record for {0:?}")]
+	InvalidSignature(NodeID),
+	#[error("peer record for {0:?} contains no addresses")]
+	NoAddresses(NodeID),
+}
+
+/// Addresses a node can be connected to at, signed by that node.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+#[archive_attr(derive(CheckBytes))]
+pub struct SignedPeerRecord<Net: Network> {
+	pub node_id: NodeID,
+	/// Dialable addresses, preferred address first.
+	pub addresses: Vec<Net::Address>,
+	/// Records with a higher sequence number replace older ones.
+	pub seq: u64,
+	pub public_key: Vec<u8>,
+	pub signature: Vec<u8>,
+}
+
+impl<Net: Network> SignedPeerRecord<Net> {
+	/// Create and sign a record for the local node. The current time is used as sequence number so that records created after a restart still replace old ones.
+	pub fn new(keys: &EncryptionKeys<Net>, node_id: NodeID, addresses: Vec<Net::Address>) -> Self {
+		let seq = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time|time.as_micros() as u64);
+		let signature = Net::sign(keys, &Self::signed_data(&node_id, &addresses, seq));
+		Self { node_id, addresses, seq, public_key: keys.public_key.as_ref().to_vec(), signature }
+	}
+
+	// Bytes covered by the signature
+	fn signed_data(node_id: &NodeID, addresses: &[Net::Address], seq: u64) -> Vec<u8> {
+		serde_json::to_vec(&(node_id, addresses, seq)).expect("peer record fields should serialize")
+	}
+
+	/// Check that the record was signed by the node it describes.
+	pub fn verify(&self) -> Result<(), PeerRecordError> {
+		if NodeID::hash(&self.public_key) != self.node_id {
+			return Err(PeerRecordError::WrongNodeID(self.node_id.clone()));
+		}
+		if !Net::verify(&self.public_key, &Self::signed_data(&self.node_id, &self.addresses, self.seq), &self.signature) {
+			return Err(PeerRecordError::InvalidSignature(self.node_id.clone()));
+		}
+		if self.addresses.is_empty() {
+			return Err(PeerRecordError::NoAddresses(self.node_id.clone()));
+		}
+		Ok(())
+	}
+
+	/// Address to dial the node at, only call on verified records.
+	pub fn address(&self) -> &Net::Address {
+		&self.addresses[0]
+	}
+}
@@ -23,6 +23,22 @@ pub enum SessionEvent<Net: Network> {
 	LatencyMeasurement(Duration),
 	/// Send Traversal Packet to main thread to be sent
 	Traversal(TraversalPacket),
+	/// Session task has exited
+	Closed(SessionCloseReason),
+}
+
+/// Why a session was closed, passed to `NodeSystem::on_session_closed`
+#[derive(Debug, Clone, Error)]
+pub enum SessionCloseReason {
+	/// `Session` was dropped locally
+	#[error("closed locally")]
+	Closed,
+	/// Session task errored, i.e. the remote disconnected or sent an invalid packet
+	#[error("session error: {0}")]
+	Error(String),
+	/// A new session to the same remote was established, the old one is dropped
+	#[error("replaced by a new session")]
+	Replaced,
 }
 
 /// Interact with remote Session
@@ -31,6 +47,8 @@ pub enum SessionAction<Net: Network> {
 	Ping(Option<usize>),
 	// Send a Packet to remote
 	Packet(NodePacket<Net>),
+	// Close the connection, the node is notified through `SessionEvent::Closed`
+	Close,
 }
 
 #[derive(Error, Debug)]
@@ -49,6 +67,8 @@ pub enum SessionError<Net: Network> {
 #[derive(Component, Clone)]
 pub struct SessionInfo<Net: Network> {
 	pub net_address: Net::Address,
+	/// Local address of the connection, if known.
+	pub local_address: Option<Net::Address>,
 	pub remote_pub_key: Option<Net::NodePubKey>,
 	pub persistent_state: Option<Net::PersistentState>,
 }
@@ -65,9 +85,14 @@ impl<Net: Network> Session<Net> {
 		let (action_sender, action_receiver) = unbounded();
 		// Spawn session task with connection
 		task::spawn(async move {
-			if let Err(err) = SessionState::run(connection, shared, entity_id, session_event_sender, action_receiver).await {
-				log::warn!("Session for node {entity_id:?} closed with error: {err}");
-			}
+			let reason = match SessionState::run(connection, shared, entity_id, session_event_sender.clone(), action_receiver).await {
+				Ok(()) => SessionCloseReason::Closed,
+				Err(err) => {
+					log::warn!("Session for node {entity_id:?} closed with error: {err}");
+					SessionCloseReason::Error(err.to_string())
+				}
+			};
+			let _ = session_event_sender.unbounded_send(EntitySessionEvent { entity: entity_id, event: SessionEvent::Closed(reason) });
 		});
 		Session { action_sender }
 	}
@@ -85,7 +110,7 @@ impl<Net: Network> Session<Net> {
 
 struct SessionState<Net: Network> {
 	packet_write: PacketWrite<Net>,
-	ping_tracker: PingTracker<64>,
+	ping_tracker: PingTracker,
 	event_sender: UnboundedSender<EntitySessionEvent<Net>>,
 	entity_id: Entity,
 	ping_countdown: usize,
@@ -94,17 +119,32 @@ struct SessionState<Net: Network> {
 
 pub struct SessionSharedState<Net: Network> {
 	pub self_node_id: NodeID,
+	pub config: SessionConfig,
 	pub _net: PhantomData<Net>,
 }
 
+/// Configures session tasks.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+	/// Maximum number of unacknowledged pings, older pings are forgotten once exceeded.
+	pub max_pending_pings: u8,
+}
+impl Default for SessionConfig {
+	fn default() -> Self {
+		Self { max_pending_pings: 64 }
+	}
+}
+
 impl<Net: Network> SessionState<Net> {
 	/// Run `Session` with network `Connection`
 	async fn run(conn: Connection<Net>, shared: Arc<ArcSwap<SessionSharedState<Net>>>, entity_id: Entity, event_sender: UnboundedSender<EntitySessionEvent<Net>>, mut action_receiver: UnboundedReceiver<SessionAction<Net>>) -> Result<(), SessionError<Net>> {
 		let mut packet_read = PacketRead::<Net>::new(conn.read);
 
+		let max_pending_pings = shared.load().config.max_pending_pings;
 		let mut state = SessionState {
 			packet_write: PacketWrite::<Net>::new(conn.write),
-			ping_tracker: PingTracker::default(),
+			ping_tracker: PingTracker::new(max_pending_pings),
 			event_sender,
 			entity_id,
 			ping_countdown: 0,
@@ -116,11 +156,18 @@ impl<Net: Network> SessionState<Net> {
 				packet = packet_read.read_packet().fuse() => {
 					state.handle_ping_packet(packet?).await?;
 				}
-				action = action_receiver.next() => {
-					if let Some(action) = action {
-						state.handle_session_action(action).await?;
+				action = action_receiver.next() => match action {
+					Some(SessionAction::Close) => {
+						state.packet_write.close().await?;
+						break
 					}
-				}
+					Some(action) => state.handle_session_action(action).await?,
+					// `Session` was dropped, close connection
+					None => {
+						state.packet_write.close().await?;
+						break
+					}
+				},
 				complete => break,
 			}
 		}
@@ -213,6 +260,7 @@ impl<Net: Network> SessionState<Net> {
 				}
 
 			},
+			SessionAction::Close => unreachable!("handled in SessionState::run"),
 		}
 		Ok(())
 	}
@@ -221,23 +269,14 @@ impl<Net: Network> SessionState<Net> {
 
 /// High-performance Ping Tracker
 #[derive(Debug, Clone)]
-struct PingTracker<const MAX_PENDING: u8>
-	where [(); MAX_PENDING as usize]: Sized + std::fmt::Debug
-{
-	// Slotmap-like staticly-sized queue for maximum performance! :D
+struct PingTracker {
+	// Slotmap-like fixed-size queue for maximum performance! :D
 	// PingSlot is either used Instant(Instant), or stores the next free slot in the list.
 	// u8 represents the slot's current generation.
-	ping_queue: [(PingSlot, u8); MAX_PENDING as usize],
+	ping_queue: Box<[(PingSlot, u8)]>,
 	// Index into ping_queue, represents next free index.
 	next_free_slot: u8,
 }
-impl<const MAX_PENDING: u8> Default for PingTracker<MAX_PENDING>
-	where [(); MAX_PENDING as usize]: Sized + std::fmt::Debug
-{
-	fn default() -> Self {
-		Self { ping_queue: [(PingSlot::default(), 0); MAX_PENDING as usize], next_free_slot: Default::default() }
-	}
-}
 /// Unique identifier for a ping. Used with `PingTracker`
 #[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 #[archive_attr(derive(bytecheck::CheckBytes))]
@@ -261,13 +300,16 @@ enum PingTrackerError {
 	#[error("invalid slot index: {0}")]
 	InvalidSlotIndex(u8)
 }
-impl<const MAX_PENDING: u8> PingTracker<MAX_PENDING>
-	where [(); MAX_PENDING as usize]: Sized + std::fmt::Debug 
-{
+impl PingTracker {
+	// Create tracker that can keep track of up to `max_pending` pings at once.
+	pub fn new(max_pending: u8) -> Self {
+		let max_pending = max_pending.max(1);
+		Self { ping_queue: vec![(PingSlot::default(), 0); max_pending as usize].into_boxed_slice(), next_free_slot: 0 }
+	}
 	// Generate a unique id for this ping. Records the current time and waits for call to record_unique_id with the returned id.
 	pub fn gen_unique_id(&mut self) -> PingID {
 		// Wrap next slot pointer around to zero if not enough free slots.
-		self.next_free_slot = self.next_free_slot % MAX_PENDING;
+		self.next_free_slot = self.next_free_slot % self.ping_queue.len() as u8;
 
 		// Get slot. Slot map be Init, have recorded Instant, or were previously cleared and store the next free slot. 
 		let (slot, generation) = &mut self.ping_queue[self.next_free_slot as usize];
@@ -325,10 +367,10 @@ use super::*;
 
 	#[test]
 	fn test_ping_tracker() {
-		let mut tracker = PingTracker::<5>::new();
+		let mut tracker = PingTracker::new(5);
 		let ping_id = tracker.gen_unique_id();
 		sleep(Duration::from_millis(10));
-		tracker.record_unique_id().unwrap();
+		tracker.record_unique_id(ping_id).unwrap();
 
 		let first_ping_id = tracker.gen_unique_id();
 		for _ in 0..4 {
@@ -1,11 +1,21 @@
 
 mod discovery;
+mod bootstrap;
+mod dht;
+mod hole_punch;
+mod gossip;
+mod address_book;
 mod latency_metrics;
 mod nc_system;
 mod routing;
 mod logging;
 
 pub use discovery::*;
+pub use bootstrap::*;
+pub use dht::*;
+pub use hole_punch::*;
+pub use gossip::*;
+pub use address_book::*;
 pub use latency_metrics::*;
 pub use nc_system::*;
 pub use routing::*;
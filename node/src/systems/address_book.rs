@@ -0,0 +1,218 @@
+//! This node system remembers peers across restarts. It records the dialable addresses, latency and coordinates of every remote in a file so that the `BootstrapSystem` can rejoin the network without seed nodes.
+
+use std::{collections::HashMap, marker::PhantomData, path::{PathBuf, Path}, time::{Duration, Instant, SystemTime}};
+
+use bevy_ecs::prelude::*;
+
+use crate::{NodeSystem, Network, NodeID, NodeConfig, Remote, Latency, PublicAddress, SignedPeerRecord, LatencyMetrics, Coordinates, ConnReceiver, Relay, session::{Session, SessionInfo}};
+
+/// Configures where and how long peers are remembered.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AddressBookConfig {
+	/// File the address book is loaded from and saved to. If not set, the address book is only kept in memory.
+	pub path: Option<PathBuf>,
+	/// Maximum number of peers remembered, least recently seen peers are forgotten first.
+	pub max_entries: usize,
+	/// Peers that haven't been seen for this long are forgotten.
+	pub max_age: Duration,
+	/// How often the address book is saved.
+	pub save_interval: Duration,
+}
+impl Default for AddressBookConfig {
+	fn default() -> Self {
+		Self {
+			path: None,
+			max_entries: 256,
+			max_age: Duration::from_secs(7 * 24 * 60 * 60),
+			save_interval: Duration::from_secs(30),
+		}
+	}
+}
+
+/// Everything known about a peer.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+pub struct PeerRecord<Net: Network> {
+	pub node_id: NodeID,
+	/// Addresses the peer can be dialed at, most recently learned first.
+	pub addresses: Vec<Net::Address>,
+	/// Last time there was an active session with the peer (or when it was first learned of).
+	pub last_seen: SystemTime,
+	/// Last measured latency in microseconds.
+	pub latency: Option<Latency>,
+	pub coordinates: Option<Coordinates>,
+	/// Latest verified record signed by the peer, relayed to other nodes in `PeerList`s.
+	#[serde(default)]
+	pub signed: Option<SignedPeerRecord<Net>>,
+}
+impl<Net: Network> PeerRecord<Net> {
+	fn new(node_id: NodeID) -> Self {
+		Self { node_id, addresses: Vec::new(), last_seen: SystemTime::now(), latency: None, coordinates: None, signed: None }
+	}
+	/// Store a verified signed record and its addresses if it is newer than the known one. Returns false if the record is outdated.
+	pub fn add_signed(&mut self, record: SignedPeerRecord<Net>) -> bool {
+		if self.signed.as_ref().map_or(false, |known|known.seq >= record.seq) { return false }
+		for addr in record.addresses.iter().rev() {
+			self.add_address(addr.clone());
+		}
+		self.signed = Some(record);
+		true
+	}
+	/// Record a dialable address, moving it to the front if already known.
+	pub fn add_address(&mut self, addr: Net::Address) {
+		self.addresses.retain(|known|*known != addr);
+		self.addresses.insert(0, addr);
+		self.addresses.truncate(MAX_ADDRESSES_PER_PEER);
+	}
+}
+
+const MAX_ADDRESSES_PER_PEER: usize = 4;
+
+/// Known peers, persisted to `AddressBookConfig::path`.
+#[derive(Resource)]
+pub struct AddressBook<Net: Network> {
+	peers: HashMap<NodeID, PeerRecord<Net>>,
+	config: AddressBookConfig,
+	last_save: Instant,
+}
+impl<Net: Network> AddressBook<Net> {
+	fn load(config: AddressBookConfig) -> Self {
+		let records = match &config.path {
+			Some(path) if path.exists() => match Self::read_file(path) {
+				Ok(records) => records,
+				Err(err) => {
+					log::warn!("address book: failed to load {path:?}: {err}");
+					Vec::new()
+				}
+			},
+			_ => Vec::new(),
+		};
+		log::info!("address book: loaded {} peers", records.len());
+		let mut book = Self {
+			peers: records.into_iter().map(|record|(record.node_id.clone(), record)).collect(),
+			config,
+			last_save: Instant::now(),
+		};
+		book.prune();
+		book
+	}
+	fn read_file(path: &Path) -> Result<Vec<PeerRecord<Net>>, Box<dyn std::error::Error>> {
+		Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+	}
+	/// Write address book to `AddressBookConfig::path` (if set).
+	pub fn save(&mut self) {
+		self.last_save = Instant::now();
+		let Some(path) = &self.config.path else { return };
+		let records = self.peers.values().collect::<Vec<&PeerRecord<Net>>>();
+		// Write to temporary file first so the address book isn't lost if writing fails halfway through
+		let tmp_path = path.with_extension("tmp");
+		let result: Result<(), Box<dyn std::error::Error>> = (|| {
+			serde_json::to_writer(std::fs::File::create(&tmp_path)?, &records)?;
+			std::fs::rename(&tmp_path, path)?;
+			Ok(())
+		})();
+		if let Err(err) = result {
+			log::error!("address book: failed to save to {path:?}: {err}");
+		}
+	}
+	// Forget peers that are too old or over the size limit
+	fn prune(&mut self) {
+		let max_age = self.config.max_age;
+		self.peers.retain(|_, record| record.last_seen.elapsed().map_or(true, |age|age < max_age));
+		if self.peers.len() > self.config.max_entries {
+			let mut by_age = self.peers.values().map(|record|(record.last_seen, record.node_id.clone())).collect::<Vec<_>>();
+			by_age.sort_by(|(a, _), (b, _)|b.cmp(a));
+			for (_, node_id) in by_age.into_iter().skip(self.config.max_entries) {
+				self.peers.remove(&node_id);
+			}
+		}
+	}
+	pub fn get(&self, node_id: &NodeID) -> Option<&PeerRecord<Net>> {
+		self.peers.get(node_id)
+	}
+	// Record of a peer, created if unknown. If the address book is full, the least recently seen peer is forgotten to make room.
+	fn entry(&mut self, node_id: &NodeID) -> &mut PeerRecord<Net> {
+		if !self.peers.contains_key(node_id) && self.peers.len() >= self.config.max_entries {
+			if let Some(oldest) = self.peers.values().min_by_key(|record|record.last_seen).map(|record|record.node_id.clone()) {
+				self.peers.remove(&oldest);
+			}
+		}
+		self.peers.entry(node_id.clone()).or_insert_with(||PeerRecord::new(node_id.clone()))
+	}
+	/// Record a verified signed record of a peer, i.e. from a `PeerList`. Returns false if a newer record is already known.
+	pub fn add_signed(&mut self, record: SignedPeerRecord<Net>) -> bool {
+		self.entry(&record.node_id.clone()).add_signed(record)
+	}
+	/// Peers with at least one known address, most recently seen first.
+	pub fn recently_seen(&self) -> impl Iterator<Item = &PeerRecord<Net>> {
+		let mut records = self.peers.values().filter(|record|!record.addresses.is_empty()).collect::<Vec<&PeerRecord<Net>>>();
+		records.sort_by(|a, b|b.last_seen.cmp(&a.last_seen));
+		records.into_iter()
+	}
+}
+
+pub struct AddressBookSystem<Net: Network> {
+	_net: PhantomData<Net::Address>,
+}
+
+impl<Net: Network> NodeSystem for AddressBookSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().address_book.clone();
+		world.insert_resource(AddressBook::<Net>::load(config));
+	}
+
+	fn register_systems(schedule: &mut Schedule) {
+		schedule.add_system(update_address_book::<Net>);
+	}
+
+	fn on_tick(world: &mut World) {
+		let mut book = world.resource_mut::<AddressBook<Net>>();
+		if book.last_save.elapsed() >= book.config.save_interval {
+			book.prune();
+			book.save();
+		}
+	}
+
+	fn on_shutdown(world: &mut World) {
+		world.resource_mut::<AddressBook<Net>>().save();
+	}
+}
+
+// Record the state of remotes with an active session
+fn update_address_book<Net: Network>(
+	mut book: ResMut<AddressBook<Net>>,
+	// The address of a relayed session is the relay's, and its latency includes the relay
+	remotes: Query<(&Remote, &SessionInfo<Net>, Option<&PublicAddress<Net>>, Option<&ConnReceiver>, Option<&LatencyMetrics>, Option<&Coordinates>), (With<Session<Net>>, Without<Relay>)>,
+	changed: Query<(), (With<Session<Net>>, Or<(Added<Session<Net>>, Changed<PublicAddress<Net>>, Changed<LatencyMetrics>, Changed<Coordinates>)>)>,
+) {
+	if changed.is_empty() { return }
+	for (remote, info, public_addr, receiver, metrics, coords) in &remotes {
+		let record = book.entry(&remote.id);
+		record.last_seen = SystemTime::now();
+		// Address of a session we initiated can be dialed again, address of an incoming session is usually not the address the remote listens on.
+		if receiver.is_some() { record.add_address(info.net_address.clone()) }
+		if let Some(public_addr) = public_addr { record.add_signed(public_addr.record.clone()); }
+		if let Some(metrics) = metrics { record.latency = Some(metrics.latest_latency()) }
+		if let Some(coords) = coords { record.coordinates = Some(coords.clone()) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_net::{TestNet, record, node_id};
+
+	#[test]
+	fn limit_entries() {
+		let mut book = AddressBook::<TestNet>::load(AddressBookConfig { max_entries: 2, ..Default::default() });
+		book.add_signed(record(1, 1001));
+		book.add_signed(record(2, 1002));
+		book.peers.get_mut(&node_id(1)).unwrap().last_seen -= Duration::from_secs(60);
+		// Adding a third peer forgets the least recently seen one
+		book.add_signed(record(3, 1003));
+		assert_eq!(book.peers.len(), 2);
+		assert!(book.get(&node_id(1)).is_none());
+		assert!(book.get(&node_id(2)).is_some() && book.get(&node_id(3)).is_some());
+	}
+}
@@ -0,0 +1,72 @@
+//! This node system joins the network by connecting to a list of known seed nodes and to peers remembered in the `AddressBook`. Once enough sessions exist, the `DiscoverySystem` takes over growing the node's neighbourhood.
+
+use std::{time::{Duration, Instant}, marker::PhantomData};
+
+use bevy_ecs::prelude::*;
+
+use crate::{NodeSystem, Network, NodeID, NodeConfig, Remote, AddressBook, session::Session};
+
+/// Configures which nodes are dialed to join the network.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""), default)]
+pub struct BootstrapConfig<Net: Network> {
+	/// Seed nodes to connect to on startup.
+	pub seeds: Vec<(NodeID, Net::Address)>,
+	/// Keep dialing seeds while there are less active sessions than this.
+	pub min_sessions: usize,
+	/// Time to wait between attempts to dial the seeds.
+	pub retry_interval: Duration,
+}
+impl<Net: Network> Default for BootstrapConfig<Net> {
+	fn default() -> Self {
+		Self { seeds: Vec::new(), min_sessions: 3, retry_interval: Duration::from_secs(5) }
+	}
+}
+
+#[derive(Resource)]
+struct BootstrapState<Net: Network> {
+	config: BootstrapConfig<Net>,
+	last_attempt: Option<Instant>,
+}
+
+pub struct BootstrapSystem<Net: Network> {
+	_net: PhantomData<Net::Address>,
+}
+
+impl<Net: Network> NodeSystem for BootstrapSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().bootstrap.clone();
+		world.insert_resource(BootstrapState::<Net> { config, last_attempt: None });
+	}
+
+	// Dial seeds and remembered peers we don't have a session with until there are enough sessions
+	fn on_tick(world: &mut World) {
+		let connected = world.query_filtered::<&Remote, With<Session<Net>>>().iter(world).map(|remote|remote.id.clone()).collect::<Vec<NodeID>>();
+		let state = world.resource::<BootstrapState<Net>>();
+		if connected.len() >= state.config.min_sessions.min(world.resource::<NodeConfig<Net>>().max_sessions) { return }
+		if state.last_attempt.map_or(false, |last|last.elapsed() < state.config.retry_interval) { return }
+
+		let self_id = &world.resource::<NodeConfig<Net>>().node_id;
+		let dialable = |id: &NodeID| id != self_id && !connected.contains(id);
+
+		// Dial all seeds plus as many remembered peers as there are missing sessions, most recently seen first.
+		let mut candidates = state.config.seeds.iter().filter(|(id, _)|dialable(id)).cloned().collect::<Vec<(NodeID, Net::Address)>>();
+		if let Some(book) = world.get_resource::<AddressBook<Net>>() {
+			let missing = state.config.min_sessions - connected.len();
+			let remembered = book.recently_seen()
+				.filter(|record|dialable(&record.node_id) && !candidates.iter().any(|(id, _)|*id == record.node_id))
+				.take(missing)
+				.map(|record|(record.node_id.clone(), record.addresses[0].clone()))
+				.collect::<Vec<(NodeID, Net::Address)>>();
+			candidates.extend(remembered);
+		}
+		if candidates.is_empty() { return }
+
+		let net = world.resource::<Net>();
+		for (id, addr) in candidates {
+			log::info!("bootstrap: dialing {id:?} at {addr} ({}/{} sessions)", connected.len(), state.config.min_sessions);
+			net.connect(id, addr, None, None);
+		}
+		world.resource_mut::<BootstrapState<Net>>().last_attempt = Some(Instant::now());
+	}
+}
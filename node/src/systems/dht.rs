@@ -0,0 +1,467 @@
+//! This node system implements a Kademlia-style distributed hash table that maps NodeIDs to the signed peer records of nodes.
+//! Nodes are stored in k-buckets by the XOR distance of their NodeID to our own and are found by iteratively sending `FindNode` to the nodes closest to the target.
+//! Lookups only query nodes we already have a session with, they never dial new nodes (which would count toward `NodeConfig::max_sessions`).
+
+use std::{collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+
+use bevy_ecs::prelude::*;
+use rkyv::{Archive, Serialize, Deserialize};
+use bytecheck::CheckBytes;
+
+use crate::{NodeSystem, NodePacket, NodeEvent, Network, NodeID, NodeConfig, RemoteIDMap, PublicAddress, SignedPeerRecord, KnownPubAddr, EventSender, Remote, session::Session};
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+#[archive_attr(derive(CheckBytes))]
+pub enum DhtPacket<Net: Network> {
+	/// Request the nodes closest to `target` that the receiver knows of. Contains the requester's own record (if known) so the receiver can add it to its routing table.
+	FindNode {
+		lookup_id: usize,
+		target: NodeID,
+		requester: Option<SignedPeerRecord<Net>>,
+	},
+	/// Sent back in response to `FindNode`.
+	Nodes {
+		lookup_id: usize,
+		nodes: Vec<SignedPeerRecord<Net>>,
+	},
+}
+impl<Net: Network> From<DhtPacket<Net>> for NodePacket<Net> {
+	fn from(value: DhtPacket<Net>) -> Self {
+		NodePacket::DhtPacket(value)
+	}
+}
+
+/// Configures the routing table and lookups.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DhtConfig {
+	/// Maximum number of nodes per k-bucket, also the number of nodes returned by `FindNode`.
+	pub k: usize,
+	/// Number of `FindNode` requests a lookup has in flight at once.
+	pub alpha: usize,
+	/// A queried node that doesn't respond in this time is skipped.
+	pub query_timeout: Duration,
+	/// Lookups that haven't finished in this time fail.
+	pub lookup_timeout: Duration,
+	/// How often the routing table is refreshed by looking up our own NodeID and a random NodeID.
+	pub refresh_interval: Duration,
+	/// Nodes that haven't been seen for this long can be replaced in a full k-bucket and are removed on refresh.
+	pub entry_timeout: Duration,
+}
+impl Default for DhtConfig {
+	fn default() -> Self {
+		Self {
+			k: 20,
+			alpha: 3,
+			query_timeout: Duration::from_secs(5),
+			lookup_timeout: Duration::from_secs(30),
+			refresh_interval: Duration::from_secs(60),
+			entry_timeout: Duration::from_secs(10 * 60),
+		}
+	}
+}
+
+/// XOR distance between two NodeIDs, compared as a big-endian number.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Distance(Vec<u8>);
+impl Distance {
+	pub fn between(a: &NodeID, b: &NodeID) -> Self {
+		Self(a.as_bytes().iter().zip(b.as_bytes()).map(|(a, b)|a ^ b).collect())
+	}
+	/// Number of leading zero bits, nodes that share a longer prefix with our NodeID go in higher buckets. `None` if the distance is zero.
+	fn bucket_index(&self) -> Option<usize> {
+		let zero_bytes = self.0.iter().take_while(|byte|**byte == 0).count();
+		self.0.get(zero_bytes).map(|byte|zero_bytes * 8 + byte.leading_zeros() as usize)
+	}
+}
+
+struct DhtEntry<Net: Network> {
+	record: SignedPeerRecord<Net>,
+	last_seen: Instant,
+}
+
+/// Known nodes, sorted into k-buckets by distance to our own NodeID.
+#[derive(Resource)]
+pub struct RoutingTable<Net: Network> {
+	own_id: NodeID,
+	/// Buckets ordered by prefix length shared with `own_id`, entries ordered least recently seen first.
+	buckets: Vec<Vec<DhtEntry<Net>>>,
+	k: usize,
+	entry_timeout: Duration,
+}
+impl<Net: Network> RoutingTable<Net> {
+	fn new(own_id: NodeID, config: &DhtConfig) -> Self {
+		Self { own_id, buckets: Vec::new(), k: config.k, entry_timeout: config.entry_timeout }
+	}
+	/// Insert or refresh a verified record. If the bucket is full, the least recently seen node is only replaced if it hasn't been seen for `DhtConfig::entry_timeout`.
+	pub fn insert(&mut self, record: SignedPeerRecord<Net>) {
+		let Some(index) = Distance::between(&self.own_id, &record.node_id).bucket_index() else { return };
+		if self.buckets.len() <= index {
+			self.buckets.resize_with(index + 1, Vec::new);
+		}
+		let bucket = &mut self.buckets[index];
+		if let Some(pos) = bucket.iter().position(|entry|entry.record.node_id == record.node_id) {
+			let mut entry = bucket.remove(pos);
+			if record.seq >= entry.record.seq { entry.record = record }
+			entry.last_seen = Instant::now();
+			bucket.push(entry);
+			return;
+		}
+		if bucket.len() >= self.k {
+			if bucket[0].last_seen.elapsed() < self.entry_timeout { return }
+			bucket.remove(0);
+		}
+		bucket.push(DhtEntry { record, last_seen: Instant::now() });
+	}
+	pub fn get(&self, node_id: &NodeID) -> Option<&SignedPeerRecord<Net>> {
+		let index = Distance::between(&self.own_id, node_id).bucket_index()?;
+		self.buckets.get(index)?.iter().find(|entry|entry.record.node_id == *node_id).map(|entry|&entry.record)
+	}
+	/// Up to `count` known nodes closest to `target`.
+	pub fn closest(&self, target: &NodeID, count: usize) -> Vec<&SignedPeerRecord<Net>> {
+		let mut records = self.buckets.iter().flatten().map(|entry|(Distance::between(target, &entry.record.node_id), &entry.record)).collect::<Vec<_>>();
+		records.sort_by(|(a, _), (b, _)|a.cmp(b));
+		records.into_iter().take(count).map(|(_, record)|record).collect()
+	}
+	pub fn len(&self) -> usize {
+		self.buckets.iter().map(|bucket|bucket.len()).sum()
+	}
+	// Remove nodes that haven't been seen for `entry_timeout`
+	fn prune(&mut self) {
+		let timeout = self.entry_timeout;
+		for bucket in &mut self.buckets {
+			bucket.retain(|entry|entry.last_seen.elapsed() < timeout);
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum QueryState {
+	New,
+	/// `FindNode` was sent.
+	Queried(Instant),
+	Responded,
+	Failed,
+}
+
+struct Lookup<Net: Network> {
+	target: NodeID,
+	/// Nodes learned of during the lookup, closest to the target first.
+	candidates: BTreeMap<Distance, (SignedPeerRecord<Net>, QueryState)>,
+	/// Connect to the target once found.
+	connect: bool,
+	/// Send `NodeEvent::LookupResult` once finished.
+	report: bool,
+	created: Instant,
+}
+
+#[derive(Resource)]
+struct DhtState<Net: Network> {
+	lookups: HashMap<usize, Lookup<Net>>,
+	last_refresh: Instant,
+}
+
+pub struct DhtSystem<Net: Network> {
+	_net: std::marker::PhantomData<Net::Address>,
+}
+
+impl<Net: Network> DhtSystem<Net> {
+	/// Start looking up the peer record of `target`. If `connect` is set, the target is dialed once found. If `report` is set, the result is sent as `NodeEvent::LookupResult`.
+	pub fn lookup(world: &mut World, target: NodeID, connect: bool, report: bool) {
+		// Join a running lookup for the same target
+		let mut state = world.resource_mut::<DhtState<Net>>();
+		if let Some(lookup) = state.lookups.values_mut().find(|lookup|lookup.target == target) {
+			lookup.connect |= connect;
+			lookup.report |= report;
+			return;
+		}
+
+		let k = world.resource::<DhtConfig>().k;
+		let candidates = world.resource::<RoutingTable<Net>>().closest(&target, k).into_iter()
+			.map(|record|(Distance::between(&target, &record.node_id), (record.clone(), QueryState::New)))
+			.collect::<BTreeMap<Distance, (SignedPeerRecord<Net>, QueryState)>>();
+		log::debug!("dht: looking up {target:?} starting from {} nodes", candidates.len());
+		let lookup_id = rand::random::<usize>();
+		world.resource_mut::<DhtState<Net>>().lookups.insert(lookup_id, Lookup { target, candidates, connect, report, created: Instant::now() });
+		advance_lookup::<Net>(world, lookup_id);
+	}
+}
+
+impl<Net: Network> NodeSystem for DhtSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>();
+		let (dht_config, own_id) = (config.dht.clone(), config.node_id.clone());
+		world.insert_resource(RoutingTable::<Net>::new(own_id, &dht_config));
+		world.insert_resource(dht_config);
+		world.insert_resource(DhtState::<Net> { lookups: HashMap::new(), last_refresh: Instant::now() });
+	}
+
+	fn register_systems(schedule: &mut Schedule) {
+		schedule.add_system(update_routing_table::<Net>);
+	}
+
+	fn on_tick(world: &mut World) {
+		let config = world.resource::<DhtConfig>().clone();
+
+		// Skip nodes that didn't respond in time and fail lookups that are taking too long
+		let mut lookup_ids = Vec::new();
+		for (lookup_id, lookup) in world.resource_mut::<DhtState<Net>>().lookups.iter_mut() {
+			for (_, query) in lookup.candidates.values_mut() {
+				if let QueryState::Queried(since) = query {
+					if since.elapsed() >= config.query_timeout { *query = QueryState::Failed }
+				}
+			}
+			lookup_ids.push(*lookup_id);
+		}
+		for lookup_id in lookup_ids {
+			advance_lookup::<Net>(world, lookup_id);
+		}
+
+		// Refresh routing table
+		if world.resource::<DhtState<Net>>().last_refresh.elapsed() < config.refresh_interval { return }
+		world.resource_mut::<DhtState<Net>>().last_refresh = Instant::now();
+		let connected = world.query_filtered::<&PublicAddress<Net>, With<Session<Net>>>().iter(world).map(|addr|addr.record.clone()).collect::<Vec<SignedPeerRecord<Net>>>();
+		let mut table = world.resource_mut::<RoutingTable<Net>>();
+		for record in connected {
+			table.insert(record);
+		}
+		table.prune();
+		log::debug!("dht: refreshing routing table with {} nodes", table.len());
+		let own_id = world.resource::<NodeConfig<Net>>().node_id.clone();
+		Self::lookup(world, own_id, false, false);
+		Self::lookup(world, NodeID::hash(&rand::random::<[u8; 32]>()), false, false);
+	}
+
+	type Packet = DhtPacket<Net>;
+
+	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
+		let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+		match packet {
+			DhtPacket::FindNode { lookup_id, target, requester } => {
+				// Remember the requester if it sent its own valid record
+				if let Some(record) = requester {
+					match record.verify() {
+						Ok(()) if record.node_id == remote_id => world.resource_mut::<RoutingTable<Net>>().insert(record),
+						Ok(()) => log::warn!("dht: {remote_id:?} sent FindNode with the record of {:?}", record.node_id),
+						Err(err) => log::warn!("dht: dropping requester record from {remote_id:?}: {err}"),
+					}
+				}
+				let k = world.resource::<DhtConfig>().k;
+				let mut nodes = world.resource::<RoutingTable<Net>>().closest(&target, k + 1).into_iter()
+					.filter(|record|record.node_id != remote_id)
+					.take(k)
+					.cloned()
+					.collect::<Vec<SignedPeerRecord<Net>>>();
+				// Our own record isn't in the routing table
+				if target == world.resource::<NodeConfig<Net>>().node_id {
+					if let Some(own) = world.resource::<KnownPubAddr<Net>>().record() { nodes.insert(0, own.clone()) }
+				}
+				world.get::<Session<Net>>(entity).unwrap().send_packet(DhtPacket::Nodes { lookup_id, nodes }.into());
+			}
+			DhtPacket::Nodes { lookup_id, nodes } => {
+				let own_id = world.resource::<NodeConfig<Net>>().node_id.clone();
+				let k = world.resource::<DhtConfig>().k;
+				let responder = world.get::<PublicAddress<Net>>(entity).map(|addr|addr.record.clone());
+				let mut state = world.resource_mut::<DhtState<Net>>();
+				let Some(lookup) = state.lookups.get_mut(&lookup_id) else { return };
+				// Only accept responses from nodes that were queried by this lookup
+				match lookup.candidates.get_mut(&Distance::between(&lookup.target, &remote_id)) {
+					Some((_, query @ QueryState::Queried(_))) => *query = QueryState::Responded,
+					_ => {
+						log::debug!("dht: unexpected response to lookup {lookup_id} from {remote_id:?}");
+						return;
+					}
+				}
+				// Responses contain at most k nodes, the rest aren't verified so a remote can't make a lookup grow without bound
+				for record in nodes.into_iter().take(k) {
+					if record.node_id == own_id { continue }
+					if let Err(err) = record.verify() {
+						log::warn!("dht: dropping record received from {remote_id:?}: {err}");
+						continue;
+					}
+					lookup.candidates.entry(Distance::between(&lookup.target, &record.node_id)).or_insert((record, QueryState::New));
+				}
+				if let Some(record) = responder {
+					world.resource_mut::<RoutingTable<Net>>().insert(record);
+				}
+				advance_lookup::<Net>(world, lookup_id);
+			}
+		}
+	}
+}
+
+// Query the closest nodes that haven't been queried yet, nodes without a session are skipped. Finishes the lookup once the target is found or the k closest nodes were all queried.
+fn advance_lookup<Net: Network>(world: &mut World, lookup_id: usize) {
+	let config = world.resource::<DhtConfig>().clone();
+	let requester = world.resource::<KnownPubAddr<Net>>().record().cloned();
+
+	let mut state = world.resource_mut::<DhtState<Net>>();
+	let Some(lookup) = state.lookups.get_mut(&lookup_id) else { return };
+	let found = lookup.candidates.get(&Distance::between(&lookup.target, &lookup.target)).is_some();
+	let timed_out = lookup.created.elapsed() >= config.lookup_timeout;
+
+	let closest = lookup.candidates.values().take(config.k);
+	let in_flight = closest.clone().filter(|(_, query)|matches!(query, QueryState::Queried(_))).count();
+	let to_query = closest.filter(|(_, query)|*query == QueryState::New).map(|(record, _)|record.clone()).take(config.alpha.saturating_sub(in_flight)).collect::<Vec<SignedPeerRecord<Net>>>();
+
+	if found || timed_out || (in_flight == 0 && to_query.is_empty()) {
+		finish_lookup::<Net>(world, lookup_id);
+		return;
+	}
+
+	let target = lookup.target.clone();
+	let mut started = Vec::new();
+	for record in to_query {
+		let entity = world.resource::<RemoteIDMap>().map.get(&record.node_id).cloned();
+		let query = match entity.and_then(|entity|world.get::<Session<Net>>(entity)) {
+			Some(session) => {
+				session.send_packet(DhtPacket::FindNode { lookup_id, target: target.clone(), requester: requester.clone() }.into());
+				QueryState::Queried(Instant::now())
+			}
+			None => QueryState::Failed,
+		};
+		started.push((Distance::between(&target, &record.node_id), query));
+	}
+	let mut state = world.resource_mut::<DhtState<Net>>();
+	let Some(lookup) = state.lookups.get_mut(&lookup_id) else { return };
+	for (distance, query) in started {
+		if let Some((_, state)) = lookup.candidates.get_mut(&distance) { *state = query }
+	}
+	// All new candidates may have failed immediately
+	if lookup.candidates.values().take(config.k).all(|(_, query)|matches!(query, QueryState::Responded | QueryState::Failed)) {
+		finish_lookup::<Net>(world, lookup_id);
+	}
+}
+
+fn finish_lookup<Net: Network>(world: &mut World, lookup_id: usize) {
+	let Some(lookup) = world.resource_mut::<DhtState<Net>>().lookups.remove(&lookup_id) else { return };
+	let record = lookup.candidates.get(&Distance::between(&lookup.target, &lookup.target)).map(|(record, _)|record.clone())
+		.or_else(||world.resource::<RoutingTable<Net>>().get(&lookup.target).cloned());
+	let queried = lookup.candidates.values().filter(|(_, query)|*query == QueryState::Responded).count();
+	log::debug!("dht: lookup of {:?} finished after querying {queried} nodes, found: {}", lookup.target, record.is_some());
+
+	if let Some(record) = &record {
+		world.resource_mut::<RoutingTable<Net>>().insert(record.clone());
+		if lookup.connect {
+			let connected = world.resource::<RemoteIDMap>().map.get(&lookup.target).map_or(false, |entity|world.get::<Session<Net>>(*entity).is_some());
+			if !connected {
+				world.resource::<Net>().connect(lookup.target.clone(), record.address().clone(), None, None);
+			}
+		}
+	} else if lookup.connect {
+		log::warn!("dht: failed to connect to {:?}, no address found", lookup.target);
+	}
+	if lookup.report {
+		let addresses = record.map(|record|record.addresses).unwrap_or_default();
+		if let Err(err) = world.resource::<EventSender<Net>>().sender.unbounded_send(NodeEvent::LookupResult(lookup.target, addresses)) {
+			log::error!("dht: failed to send lookup result: {err}");
+		}
+	}
+}
+
+// Add nodes to the routing table once their public address is known
+fn update_routing_table<Net: Network>(
+	mut table: ResMut<RoutingTable<Net>>,
+	changed: Query<&PublicAddress<Net>, (With<Session<Net>>, Changed<PublicAddress<Net>>)>,
+) {
+	for addr in &changed {
+		table.insert(addr.record.clone());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{Distance, RoutingTable, DhtSystem, DhtPacket};
+	use crate::{DhtConfig, NodeID, NodePacket, NodeEvent, NodeSystem, DiscoverySystem, SignedPeerRecord, test_net::{TestNet, record, world, node_config, addr, open_session, sent_packets}};
+
+	#[test]
+	fn distance() {
+		let (a, b) = (record(1, 1).node_id, record(2, 2).node_id);
+		assert_eq!(Distance::between(&a, &b), Distance::between(&b, &a));
+		assert_eq!(Distance::between(&a, &a).bucket_index(), None);
+
+		assert_eq!(Distance(vec![0b1000_0000, 0]).bucket_index(), Some(0));
+		assert_eq!(Distance(vec![0b0000_0001, 0xff]).bucket_index(), Some(7));
+		assert_eq!(Distance(vec![0, 0b0010_0000]).bucket_index(), Some(10));
+		assert!(Distance(vec![0, 0b0010_0000]) < Distance(vec![0, 0b0100_0000]));
+	}
+
+	// Records of test nodes whose NodeID falls into bucket 0 of `own_id`
+	fn bucket_zero(own_id: &NodeID, count: usize) -> Vec<SignedPeerRecord<TestNet>> {
+		(1..).map(|seed|record(seed, seed as u16))
+			.filter(|record|Distance::between(own_id, &record.node_id).bucket_index() == Some(0))
+			.take(count).collect()
+	}
+
+	#[test]
+	fn routing_table_eviction() {
+		let own_id = record(0, 0).node_id;
+		let records = bucket_zero(&own_id, 3);
+
+		// Full bucket keeps the nodes it has while they haven't timed out
+		let mut table = RoutingTable::<TestNet>::new(own_id.clone(), &DhtConfig { k: 2, ..Default::default() });
+		for record in &records {
+			table.insert(record.clone());
+		}
+		assert_eq!(table.len(), 2);
+		assert!(table.get(&records[0].node_id).is_some() && table.get(&records[2].node_id).is_none());
+
+		// Timed out nodes are replaced, least recently seen first
+		let mut table = RoutingTable::<TestNet>::new(own_id, &DhtConfig { k: 2, entry_timeout: Duration::ZERO, ..Default::default() });
+		table.insert(records[0].clone());
+		table.insert(records[1].clone());
+		table.insert(records[0].clone());
+		table.insert(records[2].clone());
+		assert_eq!(table.len(), 2);
+		assert!(table.get(&records[1].node_id).is_none());
+		assert!(table.get(&records[0].node_id).is_some() && table.get(&records[2].node_id).is_some());
+	}
+
+	#[test]
+	fn routing_table_closest() {
+		let own_id = record(0, 0).node_id;
+		let mut table = RoutingTable::<TestNet>::new(own_id, &DhtConfig { k: 30, ..Default::default() });
+		let records = (1..=30).map(|seed|record(seed, seed as u16)).collect::<Vec<_>>();
+		for record in &records {
+			table.insert(record.clone());
+		}
+		table.insert(record(0, 0));
+
+		let target = record(100, 100).node_id;
+		let mut expected = records.iter().map(|record|Distance::between(&target, &record.node_id)).collect::<Vec<_>>();
+		expected.sort();
+		let closest = table.closest(&target, 5).into_iter().map(|record|Distance::between(&target, &record.node_id)).collect::<Vec<_>>();
+		assert_eq!(closest, expected[..5]);
+	}
+
+	// Only the first k records of a response are added to the lookup
+	#[test]
+	fn limit_nodes_response() {
+		let mut config = node_config(1);
+		config.dht.k = 2;
+		let (mut world, mut events) = world::<DhtSystem<TestNet>>(config);
+		DiscoverySystem::<TestNet>::register_resources(&mut world);
+		let (responder, mut sent) = open_session(&mut world, 2, 2);
+		world.resource_mut::<RoutingTable<TestNet>>().insert(record(2, 2));
+
+		let target = record(100, 100);
+		let mut lookup = |nodes: Vec<SignedPeerRecord<TestNet>>| {
+			DhtSystem::<TestNet>::lookup(&mut world, target.node_id.clone(), false, true);
+			let lookup_id = match &sent_packets(&mut sent)[..] {
+				[NodePacket::DhtPacket(DhtPacket::FindNode { lookup_id, .. })] => *lookup_id,
+				packets => panic!("expected FindNode, sent {packets:?}"),
+			};
+			DhtSystem::<TestNet>::handle_packet(&mut world, responder, DhtPacket::Nodes { lookup_id, nodes });
+			match events.try_next() {
+				Ok(Some(NodeEvent::LookupResult(node_id, addresses))) if node_id == target.node_id => addresses,
+				_ => panic!("expected lookup result"),
+			}
+		};
+		// The target isn't found after the first k records, but is among them
+		assert!(lookup(vec![record(10, 10), record(11, 11), target.clone()]).is_empty());
+		assert_eq!(lookup(vec![target.clone(), record(10, 10), record(11, 11)]), [addr(100)]);
+	}
+}
@@ -1,10 +1,14 @@
 //! This node system is for peer discovery. It requests for peers from another node and receives a list of peers to connect to or awaits connections from other peers.
 
+use std::{collections::{HashMap, HashSet, VecDeque}, time::{Duration, Instant}};
+
 use bevy_ecs::prelude::*;
+use futures::channel::oneshot;
+use rand::seq::SliceRandom;
 use rkyv::{Archive, Serialize, Deserialize};
 use bytecheck::CheckBytes;
 
-use crate::{NodeSystem, session::{SessionInfo, Session}, Remote, NodePacket, Network, NodeID, RemoteIDMap, PublicAddress};
+use crate::{NodeSystem, session::{SessionInfo, Session, SessionAction, SessionCloseReason}, Remote, NodePacket, Network, NodeID, NodeConfig, RemoteIDMap, PublicAddress, AddressBook, Coordinates, Latency, SignedPeerRecord, Relay, RoutingSystem, RequestedSession, session_count};
 
 #[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
 #[serde(bound(serialize="", deserialize=""))]
@@ -13,8 +17,18 @@ use crate::{NodeSystem, session::{SessionInfo, Session}, Remote, NodePacket, Net
 pub enum PeerListDiscovery<Net: Network> {
 	RequestPeers,
 	/// Sent back in response to RequestPeers. Usually if network is small or if requester is a trusted node.
-	/// Contains a list or subset of all currently connected nodes and their publicly-accessible addresses
-	PeerList(Vec<(NodeID, Net::Address)>),
+	/// Contains a list or subset of all currently connected nodes and their publicly-accessible addresses, as signed by each node.
+	PeerList(Vec<PeerListEntry<Net>>),
+}
+
+/// Peer advertised in a `PeerList`.
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+#[archive_attr(derive(CheckBytes))]
+pub struct PeerListEntry<Net: Network> {
+	pub record: SignedPeerRecord<Net>,
+	/// Coordinates the sender received from the peer, used to connect to the closest peers first. They aren't signed by the peer, so they are only a hint.
+	pub coordinates: Option<Coordinates>,
 }
 
 #[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
@@ -24,11 +38,12 @@ pub enum NotifyRecovery<Net: Network> {
 	/// Receiver of a connection notifies the initiator of which IP they see them using.
 	NotifyOutgoingIP(Net::Address),
 	/// Initiator of the connection notifies the the receiver of the public address they can be connected to at.
-	NotifyPublicAddress(Net::Address),
+	NotifyPublicAddress(SignedPeerRecord<Net>),
 	/// Sent by a node that is looking for new nodes to connect to, usually nodes that have recently joined the network.
-	/// SocketAddr represents publically-accessible IP for new peers to connect to.
+	/// The record contains the publically-accessible addresses for new peers to connect to, `request_id` is chosen by the requester.
 	RequestPeers {
-		requester_addr: Net::Address,
+		requester: SignedPeerRecord<Net>,
+		request_id: usize,
 	},
 	/// Sent back in response to RequestPeers. Usually if a network is large or requester is untrusted.
 	/// If this packet is sent back, it tells the requester how many nodes where notified for request initiation.
@@ -39,8 +54,7 @@ pub enum NotifyRecovery<Net: Network> {
 	/// Sent by a node that receives RequestPeers to a subset of that receiver's peers.
 	/// Notifies the peer that a given node is looking for a new peer.
 	WantPeer {
-		requester_id: NodeID,
-		requester_addr: Net::Address,
+		requester: SignedPeerRecord<Net>,
 		request_id: usize,
 	},
 	// Send by a requested peer of initial `RequestPeers` receiver upon connection to `RequestPeers` sender.
@@ -56,11 +70,18 @@ pub enum DiscoveryPacket<Net: Network> {
 	PeerListDiscovery(PeerListDiscovery<Net>),
 	NotifyRecovery(NotifyRecovery<Net>),
 	/// Notify remote of public address they can use to re-connect
-	NotifyPublicAddress(Net::Address),
+	NotifyPublicAddress(SignedPeerRecord<Net>),
 	/// Request from remote what they see my address as.
 	RequestSeenAddress,
 	/// Response from remote what they see as my address
 	NotifySeenAddress(Net::Address),
+	/// Ask remote to check that it can connect to me at the given address.
+	RequestDialBack(Net::Address),
+	/// Response to `RequestDialBack`.
+	DialBackResult {
+		addr: Net::Address,
+		reachable: bool,
+	},
 }
 impl<Net: Network> From<DiscoveryPacket<Net>> for NodePacket<Net> {
     fn from(value: DiscoveryPacket<Net>) -> Self {
@@ -72,76 +93,195 @@ pub struct DiscoverySystem<Net: Network> {
 	_net: std::marker::PhantomData<Net::Address>,
 }
 
+impl<Net: Network> DiscoverySystem<Net> {
+	/// Connect to a node found outside of the network if it isn't connected yet and fewer than `DiscoveryConfig::max_peers` sessions exist.
+	pub fn add_peer(world: &mut World, remote_id: NodeID, addr: Net::Address) {
+		if remote_id == world.resource::<NodeConfig<Net>>().node_id { return }
+		let connected = world.resource::<RemoteIDMap>().map.get(&remote_id).map_or(false, |e|world.get::<Session<Net>>(*e).is_some());
+		if connected || session_count::<Net>(world) >= world.resource::<DiscoveryConfig>().max_peers { return }
+		log::debug!("adding peer {remote_id:?} at {addr}");
+		world.resource::<Net>().connect(remote_id, addr, None, None);
+	}
+}
+
 impl<Net: Network> NodeSystem for DiscoverySystem<Net> {
 	fn register_resources(world: &mut World) {
-		world.insert_resource(KnownPubAddr::<Net> { addr: None });
+		let config = world.resource::<NodeConfig<Net>>().discovery.clone();
+		world.insert_resource(config);
+		world.insert_resource(KnownPubAddr::<Net> { addr: None, reachability: Reachability::Unknown, dial_back: DialBackRequest::default(), record: None });
+		world.insert_resource(PendingDialBacks::<Net> { probes: Vec::new() });
+		world.insert_resource(NatDetection::<Net> { reports: VecDeque::new(), nat_type: NatType::Unknown });
+		world.init_resource::<PeerRequests>();
+		world.init_resource::<PendingIntroductions>();
+		world.insert_resource(NeighbourSelection { last_prune: Instant::now() });
 	}
 
 	fn register_systems(schedule: &mut Schedule) {
-		// schedule.add_system(handle_peer_request::<Net>);
-		schedule.add_system(session_setup::<Net>);
 		schedule.add_system(handle_conn_request::<Net>);
 	}
 
+	fn on_session_open(world: &mut World, entity: Entity) {
+		let remote_id = world.get::<Remote>(entity).unwrap().id.clone();
+		let introduction = world.resource_mut::<PendingIntroductions>().pending.remove(&remote_id);
+		let own_record = world.resource::<KnownPubAddr<Net>>().record.clone();
+		let session = world.get::<Session<Net>>(entity).unwrap();
+
+		// Acknowledge peer request if this session was established because of a `WantPeer`
+		if let Some((request_id, _)) = introduction {
+			session.send_packet(DiscoveryPacket::NotifyRecovery(NotifyRecovery::AcknolwedgedRequest { request_id }).into());
+			return;
+		}
+
+		// Request peers from each other. If own public address is known, peers can be introduced indirectly, otherwise ask for the remote's whole peer list.
+		match own_record {
+			Some(requester) => {
+				// Track the request right away, acknowledgements from notified peers may arrive before `PeersNotified`
+				let request_id = rand::random::<usize>();
+				session.send_packet(DiscoveryPacket::NotifyRecovery(NotifyRecovery::RequestPeers { requester, request_id }).into());
+				world.resource_mut::<PeerRequests>().requests.insert(request_id, PeerRequest::new(remote_id));
+			}
+			None => session.send_packet(DiscoveryPacket::PeerListDiscovery(PeerListDiscovery::RequestPeers).into()),
+		}
+	}
+
+	// Forget peer requests and introductions that have timed out
+	fn on_tick(world: &mut World) {
+		let timeout = world.resource::<DiscoveryConfig>().request_timeout;
+		world.resource_mut::<PeerRequests>().requests.retain(|request_id, request| {
+			let expired = request.created.elapsed() >= timeout;
+			if expired {
+				log::debug!("peer request {request_id} expired: {} of {:?} notified peers connected", request.acknowledged.len(), request.notified);
+			}
+			!expired
+		});
+		world.resource_mut::<PendingIntroductions>().pending.retain(|_, (_, created)|created.elapsed() < timeout);
+
+		request_dial_back::<Net>(world);
+		answer_dial_backs::<Net>(world);
+
+		let prune_interval = world.resource::<DiscoveryConfig>().prune_interval;
+		if world.resource::<NeighbourSelection>().last_prune.elapsed() >= prune_interval {
+			world.resource_mut::<NeighbourSelection>().last_prune = Instant::now();
+			prune_neighbours::<Net>(world);
+		}
+	}
+
+	fn on_session_closed(world: &mut World, entity: Entity, _reason: &SessionCloseReason) {
+		// Seen address and connection direction are only valid for the session that reported them
+		world.entity_mut(entity).remove::<(SeenAddr<Net>, ConnReceiver, LongRangeLink)>();
+	}
+
 	type Packet = DiscoveryPacket<Net>;
 	
 	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
 		match packet {
 			DiscoveryPacket::PeerListDiscovery(packet) => match packet {
 				PeerListDiscovery::RequestPeers => {
-					let mut query = world.query::<(&Remote, &PublicAddress<Net>)>();
-					let peer_list = query.iter(world)
-						.map(|(remote, addr)|(remote.id.clone(), addr.addr.clone()))
-						.collect::<Vec<(NodeID, Net::Address)>>();
+					// Only advertise a random sample of remotes we currently have a session with, excluding the requester and self
+					let self_id = world.resource::<NodeConfig<Net>>().node_id.clone();
+					let peer_list_size = world.resource::<DiscoveryConfig>().peer_list_size;
+					let mut query = world.query_filtered::<(Entity, &Remote, &PublicAddress<Net>, Option<&Coordinates>), With<Session<Net>>>();
+					let peers = query.iter(world)
+						.filter(|(peer, remote, _, _)|*peer != entity && remote.id != self_id)
+						.map(|(_, _, addr, coords)|(&addr.record, coords))
+						.collect::<Vec<(&SignedPeerRecord<Net>, Option<&Coordinates>)>>();
+					let peer_list = peers.choose_multiple(&mut rand::thread_rng(), peer_list_size)
+						.map(|(record, coords)|PeerListEntry { record: (*record).clone(), coordinates: coords.cloned() })
+						.collect::<Vec<PeerListEntry<Net>>>();
 					// Return peerlist
-					log::debug!("received requestpeers, sending peerlist: {:?}", peer_list);
+					log::debug!("received requestpeers, sending peerlist: {:?}", peer_list.iter().map(|entry|(&entry.record.node_id, entry.record.address())).collect::<Vec<_>>());
 					world.entity(entity).get::<Session<Net>>().unwrap().send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::PeerListDiscovery(PeerListDiscovery::PeerList(peer_list))));
 				},
 				PeerListDiscovery::PeerList(list) => {
-					log::debug!("received peerlist: {:?}", list);
-					// Connect to every peer received if not already connected
+					// Only relayed records that were signed by the node they describe are trusted. Lists are never longer than `peer_list_size`, so a remote can't make us verify an unbounded number of records.
+					let peer_list_size = world.resource::<DiscoveryConfig>().peer_list_size;
+					let list = list.into_iter().take(peer_list_size)
+						.filter_map(|entry|Some((verified(entry.record, entity)?, entry.coordinates)))
+						.collect::<Vec<(SignedPeerRecord<Net>, Option<Coordinates>)>>();
+					log::debug!("received peerlist: {:?}", list.iter().map(|(record, _)|(&record.node_id, record.address())).collect::<Vec<_>>());
+					if let Some(mut book) = world.get_resource_mut::<AddressBook<Net>>() {
+						for (record, _) in &list {
+							book.add_signed(record.clone());
+						}
+					}
+					// Connect to unknown peers from the list until there are enough sessions, closest peers first
+					let wanted = world.resource::<DiscoveryConfig>().max_peers.saturating_sub(session_count::<Net>(world));
+					let self_id = &world.resource::<NodeConfig<Net>>().node_id;
 					let map = world.resource::<RemoteIDMap>();
+					let mut list = list.into_iter()
+						.filter(|(record, _)|record.node_id != *self_id && !map.map.contains_key(&record.node_id))
+						.map(|(record, coords)|(record.node_id.clone(), record.address().clone(), coords))
+						.collect::<Vec<(NodeID, Net::Address, Option<Coordinates>)>>();
+					sort_by_predicted_latency::<Net>(world, &mut list);
 					let net = world.resource::<Net>();
-					for (id, addr) in list {
-						if !map.map.contains_key(&id) {
-							net.connect(id, addr, None, None);
-						}
+					for (id, addr, _) in list.into_iter().take(wanted) {
+						net.connect(id, addr, None, None);
 					}
 				},
 			}
 			DiscoveryPacket::NotifyRecovery(packet) => match packet {
-				/* DiscoveryPacket::RequestPeers { requester_addr  } => {
-					// When receiving a `RequestPeers` discovery request add PeerRequest component to entity
-					world.entity_mut(entity).insert(PeerRequest::<Net>(Instant::now(), requester_addr));
-				},
-				DiscoveryPacket::PeerList(list) => {
-					let net = world.resource::<Net>();
-					for (remote_id, net_address) in list {
-						net.connect(remote_id, net_address, None, None);
+				NotifyRecovery::NotifyOutgoingIP(seen_addr) => {
+					world.entity_mut(entity).insert(SeenAddr::<Net> { addr: seen_addr });
+				}
+				NotifyRecovery::NotifyPublicAddress(record) => insert_public_address(world, entity, record),
+				// Notify a random subset of peers that the requester wants peers, they will connect to the requester.
+				NotifyRecovery::RequestPeers { requester, request_id } => {
+					let Some(requester_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+					// Peers will dial the addresses in the record, so it must be signed by the requester itself
+					let Some(requester) = verified(requester, entity) else { return };
+					if requester.node_id != requester_id {
+						log::warn!("{requester_id:?} requested peers using the record of {:?}", requester.node_id);
+						return;
 					}
-				},
-				DiscoveryPacket::PeersNotified { number: _, request_id: _ } => {},
-				DiscoveryPacket::WantPeer { requester_id, requester_addr, request_id: _ } => {
-					// When receive WantPeer request, check if the WantPeer is already a peer, if not, initiate connection and register entity with WantPeer component so that AcknowledgedRequest packet can be sent.
-					#[derive(Debug, Component)]
-					struct WantPeer;
-					if let Some(requester_entity) = world.resource::<RemoteIDMap>().map.get(&requester_id) {
-						world.entity_mut(*requesting_entity).insert(WantPeer);
-					} else {
-						let requester_entity = world.spawn(WantPeer).id();
-						world.resource_mut::<RemoteIDMap>().map.insert(requester_id, requesting_entity);
+					let fanout = world.resource::<DiscoveryConfig>().want_peer_fanout;
+					let mut query = world.query_filtered::<(Entity, &Session<Net>), With<Remote>>();
+					let peers = query.iter(world).filter(|(peer, _)|*peer != entity).map(|(_, sess)|sess).collect::<Vec<&Session<Net>>>();
+					let notified = peers.choose_multiple(&mut rand::thread_rng(), fanout).collect::<Vec<_>>();
+					for sess in &notified {
+						sess.send_packet(DiscoveryPacket::NotifyRecovery(NotifyRecovery::WantPeer { requester: requester.clone(), request_id }).into());
 					}
-					let net = world.resource::<Net>();
-					
-					net.connect(requester_id, requester_addr, None, None);
-				},
-				DiscoveryPacket::AcknolwedgedRequest { request_id: _ } => {}, */
-				_ => unimplemented!()
+					log::debug!("received peer request {request_id} from {requester_id:?}, notified {} peers", notified.len());
+					let number = notified.len();
+					world.entity(entity).get::<Session<Net>>().unwrap().send_packet(DiscoveryPacket::NotifyRecovery(NotifyRecovery::PeersNotified { number, request_id }).into());
+				}
+				NotifyRecovery::PeersNotified { number, request_id } => {
+					let Some(contact) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+					log::debug!("{contact:?} notified {number} peers of peer request {request_id}");
+					let mut requests = world.resource_mut::<PeerRequests>();
+					// Only accept notification from the node the request was sent to
+					match requests.requests.get_mut(&request_id) {
+						Some(request) if request.contact == contact => request.notified = Some(number),
+						_ => log::debug!("received notification for unknown peer request {request_id} from {contact:?}"),
+					}
+				}
+				// Connect to the requester and acknowledge the request once the session is established
+				NotifyRecovery::WantPeer { requester, request_id } => {
+					let Some(requester) = verified(requester, entity) else { return };
+					let requester_id = requester.node_id.clone();
+					if requester_id == world.resource::<NodeConfig<Net>>().node_id { return }
+					// Same limit as for peers from a `PeerList`, introductions must not grow the node past its wanted degree
+					let max_peers = world.resource::<DiscoveryConfig>().max_peers;
+					let connected = world.resource::<RemoteIDMap>().map.get(&requester_id).map_or(false, |e|world.get::<Session<Net>>(*e).is_some());
+					if connected || session_count::<Net>(world) >= max_peers { return }
+					log::debug!("connecting to {requester_id:?} for peer request {request_id}");
+					world.resource_mut::<PendingIntroductions>().pending.insert(requester_id.clone(), (request_id, Instant::now()));
+					world.resource::<Net>().connect(requester_id, requester.address().clone(), None, None);
+				}
+				NotifyRecovery::AcknolwedgedRequest { request_id } => {
+					let Some(peer) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+					match world.resource_mut::<PeerRequests>().requests.get_mut(&request_id) {
+						Some(request) => {
+							request.acknowledged.insert(peer);
+							log::debug!("peer request {request_id}: {} of {:?} notified peers connected", request.acknowledged.len(), request.notified);
+						}
+						None => log::debug!("received acknowledgement for unknown peer request {request_id} from {peer:?}"),
+					}
+				}
 			}
 			// When receiving this packet, we should record what the public address is to enable reconnection
-			DiscoveryPacket::NotifyPublicAddress(addr) => {
-				log::info!("notified of public address for {entity:?}: {:?}", addr);
-				world.entity_mut(entity).insert(PublicAddress::<Net> { addr });
+			DiscoveryPacket::NotifyPublicAddress(record) => {
+				log::info!("notified of public address for {entity:?}: {:?}", record.addresses);
+				insert_public_address(world, entity, record);
 			},
 			// When receiving this packet, we should send back what we see the remote's public address as.
 			DiscoveryPacket::RequestSeenAddress => {
@@ -151,90 +291,483 @@ impl<Net: Network> NodeSystem for DiscoverySystem<Net> {
 			DiscoveryPacket::NotifySeenAddress(seen_addr) => {
 				world.entity_mut(entity).insert(SeenAddr::<Net> { addr: seen_addr });
 			}
+			// Check that the requester can be connected to at the address, the result is sent back once the probe finishes
+			DiscoveryPacket::RequestDialBack(addr) => {
+				let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+				// Only probe the host the session comes from, otherwise nodes could make us connect to arbitrary hosts
+				let Some(info) = world.get::<SessionInfo<Net>>(entity) else { return };
+				if !Net::same_host(&info.net_address, &addr) {
+					log::warn!("ignoring dial-back request from {remote_id:?} for {addr}, session is from {}", info.net_address);
+					return;
+				}
+				let pending = world.resource::<PendingDialBacks<Net>>();
+				if pending.probes.len() >= MAX_PENDING_DIAL_BACKS || pending.probes.iter().any(|(requester, _, _)|*requester == entity) {
+					log::debug!("ignoring dial-back request from {remote_id:?}, too many pending probes");
+					return;
+				}
+				let reply = world.resource::<Net>().probe(remote_id, addr.clone());
+				world.resource_mut::<PendingDialBacks<Net>>().probes.push((entity, addr, reply));
+			}
+			DiscoveryPacket::DialBackResult { addr, reachable } => {
+				let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+				let known = world.resource::<KnownPubAddr<Net>>();
+				if known.addr.as_ref() != Some(&addr) || known.reachability != Reachability::Checking || !known.dial_back.asked.contains(&remote_id) { return }
+				if reachable {
+					log::info!("public address {addr:?} verified reachable by {remote_id:?}");
+					let config = world.resource::<NodeConfig<Net>>();
+					let record = SignedPeerRecord::new(&config.keys, config.node_id.clone(), vec![addr]);
+					let mut known = world.resource_mut::<KnownPubAddr<Net>>();
+					known.reachability = Reachability::Reachable;
+					known.record = Some(record.clone());
+					// Advertise the verified address to remotes we connected to directly
+					let mut query = world.query_filtered::<&Session<Net>, (With<ConnReceiver>, Without<Relay>)>();
+					for session in query.iter(world) {
+						session.send_packet(DiscoveryPacket::NotifyPublicAddress(record.clone()).into());
+					}
+				} else {
+					let mut known = world.resource_mut::<KnownPubAddr<Net>>();
+					known.dial_back.failed.insert(remote_id);
+					if known.dial_back.failed.len() >= known.dial_back.asked.len() {
+						log::warn!("public address {addr:?} is not reachable, not advertising it");
+						known.reachability = Reachability::Unreachable;
+					}
+				}
+			}
 		}
 	}
 }
 
-fn session_setup<Net: Network>(query: Query<&Session<Net>, Added<Session<Net>>>) {
-	// Set need more pings to true
-	for session in &query {
-		// Request peers from each other
-		session.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::PeerListDiscovery(PeerListDiscovery::RequestPeers)));
+/// Configures how peers are introduced to each other.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+	/// Stop connecting to peers received in a `PeerList` or introduced by `WantPeer` once this many sessions exist.
+	pub max_peers: usize,
+	/// Maximum number of peers sent in a `PeerList`, longer received lists are truncated.
+	pub peer_list_size: usize,
+	/// Number of peers notified with `WantPeer` when receiving `NotifyRecovery::RequestPeers`.
+	pub want_peer_fanout: usize,
+	/// Peer requests and introductions are forgotten after this long.
+	pub request_timeout: Duration,
+	/// Number of peers with the lowest predicted latency to keep when pruning sessions.
+	pub near_peers: usize,
+	/// Number of random long-range peers to keep when pruning sessions, keeps the network small-world.
+	pub long_range_peers: usize,
+	/// How often redundant sessions are pruned.
+	pub prune_interval: Duration,
+	/// Number of remotes that must agree on our external address before it is advertised.
+	pub nat_confirmations: usize,
+	/// Number of most recent `SeenAddr` reports used to detect the NAT type.
+	pub max_nat_reports: usize,
+	/// Number of remotes asked to dial back our public address to verify it is reachable.
+	pub dial_back_peers: usize,
+	/// Time to wait before checking an unreachable public address again.
+	pub dial_back_interval: Duration,
+}
+impl Default for DiscoveryConfig {
+	fn default() -> Self {
+		Self {
+			max_peers: 8,
+			peer_list_size: 8,
+			want_peer_fanout: 8,
+			request_timeout: Duration::from_secs(30),
+			near_peers: 6,
+			long_range_peers: 2,
+			prune_interval: Duration::from_secs(30),
+			nat_confirmations: 2,
+			max_nat_reports: 16,
+			dial_back_peers: 3,
+			dial_back_interval: Duration::from_secs(60),
+		}
 	}
 }
 
+/// State of a `NotifyRecovery::RequestPeers` sent by this node.
+#[derive(Debug)]
+pub struct PeerRequest {
+	/// Node the request was sent to.
+	pub contact: NodeID,
+	/// Number of peers the contact notified, `None` until `PeersNotified` is received.
+	pub notified: Option<usize>,
+	/// Peers that connected because of this request.
+	pub acknowledged: HashSet<NodeID>,
+	pub created: Instant,
+}
+impl PeerRequest {
+	fn new(contact: NodeID) -> Self {
+		Self { contact, notified: None, acknowledged: HashSet::new(), created: Instant::now() }
+	}
+}
 
-/* #[derive(Debug, Component)]
-struct PeerRequest<Net: Network>(Instant, Net::Address);
-
+/// Outstanding peer requests of this node by request_id.
+#[derive(Debug, Default, Resource)]
+pub struct PeerRequests {
+	requests: HashMap<usize, PeerRequest>,
+}
+impl PeerRequests {
+	pub fn get(&self, request_id: usize) -> Option<&PeerRequest> {
+		self.requests.get(&request_id)
+	}
+}
 
-fn handle_peer_request<Net: Network>(mut requesting: Query<(&Remote, &SessionInfo<Net>, &Session<Net>, &PeerRequest<Net>), Changed<PeerRequest<Net>>>, mut peers: Query<(&Remote, &SessionInfo<Net>, &Session<Net>)>) {
-	for (req_remote, req_info, req_sess, request) in &requesting {
-		let request_id = rand::thread_rng().gen::<usize>();
-		let mut peers_count = 0usize;
-		for (_, _, sess) in &peers {
-			peers_count += 1;
+/// Requesters this node is connecting to because of a `WantPeer`, mapped to the request_id to acknowledge.
+#[derive(Debug, Default, Resource)]
+struct PendingIntroductions {
+	pending: HashMap<NodeID, (usize, Instant)>,
+}
 
-			sess.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::WantPeer {
-				requester_id: req_remote.id.clone(),
-				requester_addr: request.1.clone(),
-				request_id,
-			}));
+// Verify a peer record received from `from`, records that fail verification are dropped
+fn verified<Net: Network>(record: SignedPeerRecord<Net>, from: Entity) -> Option<SignedPeerRecord<Net>> {
+	match record.verify() {
+		Ok(()) => Some(record),
+		Err(err) => {
+			log::warn!("dropping peer record received from {from:?}: {err}");
+			None
 		}
+	}
+}
+
+// Set the public address of a remote from a record it signed itself, older records are ignored
+fn insert_public_address<Net: Network>(world: &mut World, entity: Entity, record: SignedPeerRecord<Net>) {
+	let Some(record) = verified(record, entity) else { return };
+	let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+	if record.node_id != remote_id {
+		log::warn!("{remote_id:?} sent public address record of {:?}", record.node_id);
+		return;
+	}
+	if world.get::<PublicAddress<Net>>(entity).map_or(false, |known|known.record.seq >= record.seq) { return }
+	world.entity_mut(entity).insert(PublicAddress::new(record));
+}
+
+/// Marks a session kept as a random long-range link when pruning sessions.
+#[derive(Debug, Component)]
+pub struct LongRangeLink;
+
+#[derive(Resource)]
+struct NeighbourSelection {
+	last_prune: Instant,
+}
+
+// Predicted round-trip latency to a remote with the given coordinates
+fn predicted_rtt(own: &Coordinates, remote: &Coordinates) -> Latency {
+	let (outgoing, incoming) = own.predict_latencies(remote);
+	outgoing.saturating_add(incoming)
+}
+
+// Sort peers by predicted latency using the coordinates sent along in the `PeerList`, or else the ones remembered in the `AddressBook`. Peers without usable coordinates are shuffled and put last.
+fn sort_by_predicted_latency<Net: Network>(world: &World, peers: &mut Vec<(NodeID, Net::Address, Option<Coordinates>)>) {
+	peers.shuffle(&mut rand::thread_rng());
+	let Some(own_coords) = world.get_resource::<Coordinates>() else { return };
+	let book = world.get_resource::<AddressBook<Net>>();
+	let usable = |coords: &&Coordinates|coords.algorithm == own_coords.algorithm && coords.is_valid();
+	peers.sort_by_cached_key(|(id, _, coords)| {
+		coords.as_ref().filter(usable)
+			.or_else(||book?.get(id)?.coordinates.as_ref().filter(usable))
+			.map_or(Latency::MAX, |coords|predicted_rtt(own_coords, coords))
+	});
+}
+
+// Close sessions that are neither among the `near_peers` closest peers nor one of the `long_range_peers` random long-range links.
+// Only peers whose coordinates are known are considered. Relayed sessions, sessions carrying relay circuits and sessions requested through `NodeHandle::connect` are never closed.
+fn prune_neighbours<Net: Network>(world: &mut World) {
+	let Some(own_coords) = world.get_resource::<Coordinates>().cloned() else { return };
+	let config = world.resource::<DiscoveryConfig>().clone();
+
+	let mut query = world.query_filtered::<(Entity, &Coordinates, Option<&LongRangeLink>), (With<Session<Net>>, Without<Relay>, Without<RequestedSession>)>();
+	let mut peers = query.iter(world)
+		.filter(|(entity, _, _)|!RoutingSystem::<Net>::carries_circuits(world, *entity))
+		.map(|(entity, coords, long_range)|(entity, predicted_rtt(&own_coords, coords), long_range.is_some()))
+		.collect::<Vec<(Entity, Latency, bool)>>();
+	if peers.len() <= config.near_peers + config.long_range_peers { return }
+
+	peers.sort_by_key(|(_, rtt, _)|*rtt);
+	let mut far = peers.split_off(config.near_peers);
+
+	// Prefer existing long-range links, fill the rest randomly
+	far.shuffle(&mut rand::thread_rng());
+	far.sort_by_key(|(_, _, long_range)|!long_range);
+	let redundant = far.split_off(config.long_range_peers.min(far.len()));
 
-		req_sess.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::PeersNotified { number: peers_count, request_id }));
+	for (entity, _, _) in far {
+		world.entity_mut(entity).insert(LongRangeLink);
 	}
-} */
+	for (entity, rtt, _) in &peers {
+		world.entity_mut(*entity).remove::<LongRangeLink>();
+		log::trace!("keeping near peer {entity:?}, predicted rtt: {rtt}");
+	}
+	for (entity, rtt, _) in redundant {
+		log::debug!("closing redundant session with {entity:?}, predicted rtt: {rtt}");
+		world.get::<Session<Net>>(entity).unwrap().send_action(SessionAction::Close);
+	}
+}
 
 /// Known public addresses of this node that remote nodes can send stuff through. This is a per-session component
 #[derive(Component)]
 pub struct SeenAddr<Net: Network> {
 	addr: Net::Address
 }
+impl<Net: Network> SeenAddr<Net> {
+	pub fn addr(&self) -> &Net::Address {
+		&self.addr
+	}
+}
 
+/// Own public address, confirmed by `SeenAddr` reports of remotes.
 #[derive(Resource)]
 pub struct KnownPubAddr<Net: Network> {
 	addr: Option<Net::Address>,
+	/// Whether remotes can connect to `addr`, checked by asking remotes to dial back.
+	reachability: Reachability,
+	dial_back: DialBackRequest,
+	/// Signed record of `addr` sent to remotes, only set once `addr` is verified reachable.
+	record: Option<SignedPeerRecord<Net>>,
+}
+impl<Net: Network> KnownPubAddr<Net> {
+	pub fn addr(&self) -> Option<&Net::Address> {
+		self.addr.as_ref()
+	}
+	pub fn reachability(&self) -> Reachability {
+		self.reachability
+	}
+	pub fn record(&self) -> Option<&SignedPeerRecord<Net>> {
+		self.record.as_ref()
+	}
+	// Use a new public address, it isn't advertised until verified
+	fn set(&mut self, addr: Option<Net::Address>) {
+		self.addr = addr;
+		self.reachability = Reachability::Unknown;
+		self.dial_back = DialBackRequest::default();
+		self.record = None;
+	}
+}
+
+/// Result of checking that remotes can connect to our public address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Reachability {
+	Unknown,
+	/// Waiting for `DialBackResult`s.
+	Checking,
+	Reachable,
+	Unreachable,
+}
+
+/// Remotes asked to dial back the public address.
+#[derive(Debug, Default)]
+struct DialBackRequest {
+	asked: HashSet<NodeID>,
+	/// Asked remotes that couldn't connect, each remote only counts once.
+	failed: HashSet<NodeID>,
+	sent: Option<Instant>,
+}
+
+/// Maximum number of dial-back probes run for remotes at once.
+const MAX_PENDING_DIAL_BACKS: usize = 8;
+
+/// Probes run because of a `RequestDialBack`: (requester, probed address, result)
+#[derive(Resource)]
+struct PendingDialBacks<Net: Network> {
+	probes: Vec<(Entity, Net::Address, oneshot::Receiver<bool>)>,
+}
+
+// Ask random remotes to dial back our public address if it wasn't verified yet, failed checks are retried after `dial_back_interval`
+fn request_dial_back<Net: Network>(world: &mut World) {
+	let config = world.resource::<DiscoveryConfig>().clone();
+	let known = world.resource::<KnownPubAddr<Net>>();
+	let Some(addr) = known.addr.clone() else { return };
+	let since = known.dial_back.sent.map(|sent|sent.elapsed());
+	match (known.reachability, since) {
+		(Reachability::Unknown, _) => {}
+		(Reachability::Checking, Some(since)) if since >= config.request_timeout => {
+			log::warn!("public address {addr:?} is not reachable, no remote could connect to it");
+			world.resource_mut::<KnownPubAddr<Net>>().reachability = Reachability::Unreachable;
+			return;
+		}
+		(Reachability::Unreachable, Some(since)) if since >= config.dial_back_interval => {}
+		_ => return,
+	}
+
+	let mut query = world.query::<(&Remote, &Session<Net>)>();
+	let sessions = query.iter(world).collect::<Vec<(&Remote, &Session<Net>)>>();
+	let asked = sessions.choose_multiple(&mut rand::thread_rng(), config.dial_back_peers).map(|(remote, session)| {
+		session.send_packet(DiscoveryPacket::RequestDialBack(addr.clone()).into());
+		remote.id.clone()
+	}).collect::<HashSet<NodeID>>();
+	if asked.is_empty() { return }
+	log::debug!("asked {} remotes to dial back {addr:?}", asked.len());
+
+	let mut known = world.resource_mut::<KnownPubAddr<Net>>();
+	known.reachability = Reachability::Checking;
+	known.dial_back = DialBackRequest { asked, failed: HashSet::new(), sent: Some(Instant::now()) };
+}
+
+// Send results of finished dial-back probes to the requesters
+fn answer_dial_backs<Net: Network>(world: &mut World) {
+	let mut finished = Vec::new();
+	world.resource_mut::<PendingDialBacks<Net>>().probes.retain_mut(|(requester, addr, reply)| match reply.try_recv() {
+		Ok(None) => true,
+		Ok(Some(reachable)) => { finished.push((*requester, addr.clone(), reachable)); false }
+		Err(_) => { finished.push((*requester, addr.clone(), false)); false }
+	});
+	for (requester, addr, reachable) in finished {
+		if let Some(session) = world.get::<Session<Net>>(requester) {
+			session.send_packet(DiscoveryPacket::DialBackResult { addr, reachable }.into());
+		}
+	}
 }
 
 #[derive(Component)]
 pub struct ConnReceiver;
 
-// This system handles whenever a new connection was established for the first time (i.e. the addition of ConnReceiver) or when the remote returns RequestSeenAddr
+/// How the NAT (if any) in front of this node maps outgoing connections to external addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum NatType {
+	/// Not enough reports from remotes yet.
+	Unknown,
+	/// Remotes see the local address of the connection, there is no NAT.
+	None,
+	/// Remotes agree on the external address, the mapping doesn't depend on the destination.
+	EndpointIndependent,
+	/// Remotes see different external addresses for the same local address, the mapping depends on the destination address and/or port.
+	/// The public address can't be predicted, so none is advertised.
+	AddressPortDependent,
+}
+
+/// Addresses remotes report seeing this node as (`SeenAddr`), used to vote on the external address and to classify the NAT.
+#[derive(Resource)]
+pub struct NatDetection<Net: Network> {
+	/// Most recent report of each remote: (reporter, local address of the connection, seen address)
+	reports: VecDeque<(NodeID, Option<Net::Address>, Net::Address)>,
+	nat_type: NatType,
+}
+impl<Net: Network> NatDetection<Net> {
+	pub fn nat_type(&self) -> NatType {
+		self.nat_type
+	}
+	fn report(&mut self, reporter: NodeID, local: Option<Net::Address>, seen: Net::Address, max_reports: usize) {
+		self.reports.retain(|(id, _, _)|*id != reporter);
+		self.reports.push_back((reporter, local, seen));
+		while self.reports.len() > max_reports { self.reports.pop_front(); }
+	}
+	// Classify the NAT and return the external address if enough remotes agree on it.
+	// Each report is a vote for the public address predicted from it, there must be a majority of at least `min_confirmations` votes.
+	fn classify(&self, listener_config: &Net::ListenerConfig, min_confirmations: usize) -> (NatType, Option<Net::Address>) {
+		if self.reports.len() < min_confirmations.max(1) { return (NatType::Unknown, None) }
+
+		let mut votes = HashMap::<Net::Address, usize>::new();
+		for (_, _, seen) in &self.reports {
+			if let Some(predicted) = Net::predict_public_addresses(seen, listener_config).next() {
+				*votes.entry(predicted).or_default() += 1;
+			}
+		}
+		let Some((addr, count)) = votes.into_iter().max_by_key(|(_, count)|*count) else { return (NatType::Unknown, None) };
+		let majority = count * 2 > self.reports.len();
+
+		// Same local address mapped to different external addresses
+		let mut mappings = HashMap::<&Net::Address, &Net::Address>::new();
+		let inconsistent = self.reports.iter().filter_map(|(_, local, seen)|Some((local.as_ref()?, seen)))
+			.any(|(local, seen)|*mappings.entry(local).or_insert(seen) != seen);
+
+		let nat_type = if self.reports.iter().all(|(_, local, seen)|local.as_ref() == Some(seen)) {
+			NatType::None
+		} else if inconsistent || !majority {
+			NatType::AddressPortDependent
+		} else {
+			NatType::EndpointIndependent
+		};
+		let confirmed = nat_type != NatType::AddressPortDependent && count >= min_confirmations;
+		(nat_type, confirmed.then_some(addr))
+	}
+}
+
+// This system asks remotes we connected to (i.e. on the addition of ConnReceiver) which address they see us as and votes on our public address using their `SeenAddr` reports.
+// The public address is only advertised with `NotifyPublicAddress` once confirmed and verified by dial-back.
 fn handle_conn_request<Net: Network>(
 	mut pub_addr: ResMut<KnownPubAddr<Net>>,
+	mut nat: ResMut<NatDetection<Net>>,
 	listener_config: Res<Net::ListenerConfig>,
-	requesting: Query<(&Session<Net>, Option<&SeenAddr<Net>>), Or<(Added<ConnReceiver>, Added<SeenAddr<Net>>)>>
+	discovery_config: Res<DiscoveryConfig>,
+	new_sessions: Query<&Session<Net>, (Added<ConnReceiver>, Without<Relay>)>,
+	reports: Query<(&Remote, &SessionInfo<Net>, &SeenAddr<Net>), (Changed<SeenAddr<Net>>, Without<Relay>)>,
 ) {
-	for (session, seen) in requesting.iter() {
-		if let Some(pub_addr) = match seen {
-			Some(seen) => {
-				let new_pub_addr = Net::predict_public_addresses(&seen.addr, &*listener_config).next().expect("this system only expects one listening address in ListenerConfig");
-				
-				if let Some(pub_addr) = &pub_addr.addr {
-					if *pub_addr != new_pub_addr {
-						log::error!("public address: {pub_addr:?} does not equal new calculated public address {new_pub_addr:?} these two addresses must be in different networks");
-					}
-				} else {
-					log::info!("calculated new public address for self: {:?}", new_pub_addr);
-					pub_addr.addr = Some(new_pub_addr);
-				}
-				// Return pub addr, send_packet below show notify remote if this not None.
-				pub_addr.addr.clone()
-			}
-			None => {
-				// No SeenAddr, check if has KnownPubAddr. If not, query remote for SeenAddr
-				if let Some(pub_addr) = &pub_addr.addr {
-					Some(pub_addr.clone())
-				} else {
-					// Ask remote for SeenAddr
-					session.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::RequestSeenAddress));
-					None
-				}
-			}
-		} {
-			// If match statement above returns Some(Net::Address), send public address notify.
-			session.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::NotifyPublicAddress(pub_addr)));
+	for session in &new_sessions {
+		session.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::RequestSeenAddress));
+		if let Some(record) = &pub_addr.record {
+			session.send_packet(NodePacket::DiscoveryPacket(DiscoveryPacket::NotifyPublicAddress(record.clone())));
 		}
 	}
-}
\ No newline at end of file
+
+	if reports.is_empty() { return }
+	for (remote, info, seen) in &reports {
+		nat.report(remote.id.clone(), info.local_address.clone(), seen.addr.clone(), discovery_config.max_nat_reports);
+	}
+	let (nat_type, confirmed) = nat.classify(&listener_config, discovery_config.nat_confirmations);
+	if nat_type != nat.nat_type {
+		log::info!("detected NAT type: {nat_type:?}");
+		nat.nat_type = nat_type;
+	}
+	match confirmed {
+		// Verified by dial-back in `DiscoverySystem::on_tick`
+		Some(addr) if pub_addr.addr.as_ref() != Some(&addr) => {
+			log::info!("confirmed public address for self: {addr:?}");
+			pub_addr.set(Some(addr));
+		}
+		None if pub_addr.addr.is_some() => {
+			log::warn!("public address {:?} is no longer confirmed by remotes", pub_addr.addr);
+			pub_addr.set(None);
+		}
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DiscoverySystem, DiscoveryPacket, DiscoveryConfig, PeerListDiscovery, PeerListEntry, NotifyRecovery};
+	use crate::{NodeSystem, Coordinates, CoordinateAlgorithm, NetworkCoord, test_net::{TestNet, NetRequest, world, node_config, node_id, addr, record, open_session}};
+
+	fn entry(seed: u64, coordinates: Option<Coordinates>) -> PeerListEntry<TestNet> {
+		PeerListEntry { record: record(seed, seed as u16), coordinates }
+	}
+
+	// Only `peer_list_size` records of a received list are processed
+	#[test]
+	fn truncate_peer_list() {
+		let mut config = node_config(1);
+		config.discovery.max_peers = 16;
+		config.discovery.peer_list_size = 3;
+		let (mut world, _) = world::<DiscoverySystem<TestNet>>(config);
+		let (remote, _) = open_session(&mut world, 2, 2);
+		let list = (10..20).map(|seed|entry(seed, None)).collect();
+		DiscoverySystem::<TestNet>::handle_packet(&mut world, remote, DiscoveryPacket::PeerListDiscovery(PeerListDiscovery::PeerList(list)));
+		assert_eq!(world.resource::<TestNet>().take_requests().len(), 3);
+	}
+
+	// Introductions are limited by `max_peers` like peers from a `PeerList`
+	#[test]
+	fn limit_introductions() {
+		let mut config = node_config(1);
+		config.discovery.max_peers = 1;
+		let (mut world, _) = world::<DiscoverySystem<TestNet>>(config);
+		let (remote, _) = open_session(&mut world, 2, 2);
+		let want_peer = DiscoveryPacket::NotifyRecovery(NotifyRecovery::WantPeer { requester: record(3, 3), request_id: 0 });
+		DiscoverySystem::<TestNet>::handle_packet(&mut world, remote, want_peer.clone());
+		assert_eq!(world.resource::<TestNet>().take_requests(), []);
+
+		world.resource_mut::<DiscoveryConfig>().max_peers = 2;
+		DiscoverySystem::<TestNet>::handle_packet(&mut world, remote, want_peer);
+		assert_eq!(world.resource::<TestNet>().take_requests(), [NetRequest::Connect(node_id(3), addr(3))]);
+	}
+
+	// Fresh peers are ranked by the coordinates sent along in the list
+	#[test]
+	fn connect_closest_listed_peer() {
+		let mut config = node_config(1);
+		config.discovery.max_peers = 2;
+		let (mut world, _) = world::<DiscoverySystem<TestNet>>(config);
+		let coords = |value: f64|Coordinates { out_coord: NetworkCoord::from_element(value), in_coord: NetworkCoord::from_element(value), ..Coordinates::new(CoordinateAlgorithm::Dmf) };
+		world.insert_resource(coords(1.0));
+		let (remote, _) = open_session(&mut world, 2, 2);
+		let list = vec![entry(3, Some(coords(1.0))), entry(4, None), entry(5, Some(coords(0.001)))];
+		DiscoverySystem::<TestNet>::handle_packet(&mut world, remote, DiscoveryPacket::PeerListDiscovery(PeerListDiscovery::PeerList(list)));
+		assert_eq!(world.resource::<TestNet>().take_requests(), [NetRequest::Connect(node_id(5), addr(5))]);
+	}
+}
@@ -0,0 +1,541 @@
+//! This node system implements topic-based publish/subscribe over the sessions the node already has (similar to GossipSub).
+//! For each subscribed topic, messages are pushed to a small mesh of peers subscribed to the same topic. IDs of recent messages are gossiped to other subscribed peers so that they can request messages they missed.
+//! Messages are signed by their origin and only delivered or forwarded if the signature is valid.
+
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+
+use bevy_ecs::{prelude::*, world::EntityMut};
+use rand::seq::{SliceRandom, IteratorRandom};
+use rkyv::{Archive, Serialize, Deserialize};
+use bytecheck::CheckBytes;
+
+use crate::{NodeSystem, NodePacket, NodeEvent, Network, NodeID, NodeConfig, EncryptionKeys, EventSender, session::{Session, SessionCloseReason}};
+
+/// Identifies a gossip message, hash of its origin, sequence number, topic and data.
+pub type MessageID = hashdb::Hash;
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+pub struct GossipMessage {
+	pub topic: String,
+	/// Node that published the message.
+	pub origin: NodeID,
+	/// Incremented by the origin for every message it publishes.
+	pub seq: u64,
+	pub data: Vec<u8>,
+	/// Public key of the origin, its hash is the origin's NodeID.
+	pub public_key: Vec<u8>,
+	/// Signature of the origin over the origin, sequence number, topic and data.
+	pub signature: Vec<u8>,
+}
+impl GossipMessage {
+	/// Create and sign a message published by the local node.
+	pub fn new<Net: Network>(keys: &EncryptionKeys<Net>, origin: NodeID, seq: u64, topic: String, data: Vec<u8>) -> Self {
+		let mut message = Self { topic, origin, seq, data, public_key: keys.public_key.as_ref().to_vec(), signature: Vec::new() };
+		message.signature = Net::sign(keys, &message.signed_data());
+		message
+	}
+
+	// Bytes covered by the signature and the message ID
+	fn signed_data(&self) -> Vec<u8> {
+		[self.origin.as_bytes(), &self.seq.to_le_bytes(), self.topic.as_bytes(), &self.data].concat()
+	}
+
+	pub fn id(&self) -> MessageID {
+		MessageID::hash(&self.signed_data())
+	}
+
+	/// Check that the message was signed by its origin.
+	pub fn verify<Net: Network>(&self) -> bool {
+		NodeID::hash(&self.public_key) == self.origin && Net::verify(&self.public_key, &self.signed_data(), &self.signature)
+	}
+}
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+pub enum GossipPacket {
+	/// Sender is now subscribed to the topics, sent to every peer.
+	Subscribe(Vec<String>),
+	/// Sender is no longer subscribed to the topics.
+	Unsubscribe(Vec<String>),
+	/// Sender added the receiver to its mesh for the topic.
+	Graft(String),
+	/// Sender removed the receiver from its mesh for the topic.
+	Prune(String),
+	/// Full message, pushed to mesh peers.
+	Message(GossipMessage),
+	/// IDs of messages recently seen on a topic, sent to subscribed peers outside the mesh.
+	IHave {
+		topic: String,
+		ids: Vec<MessageID>,
+	},
+	/// Request messages announced with `IHave`.
+	IWant(Vec<MessageID>),
+}
+impl<Net: Network> From<GossipPacket> for NodePacket<Net> {
+	fn from(value: GossipPacket) -> Self {
+		NodePacket::GossipPacket(value)
+	}
+}
+
+/// Configures the gossip mesh.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+	/// Number of mesh peers per topic, must be between `mesh_low` and `mesh_high`.
+	pub mesh_degree: usize,
+	/// More peers are grafted if a topic's mesh has fewer peers than this.
+	pub mesh_low: usize,
+	/// Peers are pruned if a topic's mesh has more peers than this.
+	pub mesh_high: usize,
+	/// Number of subscribed peers outside the mesh that recent message IDs are gossiped to.
+	pub gossip_peers: usize,
+	/// How often meshes are maintained and message IDs are gossiped.
+	pub heartbeat_interval: Duration,
+	/// Messages are gossiped and can be requested with `IWant` for this long.
+	pub history: Duration,
+	/// IDs of seen messages are remembered for this long, messages seen again in this time are dropped.
+	pub seen_ttl: Duration,
+	/// Larger messages are dropped.
+	pub max_message_size: usize,
+	/// Maximum number of message IDs sent in an `IHave`, the most recent messages are announced. Additional IDs in received `IHave`s are ignored.
+	pub max_ihave_length: usize,
+	/// Maximum number of messages sent in response to one `IWant`.
+	pub max_iwant_messages: usize,
+	/// Maximum number of topics remembered per peer, additional subscriptions are ignored.
+	pub max_peer_topics: usize,
+	/// Subscriptions to longer topics are ignored.
+	pub max_topic_length: usize,
+}
+impl Default for GossipConfig {
+	fn default() -> Self {
+		Self {
+			mesh_degree: 6,
+			mesh_low: 4,
+			mesh_high: 12,
+			gossip_peers: 6,
+			heartbeat_interval: Duration::from_secs(1),
+			history: Duration::from_secs(5),
+			seen_ttl: Duration::from_secs(2 * 60),
+			max_message_size: 64 * 1024,
+			max_ihave_length: 256,
+			max_iwant_messages: 64,
+			max_peer_topics: 64,
+			max_topic_length: 256,
+		}
+	}
+}
+
+/// Topics a remote is subscribed to.
+#[derive(Debug, Default, Component)]
+pub struct GossipPeer {
+	topics: HashSet<String>,
+}
+impl GossipPeer {
+	/// Remember a topic the peer is subscribed to, returns false if the topic is too long or the peer has too many topics.
+	fn subscribe(&mut self, topic: String, config: &GossipConfig) -> bool {
+		if topic.len() > config.max_topic_length { return false }
+		if self.topics.len() >= config.max_peer_topics && !self.topics.contains(&topic) { return false }
+		self.topics.insert(topic);
+		true
+	}
+}
+
+#[derive(Resource)]
+struct GossipState {
+	/// Topics this node is subscribed to and their mesh peers.
+	mesh: HashMap<String, HashSet<Entity>>,
+	/// When each recently seen message was first received.
+	seen: HashMap<MessageID, Instant>,
+	/// Recent messages, sent in response to `IWant`.
+	history: HashMap<MessageID, (GossipMessage, Instant)>,
+	seq: u64,
+	last_heartbeat: Instant,
+}
+
+pub struct GossipSystem<Net: Network> {
+	_net: std::marker::PhantomData<Net::Address>,
+}
+
+impl<Net: Network> GossipSystem<Net> {
+	/// Subscribe to a topic, received messages are sent as `NodeEvent::GossipMessage`.
+	pub fn subscribe(world: &mut World, topic: String) {
+		if world.resource::<GossipState>().mesh.contains_key(&topic) { return }
+		world.resource_mut::<GossipState>().mesh.insert(topic.clone(), HashSet::new());
+		for session in world.query::<&Session<Net>>().iter(world) {
+			session.send_packet(GossipPacket::Subscribe(vec![topic.clone()]).into());
+		}
+		heartbeat::<Net>(world);
+	}
+	pub fn unsubscribe(world: &mut World, topic: String) {
+		let Some(mesh) = world.resource_mut::<GossipState>().mesh.remove(&topic) else { return };
+		for peer in mesh {
+			send::<Net>(world, peer, GossipPacket::Prune(topic.clone()));
+		}
+		for session in world.query::<&Session<Net>>().iter(world) {
+			session.send_packet(GossipPacket::Unsubscribe(vec![topic.clone()]).into());
+		}
+	}
+	/// Publish a message on a topic. Sent to the mesh if subscribed, otherwise to some peers subscribed to the topic.
+	pub fn publish(world: &mut World, topic: String, data: Vec<u8>) {
+		world.resource_mut::<GossipState>().seq += 1;
+		let seq = world.resource::<GossipState>().seq;
+		let config = world.resource::<NodeConfig<Net>>();
+		let message = GossipMessage::new(&config.keys, config.node_id.clone(), seq, topic, data);
+		let mut state = world.resource_mut::<GossipState>();
+		let id = message.id();
+		state.seen.insert(id.clone(), Instant::now());
+		state.history.insert(id, (message.clone(), Instant::now()));
+
+		let peers = match world.resource::<GossipState>().mesh.get(&message.topic) {
+			Some(mesh) => mesh.iter().cloned().collect::<Vec<Entity>>(),
+			None => {
+				let mesh_degree = world.resource::<GossipConfig>().mesh_degree;
+				subscribed_peers::<Net>(world, &message.topic).into_iter().choose_multiple(&mut rand::thread_rng(), mesh_degree)
+			}
+		};
+		log::debug!("gossip: publishing message {} on {:?} to {} peers", message.seq, message.topic, peers.len());
+		for peer in peers {
+			send::<Net>(world, peer, GossipPacket::Message(message.clone()));
+		}
+	}
+}
+
+impl<Net: Network> NodeSystem for GossipSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().gossip.clone();
+		world.insert_resource(config);
+		world.insert_resource(GossipState { mesh: HashMap::new(), seen: HashMap::new(), history: HashMap::new(), seq: 0, last_heartbeat: Instant::now() });
+	}
+
+	fn register_components(entity_mut: &mut EntityMut) {
+		entity_mut.insert(GossipPeer::default());
+	}
+
+	// Tell new peers which topics we are subscribed to
+	fn on_session_open(world: &mut World, entity: Entity) {
+		let topics = world.resource::<GossipState>().mesh.keys().cloned().collect::<Vec<String>>();
+		if !topics.is_empty() {
+			send::<Net>(world, entity, GossipPacket::Subscribe(topics));
+		}
+	}
+
+	fn on_session_closed(world: &mut World, entity: Entity, _reason: &SessionCloseReason) {
+		for mesh in world.resource_mut::<GossipState>().mesh.values_mut() {
+			mesh.remove(&entity);
+		}
+		world.entity_mut(entity).remove::<GossipPeer>();
+	}
+
+	fn on_tick(world: &mut World) {
+		let heartbeat_interval = world.resource::<GossipConfig>().heartbeat_interval;
+		if world.resource::<GossipState>().last_heartbeat.elapsed() >= heartbeat_interval {
+			heartbeat::<Net>(world);
+		}
+	}
+
+	type Packet = GossipPacket;
+
+	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
+		match packet {
+			GossipPacket::Subscribe(topics) => {
+				let config = world.resource::<GossipConfig>().clone();
+				let Some(mut peer) = world.get_mut::<GossipPeer>(entity) else { return };
+				let ignored = topics.into_iter().filter(|topic|!peer.subscribe(topic.clone(), &config)).count();
+				if ignored > 0 {
+					log::debug!("gossip: ignoring {ignored} subscriptions from {entity:?}");
+				}
+			}
+			GossipPacket::Unsubscribe(topics) => {
+				if let Some(mut peer) = world.get_mut::<GossipPeer>(entity) {
+					for topic in &topics { peer.topics.remove(topic); }
+				}
+				let mut state = world.resource_mut::<GossipState>();
+				for topic in &topics {
+					if let Some(mesh) = state.mesh.get_mut(topic) { mesh.remove(&entity); }
+				}
+			}
+			// Accept graft if subscribed to the topic, otherwise tell the peer to prune us
+			GossipPacket::Graft(topic) => {
+				let config = world.resource::<GossipConfig>().clone();
+				let subscribed = world.get_mut::<GossipPeer>(entity).map_or(false, |mut peer|peer.subscribe(topic.clone(), &config));
+				match world.resource_mut::<GossipState>().mesh.get_mut(&topic) {
+					Some(mesh) if subscribed && mesh.len() < config.mesh_high => { mesh.insert(entity); }
+					_ => send::<Net>(world, entity, GossipPacket::Prune(topic)),
+				}
+			}
+			GossipPacket::Prune(topic) => if let Some(mesh) = world.resource_mut::<GossipState>().mesh.get_mut(&topic) {
+				mesh.remove(&entity);
+			},
+			GossipPacket::Message(message) => handle_message::<Net>(world, entity, message),
+			// Request messages we haven't seen yet
+			GossipPacket::IHave { topic, ids } => {
+				let max_ihave_length = world.resource::<GossipConfig>().max_ihave_length;
+				let state = world.resource::<GossipState>();
+				if !state.mesh.contains_key(&topic) { return }
+				let wanted = ids.into_iter().take(max_ihave_length).filter(|id|!state.seen.contains_key(id)).collect::<Vec<MessageID>>();
+				if !wanted.is_empty() {
+					send::<Net>(world, entity, GossipPacket::IWant(wanted));
+				}
+			}
+			GossipPacket::IWant(ids) => {
+				let max_iwant_messages = world.resource::<GossipConfig>().max_iwant_messages;
+				let messages = ids.iter()
+					.filter_map(|id|world.resource::<GossipState>().history.get(id).map(|(message, _)|message.clone()))
+					.take(max_iwant_messages)
+					.collect::<Vec<GossipMessage>>();
+				for message in messages {
+					send::<Net>(world, entity, GossipPacket::Message(message));
+				}
+			}
+		}
+	}
+}
+
+fn send<Net: Network>(world: &World, entity: Entity, packet: GossipPacket) {
+	if let Some(session) = world.get::<Session<Net>>(entity) {
+		session.send_packet(packet.into());
+	}
+}
+
+/// Peers with a session that are subscribed to `topic`.
+fn subscribed_peers<Net: Network>(world: &mut World, topic: &str) -> Vec<Entity> {
+	world.query_filtered::<(Entity, &GossipPeer), With<Session<Net>>>().iter(world)
+		.filter(|(_, peer)|peer.topics.contains(topic))
+		.map(|(entity, _)|entity)
+		.collect()
+}
+
+// Deliver new messages on subscribed topics and forward them to the rest of the mesh
+fn handle_message<Net: Network>(world: &mut World, entity: Entity, message: GossipMessage) {
+	let max_message_size = world.resource::<GossipConfig>().max_message_size;
+	if message.data.len() > max_message_size {
+		log::debug!("gossip: dropping message of {} bytes from {entity:?}", message.data.len());
+		return;
+	}
+	let id = message.id();
+	if world.resource::<GossipState>().seen.contains_key(&id) { return }
+	// Checked before the message is marked as seen, so a forged copy doesn't cause the real message to be dropped
+	if !message.verify::<Net>() {
+		log::debug!("gossip: dropping message with invalid signature from {entity:?}");
+		return;
+	}
+	let mut state = world.resource_mut::<GossipState>();
+	state.seen.insert(id.clone(), Instant::now());
+	let Some(mesh) = state.mesh.get(&message.topic) else { return };
+	let peers = mesh.iter().filter(|peer|**peer != entity).cloned().collect::<Vec<Entity>>();
+	state.history.insert(id, (message.clone(), Instant::now()));
+
+	for peer in peers {
+		send::<Net>(world, peer, GossipPacket::Message(message.clone()));
+	}
+	let GossipMessage { topic, origin, data, .. } = message;
+	if let Err(err) = world.resource::<EventSender<Net>>().sender.unbounded_send(NodeEvent::GossipMessage(topic, origin, data)) {
+		log::error!("gossip: failed to send message event: {err}");
+	}
+}
+
+// Keep the mesh of each subscribed topic between `mesh_low` and `mesh_high` peers, gossip recent message IDs and forget old messages
+fn heartbeat<Net: Network>(world: &mut World) {
+	let config = world.resource::<GossipConfig>().clone();
+	world.resource_mut::<GossipState>().last_heartbeat = Instant::now();
+	let mut rng = rand::thread_rng();
+
+	let topics = world.resource::<GossipState>().mesh.keys().cloned().collect::<Vec<String>>();
+	for topic in topics {
+		let subscribed = subscribed_peers::<Net>(world, &topic);
+		let mut state = world.resource_mut::<GossipState>();
+		let mesh = state.mesh.get_mut(&topic).unwrap();
+		mesh.retain(|peer|subscribed.contains(peer));
+
+		let mut packets = Vec::new();
+		if mesh.len() < config.mesh_low {
+			let candidates = subscribed.iter().filter(|peer|!mesh.contains(peer)).cloned().collect::<Vec<Entity>>();
+			for peer in candidates.choose_multiple(&mut rng, config.mesh_degree.saturating_sub(mesh.len())) {
+				mesh.insert(*peer);
+				packets.push((*peer, GossipPacket::Graft(topic.clone())));
+			}
+		} else if mesh.len() > config.mesh_high {
+			let pruned = mesh.iter().cloned().choose_multiple(&mut rng, mesh.len().saturating_sub(config.mesh_degree));
+			for peer in pruned {
+				mesh.remove(&peer);
+				packets.push((peer, GossipPacket::Prune(topic.clone())));
+			}
+		}
+
+		let mesh = mesh.clone();
+		let mut recent = state.history.iter()
+			.filter(|(_, (message, _))|message.topic == topic)
+			.map(|(id, (_, received))|(*received, id.clone()))
+			.collect::<Vec<(Instant, MessageID)>>();
+		recent.sort_by(|(a, _), (b, _)|b.cmp(a));
+		let ids = recent.into_iter().take(config.max_ihave_length).map(|(_, id)|id).collect::<Vec<MessageID>>();
+		if !ids.is_empty() {
+			let lazy = subscribed.iter().filter(|peer|!mesh.contains(peer)).cloned().choose_multiple(&mut rng, config.gossip_peers);
+			for peer in lazy {
+				packets.push((peer, GossipPacket::IHave { topic: topic.clone(), ids: ids.clone() }));
+			}
+		}
+		for (peer, packet) in packets {
+			send::<Net>(world, peer, packet);
+		}
+	}
+
+	let mut state = world.resource_mut::<GossipState>();
+	state.seen.retain(|_, seen|seen.elapsed() < config.seen_ttl);
+	state.history.retain(|_, (_, received)|received.elapsed() < config.history);
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy_ecs::prelude::*;
+	use futures::channel::mpsc::UnboundedReceiver;
+
+	use super::{GossipSystem, GossipPacket, GossipMessage, GossipPeer, GossipConfig, GossipState, heartbeat};
+	use crate::{NodeSystem, NodePacket, NodeEvent, session::SessionAction, test_net::{TestNet, world, node_config, node_id, keys, open_session, sent_packets}};
+
+	fn gossip_packets(actions: &mut UnboundedReceiver<SessionAction<TestNet>>) -> Vec<GossipPacket> {
+		sent_packets(actions).into_iter().filter_map(|packet|match packet { NodePacket::GossipPacket(packet) => Some(packet), _ => None }).collect()
+	}
+
+	// Add a peer subscribed to `topic`
+	fn subscribed_peer(world: &mut World, seed: u64, topic: &str) -> (Entity, UnboundedReceiver<SessionAction<TestNet>>) {
+		let (entity, actions) = open_session(world, seed, seed as u16);
+		world.entity_mut(entity).insert(GossipPeer::default());
+		GossipSystem::<TestNet>::handle_packet(world, entity, GossipPacket::Subscribe(vec![topic.to_owned()]));
+		(entity, actions)
+	}
+
+	#[test]
+	fn deduplicate_messages() {
+		let (mut world, mut events) = world::<GossipSystem<TestNet>>(node_config(1));
+		let (sender, _) = subscribed_peer(&mut world, 2, "topic");
+		let (_, mut mesh_peer) = subscribed_peer(&mut world, 3, "topic");
+		GossipSystem::<TestNet>::subscribe(&mut world, "topic".to_owned());
+		gossip_packets(&mut mesh_peer);
+
+		let message = GossipMessage::new(&keys(2), node_id(2), 1, "topic".to_owned(), b"data".to_vec());
+		GossipSystem::<TestNet>::handle_packet(&mut world, sender, GossipPacket::Message(message.clone()));
+		GossipSystem::<TestNet>::handle_packet(&mut world, sender, GossipPacket::Message(message));
+		assert!(matches!(&gossip_packets(&mut mesh_peer)[..], [GossipPacket::Message(message)] if message.seq == 1));
+		assert!(matches!(events.try_next(), Ok(Some(NodeEvent::GossipMessage(topic, origin, _))) if topic == "topic" && origin == node_id(2)));
+		assert!(events.try_next().is_err());
+	}
+
+	#[test]
+	fn mesh_bounds() {
+		let mut config = node_config(1);
+		config.gossip = GossipConfig { mesh_low: 2, mesh_degree: 3, mesh_high: 4, ..Default::default() };
+		let (mut world, _) = world::<GossipSystem<TestNet>>(config);
+		let peers = (2..10).map(|seed|subscribed_peer(&mut world, seed, "topic")).collect::<Vec<_>>();
+
+		// Subscribing grafts `mesh_degree` peers
+		GossipSystem::<TestNet>::subscribe(&mut world, "topic".to_owned());
+		assert_eq!(world.resource::<GossipState>().mesh["topic"].len(), 3);
+
+		// Grafts from other peers are accepted up to `mesh_high`
+		for (peer, _) in &peers {
+			GossipSystem::<TestNet>::handle_packet(&mut world, *peer, GossipPacket::Graft("topic".to_owned()));
+		}
+		assert_eq!(world.resource::<GossipState>().mesh["topic"].len(), 4);
+
+		// A mesh over `mesh_high` is pruned back to `mesh_degree`
+		world.resource_mut::<GossipState>().mesh.insert("topic".to_owned(), peers.iter().map(|(peer, _)|*peer).collect());
+		heartbeat::<TestNet>(&mut world);
+		assert_eq!(world.resource::<GossipState>().mesh["topic"].len(), 3);
+
+		// Mesh sizes that don't satisfy `mesh_low <= mesh_degree` don't underflow
+		world.resource_mut::<GossipConfig>().mesh_degree = 0;
+		world.resource_mut::<GossipState>().mesh.insert("topic".to_owned(), Default::default());
+		heartbeat::<TestNet>(&mut world);
+		assert!(world.resource::<GossipState>().mesh["topic"].is_empty());
+	}
+
+	// A message missed by node 2 is announced with IHave and requested with IWant
+	#[test]
+	fn recover_with_ihave() {
+		// Publisher keeps no mesh, so the message only spreads through gossip
+		let mut config = node_config(1);
+		config.gossip = GossipConfig { mesh_low: 0, mesh_degree: 0, ..Default::default() };
+		let (mut publisher, _) = world::<GossipSystem<TestNet>>(config);
+		let (mut receiver, mut events) = world::<GossipSystem<TestNet>>(node_config(2));
+		GossipSystem::<TestNet>::subscribe(&mut publisher, "topic".to_owned());
+		GossipSystem::<TestNet>::subscribe(&mut receiver, "topic".to_owned());
+		let (to_receiver, mut receiver_sent) = subscribed_peer(&mut publisher, 2, "topic");
+		let (to_publisher, mut publisher_sent) = subscribed_peer(&mut receiver, 1, "topic");
+
+		GossipSystem::<TestNet>::publish(&mut publisher, "topic".to_owned(), b"data".to_vec());
+		assert!(gossip_packets(&mut receiver_sent).is_empty());
+		heartbeat::<TestNet>(&mut publisher);
+		let ihave = gossip_packets(&mut receiver_sent).into_iter().find(|packet|matches!(packet, GossipPacket::IHave { .. })).expect("no IHave sent");
+
+		GossipSystem::<TestNet>::handle_packet(&mut receiver, to_publisher, ihave);
+		let iwant = gossip_packets(&mut publisher_sent).into_iter().find(|packet|matches!(packet, GossipPacket::IWant(_))).expect("no IWant sent");
+		GossipSystem::<TestNet>::handle_packet(&mut publisher, to_receiver, iwant);
+		let message = gossip_packets(&mut receiver_sent).pop().expect("message not sent");
+		GossipSystem::<TestNet>::handle_packet(&mut receiver, to_publisher, message);
+		assert!(matches!(events.try_next(), Ok(Some(NodeEvent::GossipMessage(_, origin, data))) if origin == node_id(1) && data == b"data"));
+	}
+
+	#[test]
+	fn limit_ihave_and_iwant() {
+		let mut config = node_config(1);
+		config.gossip = GossipConfig { mesh_low: 0, mesh_degree: 0, max_ihave_length: 2, max_iwant_messages: 1, ..Default::default() };
+		let (mut world, _) = world::<GossipSystem<TestNet>>(config);
+		GossipSystem::<TestNet>::subscribe(&mut world, "topic".to_owned());
+		for _ in 0..3 {
+			GossipSystem::<TestNet>::publish(&mut world, "topic".to_owned(), b"data".to_vec());
+		}
+		let (peer, mut sent) = subscribed_peer(&mut world, 2, "topic");
+		heartbeat::<TestNet>(&mut world);
+		let packets = gossip_packets(&mut sent);
+		let ids = packets.iter().find_map(|packet|match packet { GossipPacket::IHave { ids, .. } => Some(ids.clone()), _ => None }).expect("no IHave sent");
+		assert_eq!(ids.len(), 2);
+
+		GossipSystem::<TestNet>::handle_packet(&mut world, peer, GossipPacket::IWant(ids));
+		assert_eq!(gossip_packets(&mut sent).len(), 1);
+	}
+
+	// Messages not signed by their origin are neither delivered nor forwarded
+	#[test]
+	fn drop_forged_messages() {
+		let (mut world, mut events) = world::<GossipSystem<TestNet>>(node_config(1));
+		let (sender, _) = subscribed_peer(&mut world, 2, "topic");
+		let (_, mut mesh_peer) = subscribed_peer(&mut world, 3, "topic");
+		GossipSystem::<TestNet>::subscribe(&mut world, "topic".to_owned());
+		gossip_packets(&mut mesh_peer);
+
+		// Signed by node 2 but claiming to come from node 3
+		let mut forged = GossipMessage::new(&keys(2), node_id(2), 1, "topic".to_owned(), b"data".to_vec());
+		forged.origin = node_id(3);
+		GossipSystem::<TestNet>::handle_packet(&mut world, sender, GossipPacket::Message(forged));
+		// Altered data
+		let mut altered = GossipMessage::new(&keys(3), node_id(3), 1, "topic".to_owned(), b"data".to_vec());
+		altered.data = b"altered".to_vec();
+		GossipSystem::<TestNet>::handle_packet(&mut world, sender, GossipPacket::Message(altered));
+		assert!(gossip_packets(&mut mesh_peer).is_empty());
+		assert!(events.try_next().is_err());
+
+		// The real message with the same ID as the forged one is still delivered
+		let message = GossipMessage::new(&keys(3), node_id(3), 1, "topic".to_owned(), b"data".to_vec());
+		GossipSystem::<TestNet>::handle_packet(&mut world, sender, GossipPacket::Message(message));
+		assert!(matches!(events.try_next(), Ok(Some(NodeEvent::GossipMessage(_, origin, data))) if origin == node_id(3) && data == b"data"));
+	}
+
+	#[test]
+	fn limit_peer_topics() {
+		let mut config = node_config(1);
+		config.gossip = GossipConfig { max_peer_topics: 2, max_topic_length: 8, ..Default::default() };
+		let (mut world, _) = world::<GossipSystem<TestNet>>(config);
+		let (peer, _) = subscribed_peer(&mut world, 2, "a");
+		GossipSystem::<TestNet>::handle_packet(&mut world, peer, GossipPacket::Subscribe(vec!["too long topic".to_owned(), "b".to_owned(), "c".to_owned()]));
+		let topics = &world.get::<GossipPeer>(peer).unwrap().topics;
+		assert_eq!(topics.len(), 2);
+		assert!(topics.contains("a") && topics.contains("b"));
+
+		// Grafts for topics over the limit are pruned
+		GossipSystem::<TestNet>::subscribe(&mut world, "c".to_owned());
+		GossipSystem::<TestNet>::handle_packet(&mut world, peer, GossipPacket::Graft("c".to_owned()));
+		assert!(world.resource::<GossipState>().mesh["c"].is_empty());
+	}
+}
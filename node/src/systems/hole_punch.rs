@@ -0,0 +1,289 @@
+//! This node system connects nodes that can't dial each other directly (i.e. both are behind NATs) through TCP hole punching.
+//! A peer that has a session with both nodes tells each of them to dial the other at the same time, using the addresses it sees them at. The outgoing connection attempts open each NAT for the other side's attempt and the resulting connection is handled like any other.
+//! The requester only follows `Connect` from the peer it asked to coordinate. The target can't tell a real coordination apart, so it dials at most `HolePunchConfig::max_incoming` coordinated punches at once and only one per coordinating peer.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use bevy_ecs::prelude::*;
+use rand::seq::SliceRandom;
+use rkyv::{Archive, Serialize, Deserialize};
+use bytecheck::CheckBytes;
+
+use crate::{NodeSystem, NodePacket, Network, NodeID, NodeConfig, RemoteIDMap, Remote, LatencyMetrics, RoutingSystem, RelayConfig, session::{Session, SessionInfo}, session_count};
+
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[serde(bound(serialize="", deserialize=""))]
+#[archive_attr(derive(CheckBytes))]
+pub enum HolePunchPacket<Net: Network> {
+	/// Ask a peer to coordinate a hole punch with `target`.
+	RequestHolePunch {
+		target: NodeID,
+	},
+	/// Sent back in response to `RequestHolePunch` if the receiver has no session with the target.
+	TargetUnavailable {
+		target: NodeID,
+	},
+	/// Sent by the coordinating peer to both nodes, tells the receiver to dial `remote` at `addr` after `delay` microseconds.
+	/// The delays are adjusted by the latency to each node so that both dial at the same time.
+	Connect {
+		remote: NodeID,
+		addr: Net::Address,
+		delay: u64,
+	},
+}
+impl<Net: Network> From<HolePunchPacket<Net>> for NodePacket<Net> {
+	fn from(value: HolePunchPacket<Net>) -> Self {
+		NodePacket::HolePunchPacket(value)
+	}
+}
+
+/// Configures hole punching.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HolePunchConfig {
+	/// Minimum time between coordinating a hole punch and both nodes dialing, `Connect` must reach both nodes before then.
+	pub punch_delay: Duration,
+	/// Time to wait for a session with the target before asking the next peer to coordinate.
+	pub attempt_timeout: Duration,
+	/// Maximum number of peers asked to coordinate a hole punch with the same target.
+	pub max_attempts: usize,
+	/// Maximum number of hole punches requested by other nodes that are dialed at once.
+	pub max_incoming: usize,
+}
+impl Default for HolePunchConfig {
+	fn default() -> Self {
+		Self {
+			punch_delay: Duration::from_secs(1),
+			attempt_timeout: Duration::from_secs(10),
+			max_attempts: 3,
+			max_incoming: 4,
+		}
+	}
+}
+
+/// Hole punch started by this node.
+struct HolePunch {
+	/// Peers that haven't been asked to coordinate yet.
+	candidates: Vec<Entity>,
+	/// Peer currently coordinating and when it was asked.
+	asked: Option<(Entity, Instant)>,
+}
+
+#[derive(Default, Resource)]
+struct HolePunchState {
+	punches: HashMap<NodeID, HolePunch>,
+	/// Hole punches requested by other nodes that we are dialing: requester -> (coordinating peer, when `Connect` was received)
+	incoming: HashMap<NodeID, (Entity, Instant)>,
+}
+
+pub struct HolePunchSystem<Net: Network> {
+	_net: std::marker::PhantomData<Net::Address>,
+}
+
+impl<Net: Network> HolePunchSystem<Net> {
+	/// Connect to `target` by asking peers we have a session with to coordinate a hole punch, one peer at a time until a session is established.
+	pub fn punch(world: &mut World, target: NodeID) {
+		let target_entity = world.resource::<RemoteIDMap>().map.get(&target).cloned();
+		if target_entity.map_or(false, |entity|world.get::<Session<Net>>(entity).is_some()) {
+			log::info!("hole punch: already connected to {target:?}");
+			return;
+		}
+		if world.resource::<HolePunchState>().punches.contains_key(&target) { return }
+
+		let max_attempts = world.resource::<HolePunchConfig>().max_attempts;
+		let mut query = world.query_filtered::<Entity, With<Session<Net>>>();
+		let mut candidates = query.iter(world).filter(|entity|Some(*entity) != target_entity).collect::<Vec<Entity>>();
+		candidates.shuffle(&mut rand::thread_rng());
+		candidates.truncate(max_attempts);
+		log::debug!("hole punch: connecting to {target:?} through up to {} peers", candidates.len());
+		world.resource_mut::<HolePunchState>().punches.insert(target.clone(), HolePunch { candidates, asked: None });
+		ask_next_peer::<Net>(world, target);
+	}
+}
+
+impl<Net: Network> NodeSystem for HolePunchSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().hole_punch.clone();
+		world.insert_resource(config);
+		world.init_resource::<HolePunchState>();
+	}
+
+	// Hole punch succeeded (or the target connected some other way)
+	fn on_session_open(world: &mut World, entity: Entity) {
+		let remote_id = world.get::<Remote>(entity).unwrap().id.clone();
+		let mut state = world.resource_mut::<HolePunchState>();
+		if state.punches.remove(&remote_id).is_some() | state.incoming.remove(&remote_id).is_some() {
+			log::info!("hole punch: connected to {remote_id:?}");
+		}
+	}
+
+	// Ask the next peer if the current one didn't get us connected in time
+	fn on_tick(world: &mut World) {
+		let timeout = world.resource::<HolePunchConfig>().attempt_timeout;
+		world.resource_mut::<HolePunchState>().incoming.retain(|_, (_, received)|received.elapsed() < timeout);
+		let timed_out = world.resource::<HolePunchState>().punches.iter()
+			.filter(|(_, punch)|punch.asked.map_or(false, |(_, asked)|asked.elapsed() >= timeout))
+			.map(|(target, _)|target.clone())
+			.collect::<Vec<NodeID>>();
+		for target in timed_out {
+			ask_next_peer::<Net>(world, target);
+		}
+	}
+
+	type Packet = HolePunchPacket<Net>;
+
+	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
+		let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+		match packet {
+			// Send each node the address we see the other at
+			HolePunchPacket::RequestHolePunch { target } => {
+				let target_entity = world.resource::<RemoteIDMap>().map.get(&target).cloned().filter(|target|*target != entity);
+				let Some(target_entity) = target_entity.filter(|target|world.get::<Session<Net>>(*target).is_some()) else {
+					world.get::<Session<Net>>(entity).unwrap().send_packet(HolePunchPacket::TargetUnavailable { target }.into());
+					return;
+				};
+				let punch_delay = world.resource::<HolePunchConfig>().punch_delay.as_micros() as u64;
+				let one_way_latency = |entity: Entity| world.get::<LatencyMetrics>(entity).map_or(0, |metrics|metrics.min_latency() / 2);
+				let (requester_latency, target_latency) = (one_way_latency(entity), one_way_latency(target_entity));
+				let at = punch_delay.max(2 * requester_latency.max(target_latency));
+
+				let requester_addr = world.get::<SessionInfo<Net>>(entity).unwrap().net_address.clone();
+				let target_addr = world.get::<SessionInfo<Net>>(target_entity).unwrap().net_address.clone();
+				log::debug!("hole punch: coordinating {remote_id:?} at {requester_addr} and {target:?} at {target_addr}");
+				world.get::<Session<Net>>(entity).unwrap().send_packet(HolePunchPacket::Connect { remote: target, addr: target_addr, delay: at - requester_latency }.into());
+				world.get::<Session<Net>>(target_entity).unwrap().send_packet(HolePunchPacket::Connect { remote: remote_id, addr: requester_addr, delay: at - target_latency }.into());
+			}
+			HolePunchPacket::TargetUnavailable { target } => {
+				let asked = world.resource::<HolePunchState>().punches.get(&target).and_then(|punch|punch.asked);
+				if asked.map_or(false, |(asked, _)|asked == entity) {
+					log::debug!("hole punch: {remote_id:?} has no session with {target:?}");
+					ask_next_peer::<Net>(world, target);
+				}
+			}
+			// Dial the remote, this is also received by nodes that didn't request the hole punch
+			HolePunchPacket::Connect { remote, addr, delay } => {
+				let config = world.resource::<NodeConfig<Net>>();
+				if remote == config.node_id || remote == remote_id { return }
+				let max_sessions = config.max_sessions;
+				let connected = world.resource::<RemoteIDMap>().map.get(&remote).map_or(false, |entity|world.get::<Session<Net>>(*entity).is_some());
+				if connected || session_count::<Net>(world) >= max_sessions { return }
+				let HolePunchConfig { attempt_timeout, max_incoming, .. } = *world.resource::<HolePunchConfig>();
+				let mut state = world.resource_mut::<HolePunchState>();
+				match state.punches.get(&remote) {
+					// Started by us, only the peer currently asked may coordinate
+					Some(punch) => if punch.asked.map_or(true, |(asked, _)|asked != entity) {
+						log::warn!("hole punch: ignoring Connect for {remote:?} from {remote_id:?}, it wasn't asked to coordinate");
+						return;
+					},
+					None => {
+						if state.incoming.contains_key(&remote) || state.incoming.len() >= max_incoming || state.incoming.values().any(|(coordinator, _)|*coordinator == entity) {
+							log::debug!("hole punch: ignoring Connect for {remote:?} from {remote_id:?}, too many pending hole punches");
+							return;
+						}
+						state.incoming.insert(remote.clone(), (entity, Instant::now()));
+					}
+				}
+				// The attempt must not outlive the hole punch
+				let delay = Duration::from_micros(delay).min(attempt_timeout);
+				log::debug!("hole punch: dialing {remote:?} at {addr} in {delay:?} as coordinated by {remote_id:?}");
+				world.resource::<Net>().punch(remote, addr, delay);
+			}
+		}
+	}
+}
+
+// Ask the next candidate to coordinate the hole punch with `target`, gives up once all candidates were asked
+fn ask_next_peer<Net: Network>(world: &mut World, target: NodeID) {
+	let mut state = world.resource_mut::<HolePunchState>();
+	let Some(punch) = state.punches.get_mut(&target) else { return };
+	let candidates = std::mem::take(&mut punch.candidates);
+	let mut candidates = candidates.into_iter();
+	let peer = candidates.by_ref().find(|peer|world.get::<Session<Net>>(*peer).is_some());
+
+	let mut state = world.resource_mut::<HolePunchState>();
+	let Some(peer) = peer else {
+		state.punches.remove(&target);
+		// Fall back to a relayed connection if relaying is registered
+		if world.contains_resource::<RelayConfig>() {
+			log::info!("hole punch: failed to connect to {target:?}, trying relays");
+			RoutingSystem::<Net>::connect_relayed(world, target);
+		} else {
+			log::warn!("hole punch: failed to connect to {target:?}, no more peers to coordinate");
+		}
+		return;
+	};
+	let punch = state.punches.get_mut(&target).unwrap();
+	punch.candidates = candidates.collect();
+	punch.asked = Some((peer, Instant::now()));
+	world.get::<Session<Net>>(peer).unwrap().send_packet(HolePunchPacket::RequestHolePunch { target }.into());
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy_ecs::prelude::*;
+
+	use super::{HolePunchSystem, HolePunchPacket, HolePunchConfig};
+	use crate::{NodeSystem, NodePacket, test_net::{TestNet, NetRequest, world, node_config, node_id, addr, open_session, sent_packets}};
+
+	fn hole_punch_packets(packets: Vec<NodePacket<TestNet>>) -> Vec<HolePunchPacket<TestNet>> {
+		packets.into_iter().filter_map(|packet|match packet { NodePacket::HolePunchPacket(packet) => Some(packet), _ => None }).collect()
+	}
+
+	// Requester 1 and target 2 both have a session with coordinator 3
+	#[test]
+	fn coordinate_hole_punch() {
+		let (mut requester, _) = world::<HolePunchSystem<TestNet>>(node_config(1));
+		let (mut coordinator, _) = world::<HolePunchSystem<TestNet>>(node_config(3));
+		let (mut target, _) = world::<HolePunchSystem<TestNet>>(node_config(2));
+
+		// Requester asks the coordinator, its only peer
+		let (requester_to_coordinator, mut coordinator_sent) = open_session(&mut requester, 3, 3);
+		HolePunchSystem::<TestNet>::punch(&mut requester, node_id(2));
+		assert!(matches!(&hole_punch_packets(sent_packets(&mut coordinator_sent))[..], [HolePunchPacket::RequestHolePunch { target }] if *target == node_id(2)));
+		let (requester_to_other, _) = open_session(&mut requester, 4, 4);
+
+		// Coordinator tells both to dial the address it sees the other at
+		let (coordinator_to_requester, mut requester_sent) = open_session(&mut coordinator, 1, 1);
+		let (_, mut target_sent) = open_session(&mut coordinator, 2, 2);
+		HolePunchSystem::<TestNet>::handle_packet(&mut coordinator, coordinator_to_requester, HolePunchPacket::RequestHolePunch { target: node_id(2) });
+		let mut to_requester = hole_punch_packets(sent_packets(&mut requester_sent));
+		let mut to_target = hole_punch_packets(sent_packets(&mut target_sent));
+		assert!(matches!(&to_requester[..], [HolePunchPacket::Connect { remote, addr: a, .. }] if *remote == node_id(2) && *a == addr(2)));
+		assert!(matches!(&to_target[..], [HolePunchPacket::Connect { remote, addr: a, .. }] if *remote == node_id(1) && *a == addr(1)));
+
+		// Requester ignores Connect from a peer it didn't ask, then dials once the coordinator's arrives
+		let connect = to_requester.pop().unwrap();
+		HolePunchSystem::<TestNet>::handle_packet(&mut requester, requester_to_other, connect.clone());
+		assert_eq!(requester.resource::<TestNet>().take_requests(), []);
+		HolePunchSystem::<TestNet>::handle_packet(&mut requester, requester_to_coordinator, connect);
+		assert!(matches!(&requester.resource::<TestNet>().take_requests()[..], [NetRequest::Punch(remote, a, _)] if *remote == node_id(2) && *a == addr(2)));
+
+		// Target dials the requester, but only one hole punch per coordinator at a time
+		let (target_to_coordinator, _) = open_session(&mut target, 3, 3);
+		HolePunchSystem::<TestNet>::handle_packet(&mut target, target_to_coordinator, to_target.pop().unwrap());
+		assert!(matches!(&target.resource::<TestNet>().take_requests()[..], [NetRequest::Punch(remote, a, _)] if *remote == node_id(1) && *a == addr(1)));
+		HolePunchSystem::<TestNet>::handle_packet(&mut target, target_to_coordinator, HolePunchPacket::Connect { remote: node_id(5), addr: addr(5), delay: 0 });
+		assert_eq!(target.resource::<TestNet>().take_requests(), []);
+	}
+
+	#[test]
+	fn clamp_delay() {
+		let (mut world, _) = world::<HolePunchSystem<TestNet>>(node_config(2));
+		let (coordinator, _) = open_session(&mut world, 3, 3);
+		HolePunchSystem::<TestNet>::handle_packet(&mut world, coordinator, HolePunchPacket::Connect { remote: node_id(1), addr: addr(1), delay: u64::MAX });
+		let timeout = world.resource::<HolePunchConfig>().attempt_timeout;
+		assert_eq!(world.resource::<TestNet>().take_requests(), [NetRequest::Punch(node_id(1), addr(1), timeout)]);
+	}
+
+	// Pending punches requested by others are limited
+	#[test]
+	fn limit_incoming() {
+		let (mut world, _) = world::<HolePunchSystem<TestNet>>(node_config(1));
+		let max_incoming = world.resource::<HolePunchConfig>().max_incoming as u64;
+		let coordinators = (0..max_incoming + 1).map(|seed|open_session(&mut world, 100 + seed, 100 + seed as u16).0).collect::<Vec<Entity>>();
+		for (seed, coordinator) in (0..).zip(coordinators) {
+			HolePunchSystem::<TestNet>::handle_packet(&mut world, coordinator, HolePunchPacket::Connect { remote: node_id(200 + seed), addr: addr(200 + seed as u16), delay: 0 });
+		}
+		assert_eq!(world.resource::<TestNet>().take_requests().len() as u64, max_incoming);
+	}
+}
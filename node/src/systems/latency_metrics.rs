@@ -2,65 +2,94 @@ use std::{time::{Duration, Instant}, collections::VecDeque, marker::PhantomData}
 
 use bevy_ecs::prelude::*;
 
-use crate::{NodeSystem, Network, Latency, session::{Session, SessionAction}};
+use crate::{NodeSystem, Network, Latency, NodeConfig, session::{Session, SessionAction, SessionCloseReason}};
 
 pub struct LatencyMetricsSystem<Net: Network> {
 	_net: PhantomData<Net::Address>,
 }
 
 impl<Net: Network> NodeSystem for LatencyMetricsSystem<Net> {
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().latency_metrics.clone();
+		world.insert_resource(config);
+	}
+
     fn register_systems(schedule: &mut Schedule) {
-		schedule.add_system(session_setup::<Net>);
 		schedule.add_system(notify_session_to_ping::<Net>);
     }
 
+	fn on_session_open(world: &mut World, entity: Entity) {
+		// Should ping at least once when session is established (We need this because we only create the LatencyMetrics component when first receiving a measurement)
+		world.entity(entity).get::<Session<Net>>().unwrap().send_action(SessionAction::Ping(Some(1)));
+	}
+
+	// Measurements from a previous session are stale, a new session starts measuring from scratch
+	fn on_session_closed(world: &mut World, entity: Entity, _reason: &SessionCloseReason) {
+		world.entity_mut(entity).remove::<LatencyMetrics>();
+	}
+
     type Packet = Duration;
 
     fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
 		let latency = packet.as_micros() as u64;
+		let max_measurements = world.resource::<LatencyMetricsConfig>().max_measurements;
 		if let Some(mut metrics) = world.entity_mut(entity).get_mut::<LatencyMetrics>() {
-			metrics.register_latency(latency)
+			metrics.register_latency(latency, max_measurements)
 		} else {
-			world.entity_mut(entity).insert(LatencyMetrics::new(latency));
+			world.entity_mut(entity).insert(LatencyMetrics::new(latency, max_measurements));
 		}
 		
 	}	
 }
 
-fn session_setup<Net: Network>(query: Query<&Session<Net>, Added<Session<Net>>>) {
-	// Should ping at least once when session is established (We need this because we only create the LatencyMetrics component when first receiving a measurement)
-	for session in &query {
-		session.send_action(SessionAction::Ping(Some(1)));
+/// Configures how often remotes are pinged.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct LatencyMetricsConfig {
+	/// Number of recent measurements kept per remote, remotes are pinged until this many measurements are collected.
+	pub max_measurements: usize,
+	/// Ping a remote again if there was no measurement for this long.
+	pub ping_timeout: Duration,
+	/// Notify a session to ping if there was no measurement for this long.
+	pub ping_interval: Duration,
+}
+impl Default for LatencyMetricsConfig {
+	fn default() -> Self {
+		Self {
+			max_measurements: 20,
+			ping_timeout: Duration::from_secs(3),
+			ping_interval: Duration::from_millis(1000),
+		}
 	}
 }
 
-pub const MAX_MEASUREMENT_COUNT: usize = 20;
-
 /// Information about latency measurements with a remote node
 #[derive(Debug, Clone, Component)]
 pub struct LatencyMetrics {
 	latencies: VecDeque<Latency>,
 	min_latency: Latency,
 
+	first_update: Instant,
 	last_update: Instant,
 
 	pending_pings: usize,
 }
 impl LatencyMetrics {
-	pub fn new(latency: Latency) -> Self {
+	pub fn new(latency: Latency, max_measurements: usize) -> Self {
 		let mut ret = LatencyMetrics {
 			latencies: VecDeque::new(),
 			min_latency: latency,
+			first_update: Instant::now(),
 			last_update: Instant::now(),
 			pending_pings: 0,
 		};
-		ret.register_latency(latency);
+		ret.register_latency(latency, max_measurements);
 		ret
 	}
 	// Register latency
-	pub fn register_latency(&mut self, latency: Latency) {
+	pub fn register_latency(&mut self, latency: Latency, max_measurements: usize) {
 		self.latencies.push_back(latency);
-		if self.latencies.len() >= MAX_MEASUREMENT_COUNT { self.latencies.pop_front(); }
+		if self.latencies.len() >= max_measurements.max(2) { self.latencies.pop_front(); }
 		self.last_update = Instant::now();
 		self.pending_pings = self.pending_pings.saturating_sub(1);
 	}
@@ -70,14 +99,21 @@ impl LatencyMetrics {
 	pub fn min_latency(&self) -> Latency {
 		self.latencies.iter().min().unwrap().clone()
 	}
+	pub fn mean_latency(&self) -> Latency {
+		self.latencies.iter().sum::<Latency>() / self.latencies.len() as Latency
+	}
+	/// Recent latency measurements, oldest first
+	pub fn latencies(&self) -> impl Iterator<Item = Latency> + '_ {
+		self.latencies.iter().cloned()
+	}
 	/// How many more pings we would like to receive at this moment, will return None if there are already pending pings
-	pub fn how_many_more_pings(&mut self) -> Option<usize> {
+	pub fn how_many_more_pings(&mut self, config: &LatencyMetricsConfig) -> Option<usize> {
 		if self.pending_pings > 0 { return None }
-		// Need 1 ping if more than 5 seconds have passed, otherwise 0
-		let timeout_pings = (self.last_update.elapsed() >= Duration::from_secs(3)) as usize;
+		// Need 1 ping if more than ping_timeout has passed, otherwise 0
+		let timeout_pings = (self.last_update.elapsed() >= config.ping_timeout) as usize;
 		
-		// If there are less than 10 pings in the latency list, return the remaining needed number of pings
-		let count_pings = MAX_MEASUREMENT_COUNT.saturating_sub(self.latencies.len());
+		// If there are less than max_measurements pings in the latency list, return the remaining needed number of pings
+		let count_pings = config.max_measurements.saturating_sub(self.latencies.len());
 
 		// Return max of required pings of the various counts
 		self.pending_pings = usize::max(timeout_pings, count_pings);
@@ -86,15 +122,19 @@ impl LatencyMetrics {
 	pub fn last_update(&self) -> Instant {
 		self.last_update
 	}
+	/// When the first latency was measured, remotes that have been measured the longest have the oldest.
+	pub fn first_update(&self) -> Instant {
+		self.first_update
+	}
 }
 
-fn notify_session_to_ping<Net: Network>(mut query: Query<(&mut LatencyMetrics, &Session<Net>)>) {
+fn notify_session_to_ping<Net: Network>(mut query: Query<(&mut LatencyMetrics, &Session<Net>)>, config: Res<LatencyMetricsConfig>) {
 	for (mut metrics, sess) in query.iter_mut() {
-		if let Some(pings) = metrics.bypass_change_detection().how_many_more_pings() {
+		if let Some(pings) = metrics.bypass_change_detection().how_many_more_pings(&config) {
 			if pings > 0 {
 				sess.send_action(SessionAction::Ping(Some(pings)));
 			}
-		} else if metrics.last_update().elapsed() > Duration::from_millis(1000) { // If no measurement for more than 200 millis, notify session thread
+		} else if metrics.last_update().elapsed() > config.ping_interval { // If no measurement for more than ping_interval, notify session thread
 			sess.send_action(SessionAction::Ping(None));
 		}
 	}
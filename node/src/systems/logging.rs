@@ -17,7 +17,7 @@ fn coord_logging(own_coord: Res<Coordinates>, peers: Query<(&LatencyMetrics, &Co
 	if own_coord.is_changed() {
 		// Measure 
 		let predicted_lats = peers.iter().map(|(_, coord)|
-			(own_coord.out_coord.dot(&coord.in_coord) * 1000.0)
+			own_coord.predict_latencies(coord).0 as f64
 		).collect::<Vec<f64>>();
 		let latencies = peers.iter().map(|(lat, _)| {
 			lat.latest_latency() as f64
@@ -8,9 +8,10 @@ use bytecheck::CheckBytes;
 
 use rkyv::{Serialize, Archive, Deserialize};
 
-use crate::{LatencyMetrics, session::Session, NodePacket, Network, NodeSystem, Latency};
+use crate::{LatencyMetrics, session::{Session, SessionCloseReason}, NodePacket, Network, NodeSystem, Latency, NodeConfig, NodeCoordinator, CoordinateAlgorithm, RemoteSample, CoordDDHf64, CoordinateType, prediction_error};
 
-const COORDINATE_DIMENSIONS: usize = 5;
+/// Number of dimensions of network coordinates. This is part of the wire format of `Coordinates`, so it can't be configured at runtime.
+pub const COORDINATE_DIMENSIONS: usize = 5;
 
 pub type NetworkCoord = nalgebra::SVector<f64, COORDINATE_DIMENSIONS>;
 
@@ -22,27 +23,26 @@ pub enum NCSystemPacket {
 	NotifyNetworkCoordinates(Coordinates),
 }
 
-pub struct NCSystem<Net: Network> {
-	_net: PhantomData<Net::Address>,
+/// Calculates this node's network coordinates using the coordinator `C` and shares them with peers.
+pub struct NCSystem<Net: Network, C: NodeCoordinator = DmfCoordinator> {
+	_net: PhantomData<(Net::Address, C)>,
 }
-impl<Net: Network> NodeSystem for NCSystem<Net> {
+impl<Net: Network, C: NodeCoordinator> NodeSystem for NCSystem<Net, C> {
     fn register_resources(world: &mut World) {
         // Init NC Resources
-		world.insert_resource(Coordinates::new());
-		world.insert_resource(CoordinateSolver::new());
-		world.insert_resource(CoordinateSolverState::default());
-		world.insert_resource(CoordinateSolverProblem::default());
+		let config = world.resource::<NodeConfig<Net>>().nc.clone();
+		world.insert_resource(C::new(&config));
+		world.insert_resource(config);
+		world.insert_resource(Coordinates::new(C::ALGORITHM));
 		world.insert_resource(ShouldUpdate::default());
     }
 
     fn register_systems(schedule: &mut Schedule) {
 		// Register NC Systems
-		schedule.add_system(setup_session::<Net>);
-
 		schedule.add_systems((
 			nc_system_controller,
 			calculate_weights.run_if(resource_changed::<ShouldUpdate>()),
-			network_coordinate_system.run_if(resource_changed::<ShouldUpdate>()),
+			update_coordinates::<C>.run_if(resource_changed::<ShouldUpdate>()),
 			push_coordinates::<Net>.run_if(resource_changed::<Coordinates>()),
 		).chain());
 	}
@@ -51,8 +51,20 @@ impl<Net: Network> NodeSystem for NCSystem<Net> {
 		entity_mut.insert(CoordinateWeight::default());
 	}
 
+	// When a new session is established, send coordinates
+	fn on_session_open(world: &mut World, entity: Entity) {
+		let coords = world.resource::<Coordinates>().clone();
+		world.entity(entity).get::<Session<Net>>().unwrap().send_packet(NodePacket::NCSystemPacket(NCSystemPacket::NotifyNetworkCoordinates(coords)));
+	}
+
+	// Coordinates of a disconnected remote should no longer be used to update own coordinates
+	fn on_session_closed(world: &mut World, entity: Entity, _reason: &SessionCloseReason) {
+		world.entity_mut(entity).remove::<(Coordinates, CoordinateWeight)>();
+	}
+
     type Packet = NCSystemPacket;
 
+	// Doesn't depend on `C`, the algorithm in use is the one own coordinates are tagged with
     fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
 		match packet {
 			// My network coordinates have been requested, make sure to send them back
@@ -60,40 +72,98 @@ impl<Net: Network> NodeSystem for NCSystem<Net> {
 				let coords = world.resource::<Coordinates>();
 				world.entity(entity).get::<Session<Net>>().unwrap().send_packet(NodePacket::NCSystemPacket(NCSystemPacket::NotifyNetworkCoordinates(coords.clone())));
 			},
-			// Received a remote's network coordinates, make sure to record them.
+			// Received a remote's network coordinates, make sure to record them if they were calculated the same way as ours.
 			NCSystemPacket::NotifyNetworkCoordinates(coords) => {
+				let algorithm = world.resource::<Coordinates>().algorithm;
+				if coords.algorithm != algorithm {
+					log::debug!("ignoring {:?} coordinates from {:?}, using {:?}", coords.algorithm, entity, algorithm);
+					world.entity_mut(entity).remove::<Coordinates>();
+					return;
+				}
+				if !coords.is_valid() {
+					log::warn!("ignoring invalid coordinates from {:?}: {:?}", entity, coords);
+					world.entity_mut(entity).remove::<Coordinates>();
+					return;
+				}
 				log::debug!("received coordinates from {:?}: {:?}", entity, coords);
-				world.entity_mut(entity).insert(coords);
+				let error = coords.error.clamp(0.0, 1.0);
+				world.entity_mut(entity).insert(Coordinates { error, ..coords });
 			},
 		}
 	}
 	
 }
 
-// When a new session is established, send coordinates
-fn setup_session<Net: Network>(
-	coords: Res<Coordinates>,
-	query: Query<&Session<Net>, Added<Session<Net>>>
-) {
-	for session in &query {
-		session.send_packet(NodePacket::NCSystemPacket(NCSystemPacket::NotifyNetworkCoordinates(coords.clone())));
+/// Configures the calculation of network coordinates.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct NCConfig {
+	/// Algorithm used to calculate coordinates, nodes only use coordinates of remotes using the same algorithm.
+	pub algorithm: CoordinateAlgorithm,
+	/// Penalizes large coordinates to prevent overfitting (DMF and Phoenix).
+	pub regularization_coeff: f64,
+	/// How fast the error estimate adapts to new samples (Vivaldi's c_e).
+	pub vivaldi_error_const: f64,
+	/// How far a sample moves a node with a high error estimate (Vivaldi's c_c).
+	pub vivaldi_move_const: f64,
+}
+impl Default for NCConfig {
+	fn default() -> Self {
+		Self {
+			algorithm: CoordinateAlgorithm::Dmf,
+			regularization_coeff: 0.001,
+			vivaldi_error_const: 0.25,
+			vivaldi_move_const: 0.25,
+		}
 	}
 }
 
 #[derive(Debug, Clone, Default, Component, Resource, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct Coordinates {
+	pub algorithm: CoordinateAlgorithm,
 	pub out_coord: NetworkCoord, // Outgoing coord for this node dot incoming coord for remote = predicted RTT latency from this node to remote
 	pub in_coord: NetworkCoord,
+	/// Relative error estimate of these coordinates, starts at 1.0.
+	pub error: f64,
+	/// Number of latency samples used to calculate these coordinates, 0 if they are still the initial ones.
+	pub samples: u32,
 }
 impl Coordinates {
-	pub fn new() -> Self {
-		Coordinates { out_coord: NetworkCoord::new_random(), in_coord: NetworkCoord::new_random() }
+	/// Coordinates to start from, random (and non-negative) for DMF and Phoenix and the origin for Vivaldi.
+	pub fn new(algorithm: CoordinateAlgorithm) -> Self {
+		match algorithm {
+			CoordinateAlgorithm::Dmf | CoordinateAlgorithm::Phoenix => Coordinates { algorithm, out_coord: NetworkCoord::new_random(), in_coord: NetworkCoord::new_random(), error: 1.0, samples: 0 },
+			CoordinateAlgorithm::Vivaldi => Coordinates { algorithm, out_coord: NetworkCoord::zeros(), in_coord: NetworkCoord::zeros(), error: 1.0, samples: 0 },
+		}
+	}
+	/// Whether coordinates received from a remote can be used: every value is finite, and Phoenix coordinates are non-negative like the ones it calculates.
+	pub fn is_valid(&self) -> bool {
+		let mut values = self.out_coord.iter().chain(self.in_coord.iter());
+		let non_negative = self.algorithm != CoordinateAlgorithm::Phoenix || values.clone().all(|value|*value >= 0.0);
+		values.all(|value|value.is_finite()) && self.error.is_finite() && non_negative
+	}
+	/// Error estimate, coordinates that weren't calculated from any samples yet are maximally wrong.
+	pub fn effective_error(&self) -> f64 {
+		if self.samples == 0 { 1.0 } else { self.error.clamp(0.0, 1.0) }
 	}
+	/// How much these coordinates can be trusted, from 0.0 (initial coordinates) to 1.0 (no prediction error).
+	pub fn confidence(&self) -> f64 {
+		1.0 - self.effective_error()
+	}
+	/// Predicted latencies from this node to `other` and back, Vivaldi's predictions are symmetric.
 	pub fn predict_latencies(&self, other: &Coordinates) -> (Latency, Latency) {
-		let outgoing = (self.out_coord.dot(&other.in_coord) * 1000.0) as Latency;
-		let incoming = (self.in_coord.dot(&other.out_coord) * 1000.0) as Latency;
-		(outgoing, incoming)
+		match self.algorithm {
+			CoordinateAlgorithm::Dmf | CoordinateAlgorithm::Phoenix => {
+				let outgoing = (self.out_coord.dot(&other.in_coord) * 1000.0) as Latency;
+				let incoming = (self.in_coord.dot(&other.out_coord) * 1000.0) as Latency;
+				(outgoing, incoming)
+			}
+			CoordinateAlgorithm::Vivaldi => {
+				let distance = (CoordDDHf64::from_coordinates(self).distance(&CoordDDHf64::from_coordinates(other)) * 1000.0) as Latency;
+				(distance, distance)
+			}
+		}
 	}
 }
 impl ArgminDot<Coordinates, f64> for Coordinates {
@@ -104,16 +174,22 @@ impl ArgminDot<Coordinates, f64> for Coordinates {
 impl ArgminMul<f64, Coordinates> for Coordinates {
     fn mul(&self, other: &f64) -> Coordinates {
         Coordinates {
+			algorithm: self.algorithm,
 			out_coord: self.out_coord * *other,
-			in_coord: self.in_coord * *other
+			in_coord: self.in_coord * *other,
+			error: self.error,
+			samples: self.samples,
 		}
     }
 }
 impl ArgminAdd<Coordinates, Coordinates> for Coordinates {
     fn add(&self, other: &Coordinates) -> Coordinates {
         Coordinates {
+			algorithm: self.algorithm,
 			out_coord: self.out_coord + other.out_coord,
 			in_coord: self.in_coord + other.in_coord,
+			error: self.error,
+			samples: self.samples,
 		}
     }
 }
@@ -153,110 +229,137 @@ fn nc_system_controller(mut query: Query<(Ref<LatencyMetrics>, Ref<Coordinates>)
 /// 
 /// Other inspirations: [Phoenix](https://user.informatik.uni-goettingen.de/~ychen/papers/Phoenix_TNSM.pdf), [DMFSGD](https://arxiv.org/pdf/1201.1174.pdf)
 
+/// How much a remote's coordinates and latency contribute to the calculation of own coordinates.
 #[derive(Debug, Component, Default)]
-struct CoordinateWeight {
+pub struct CoordinateWeight {
 	value: f64,
 }
-fn calculate_weights(mut query: Query<(&LatencyMetrics, &mut CoordinateWeight)>) {
+impl CoordinateWeight {
+	pub fn value(&self) -> f64 {
+		self.value
+	}
+}
+// Weighs remotes by measurement age and by how accurate their coordinates are compared to our own
+fn calculate_weights(own: Res<Coordinates>, mut query: Query<(&LatencyMetrics, Option<&Coordinates>, &mut CoordinateWeight)>) {
 	// calculate last received measurement from nodes (a_max)
 	let now = Instant::now();
 
 	let a_max = query.iter()
-		.map(|(metrics, _)| now.duration_since(metrics.last_update()))
+		.map(|(metrics, _, _)| now.duration_since(metrics.last_update()))
 		.max()
 		.unwrap_or(Duration::from_micros(1)).max(Duration::from_micros(1));
 
 	// calculate duration_sum: sum (a_max - a_j) where a_j is a given peer's time since last measurement
 	let duration_sum = query.iter()
-		.map(|(metrics, _)|now.duration_since(metrics.last_update()))
+		.map(|(metrics, _, _)|now.duration_since(metrics.last_update()))
 		.map(|a_j|a_max - a_j)
 		.sum::<Duration>();
-
-	if duration_sum.is_zero() {
-		return
+	let count = query.iter().len();
+
+	// calculate weights: w_j = (a_max - a_j) / duration_sum * e_i / (e_i + e_j)
+	for (metrics, coords, mut weight) in query.iter_mut() {
+		// All measurements are the same age (or there is only one)
+		let age_weight = if duration_sum.is_zero() { 1.0 / count as f64 } else {
+			let a_j = now.duration_since(metrics.last_update()).as_secs_f64();
+			(a_max.as_secs_f64() - a_j) / duration_sum.as_secs_f64()
+		};
+		// Remotes with a lower error than ours are trusted more, as in Vivaldi
+		let remote_error = coords.map_or(1.0, |coords|coords.effective_error());
+		let total_error = own.effective_error() + remote_error;
+		let error_weight = if total_error > 0.0 { own.effective_error() / total_error } else { 0.5 };
+		weight.value = age_weight * error_weight;
 	}
+}
 
-	// calculate weights: w_j = (a_max - a_j) / duration_sum )
-	for (metrics, mut weight) in query.iter_mut() {
-		let a_j = now.duration_since(metrics.last_update()).as_secs_f64();
-		weight.value = (a_max.as_secs_f64() - a_j) / duration_sum.as_secs_f64();
+// Collects the measurements to remotes and updates own coordinates with them
+fn update_coordinates<C: NodeCoordinator>(
+	mut coordinates: ResMut<Coordinates>,
+	mut coordinator: ResMut<C>,
+	query: Query<(&Coordinates, &LatencyMetrics, &CoordinateWeight)>,
+) {
+	if query.is_empty() { return }
+	let remotes = query.iter()
+		.map(|(coords, metrics, weight)|RemoteSample { coords, metrics, weight: weight.value })
+		.collect::<Vec<RemoteSample>>();
+	if let Some(coords) = coordinator.update(&coordinates, &remotes) {
+		*coordinates = coords;
 	}
-	
 }
 
+/// Calculates coordinates through decentralized matrix factorization: the dot product of one node's `out_coord` and another's `in_coord` predicts the latency between them.
 #[derive(Resource)]
-struct CoordinateSolverState {
-	state: IterState<Coordinates, Coordinates, (), (), f64>
-}
-impl Default for CoordinateSolverState {
-    fn default() -> Self {
-        Self { state: IterState::new() }
-    }
-}
-#[derive(Resource)]
-struct CoordinateSolverProblem {
+pub struct DmfCoordinator {
+	solver: SteepestDescent<MoreThuenteLineSearch<Coordinates, Coordinates, f64>>,
+	state: IterState<Coordinates, Coordinates, (), (), f64>,
 	problem: Problem<CoordinateProblem>,
+	regularization_coeff: f64,
+	last_update: Option<Instant>,
 }
-impl Default for CoordinateSolverProblem {
-    fn default() -> Self {
-        Self { problem: Problem { problem: None, counts: Default::default() } }
-    }
-}
+impl NodeCoordinator for DmfCoordinator {
+	const ALGORITHM: CoordinateAlgorithm = CoordinateAlgorithm::Dmf;
 
-fn network_coordinate_system(
-	mut coordinates: ResMut<Coordinates>,
-	mut solver: ResMut<CoordinateSolver>,
-	mut solver_state: ResMut<CoordinateSolverState>,
-	mut solver_problem: ResMut<CoordinateSolverProblem>,
-	mut query: Query<(Entity, &Coordinates, &LatencyMetrics, &CoordinateWeight)>,
-) {
-	if query.is_empty() { return }
-	// log::debug!("running coordinate update using data from {:?}: coord: {:?}, lat: {:?}, weight: {:?}", entity, coordinates, metrics.latest_latency(), weight);
-	let problem = solver_problem.problem.problem.get_or_insert(CoordinateProblem::default());
-	problem.remote_measurements.clear();
-	problem.remote_coords.clear();
-	problem.remote_weights.clear();
-	problem.incoming = false;
-	// Update coordinate with all recent latencies and coordinates
-	for (entity, coords, metrics, weight) in query.iter() {
-		problem.remote_measurements.push(Duration::from_micros(metrics.latest_latency()).as_secs_f64() * 1000.0);
-		problem.remote_coords.push(coords.clone());
-		problem.remote_weights.push(weight.value);
+	fn new(config: &NCConfig) -> Self {
+		Self {
+			solver: SteepestDescent::new(MoreThuenteLineSearch::new()),
+			state: IterState::new(),
+			problem: Problem { problem: None, counts: Default::default() },
+			regularization_coeff: config.regularization_coeff,
+			last_update: None,
+		}
 	}
 
-	let mut state = solver_state.state.clone();
-	state.param = Some(coordinates.clone());
+	fn update(&mut self, own: &Coordinates, remotes: &[RemoteSample]) -> Option<Coordinates> {
+		// log::debug!("running coordinate update using data from {:?}: coord: {:?}, lat: {:?}, weight: {:?}", entity, coordinates, metrics.latest_latency(), weight);
+		let problem = self.problem.problem.get_or_insert(CoordinateProblem::default());
+		problem.remote_measurements.clear();
+		problem.remote_coords.clear();
+		problem.remote_weights.clear();
+		problem.incoming = false;
+		problem.regularization_coeff = self.regularization_coeff;
+		// Update coordinate with all recent latencies and coordinates
+		for remote in remotes {
+			problem.remote_measurements.push(remote.rtt());
+			problem.remote_coords.push(remote.coords.clone());
+			problem.remote_weights.push(remote.weight);
+		}
 
-	if state.get_iter() == 0 {
-		// log::debug!("Initiating state");
-		state = solver.solver.init(&mut solver_problem.problem, state).unwrap().0;
-		state.target_cost = 1.0; // prevents weird bug where optimization spits out weird coordinates if loss gets too low
-		state.update();
-		state.func_counts(&solver_problem.problem);
-	}
+		let mut state = self.state.clone();
+		state.param = Some(own.clone());
 
-	match solver.solver.next_iter(&mut solver_problem.problem, state.clone()) {
-		Ok((new_state, _)) => {
-			// Only update state if the new cost is not less than the target to avoid over optimizing.
-			if !(new_state.cost < state.target_cost) {
-				state = new_state;
-				state.func_counts(&solver_problem.problem);
-				state.update();
-				state.increment_iter();
+		if state.get_iter() == 0 {
+			// log::debug!("Initiating state");
+			state = self.solver.init(&mut self.problem, state).unwrap().0;
+			state.target_cost = 1.0; // prevents weird bug where optimization spits out weird coordinates if loss gets too low
+			state.update();
+			state.func_counts(&self.problem);
+		}
+
+		match self.solver.next_iter(&mut self.problem, state.clone()) {
+			Ok((new_state, _)) => {
+				// Only update state if the new cost is not less than the target to avoid over optimizing.
+				if !(new_state.cost < state.target_cost) {
+					state = new_state;
+					state.func_counts(&self.problem);
+					state.update();
+					state.increment_iter();
+				}
+			},
+			Err(err) => log::error!("error running coordinate solver: {err:?}"),
+		}
+		
+		self.state = state;
+		// Update personal coordinates
+		let mut coords = self.state.get_param().cloned();
+		match &mut coords {
+			Some(coords) => {
+				coords.error = prediction_error(coords, remotes.iter());
+				coords.samples = own.samples.saturating_add(remotes.iter().filter(|remote|remote.is_new(self.last_update)).count() as u32);
+				log::debug!("Updating coordinates: {:?} -> {:?}, state: {:?}", own, coords, self.state);
 			}
-		},
-		Err(err) => log::error!("error running coordinate solver: {err:?}"),
-	}
-	
-	
-	solver_state.state = state;
-	// Update personal coordinates
-	if let Some(coords) = solver_state.state.get_param() {
-		log::debug!("Updating coordinates: {:?} -> {:?}, state: {:?}", &*coordinates, coords, solver_state.state);
-		// log::debug!("Gradient: {:?}. Dist moved: {:?}", solver_state.state.get_gradient(), coordinates.out_coord.metric_distance(&coords.out_coord));
-		*coordinates = coords.clone();
-	} else {
-		log::warn!("Failed to calculate best parameter, current coords: {:?}, state: {:?}", &*coordinates, solver_state.state);
+			None => log::warn!("Failed to calculate best parameter, current coords: {:?}, state: {:?}", own, self.state),
+		}
+		self.last_update = Some(Instant::now());
+		coords
 	}
 }
 
@@ -271,19 +374,6 @@ fn push_coordinates<Net: Network>(
 }
 
 
-/// Solvevr wrapper for CoordinateProblem
-#[derive(Resource)]
-struct CoordinateSolver {
-	solver: SteepestDescent<MoreThuenteLineSearch<Coordinates, Coordinates, f64>>,
-}
-impl CoordinateSolver {
-	fn new() -> Self {
-		Self {
-			solver: SteepestDescent::new(MoreThuenteLineSearch::new())
-		}
-	}
-}
-
 /* /// Custom solver for CoordinateProblem
 #[derive(Resource)]
 struct CoordinateSolver {
@@ -332,6 +422,7 @@ struct CoordinateProblem {
 	remote_weights: Vec<f64>,
 	/// Whether or not the measurement was initiated from the remote (incoming = true), or initiated locally to the remote (incoming = false)
 	incoming: bool,
+	regularization_coeff: f64,
 }
 
 // Currently using L1 Norm as loss function
@@ -343,8 +434,6 @@ fn loss_gradient(expected: f64, prediction: f64) -> f64 {
 	-(expected - prediction).signum()
 }
 
-const REGULARIZATION_COEFF: f64 = 0.001;
-
 // Cost function and gradients given by: https://orbi.uliege.be/bitstream/2268/136727/1/phdthesis.pdf#page=36
 impl CostFunction for CoordinateProblem {
     type Param = Coordinates;
@@ -359,12 +448,12 @@ impl CostFunction for CoordinateProblem {
 			// Predictions & coordinates are directional (Out_a * In_b) = predicted rtt from a -> b.
 			let outgoing_prediction = param.out_coord.dot(&remote_coords.in_coord);
 			let incoming_prediction = param.in_coord.dot(&remote_coords.out_coord);
-			cost += loss(*remote_measurement, outgoing_prediction);
-        	cost += loss(*remote_measurement, incoming_prediction);
+			cost += *weight * loss(*remote_measurement, outgoing_prediction);
+			cost += *weight * loss(*remote_measurement, incoming_prediction);
 			
 			// Penalize large norms of (local) in and out coords (to prevent coordinates from overfitting or becoming larger than necessary)
-			// cost += REGULARIZATION_COEFF * param.out_coord.dot(&param.out_coord);
-			// cost += REGULARIZATION_COEFF * param.in_coord.dot(&param.in_coord);
+			// cost += self.regularization_coeff * param.out_coord.dot(&param.out_coord);
+			// cost += self.regularization_coeff * param.in_coord.dot(&param.in_coord);
 		}
 
 		Ok(cost)
@@ -388,8 +477,8 @@ impl Gradient for CoordinateProblem {
 			gradient_out += *weight * loss_gradient(*remote_measurement, outgoing_prediction) * remote_coords.in_coord;
 			gradient_in  += *weight * loss_gradient(*remote_measurement, incoming_prediction) * remote_coords.out_coord;
 		}
-		gradient_out += REGULARIZATION_COEFF * param.out_coord;
-		gradient_in += REGULARIZATION_COEFF * param.in_coord;
-		Ok(Coordinates { out_coord: gradient_out, in_coord: gradient_in })
+		gradient_out += self.regularization_coeff * param.out_coord;
+		gradient_in += self.regularization_coeff * param.in_coord;
+		Ok(Coordinates { algorithm: param.algorithm, out_coord: gradient_out, in_coord: gradient_in, error: param.error, samples: param.samples })
     }
 }
\ No newline at end of file
@@ -1,52 +1,538 @@
 //! This is the routing system
 //! The goal is to facilitate multiple types of routing based on the goals of the application.
 //! It takes connection requests from the application and establishes a routed connection of some type.
+//!
+//! Nodes that can't connect directly (not even by hole punching) can connect through a relay: a peer both nodes have a session with forwards a byte stream between them (a circuit).
+//! Each end passes its `RelayStream` to the `Network`, which runs its usual handshake over it. Circuits aren't encrypted by this system: the relay can read and alter a relayed session unless the `Network` handshake sets up encryption, which `TcpNoenc` doesn't.
+//! Relaying is opt-in and bandwidth-limited per circuit and over all circuits. The relay grants each end credit for the data it may send and more as that data is forwarded, so a fast sender waits instead of filling the relay's queue.
 
-use std::{marker::PhantomData, time::Instant};
+use std::{collections::{HashMap, VecDeque}, io, marker::PhantomData, pin::Pin, task::{Context, Poll}, time::{Duration, Instant}};
 
 use bevy_ecs::prelude::*;
-use futures::channel::mpsc;
+use futures::{channel::mpsc::{self, UnboundedSender, UnboundedReceiver, unbounded}, AsyncRead, AsyncWrite, StreamExt, ready};
+use rand::seq::SliceRandom;
 use rkyv::{Archive, Serialize, Deserialize};
 use bytecheck::CheckBytes;
 
-use crate::{NetworkCoord, Network, NodeSystem, NodeID, Remote};
+use crate::{NetworkCoord, Network, NodeSystem, NodeID, NodePacket, NodeConfig, Remote, RemoteIDMap, Disconnected, session::{Session, SessionAction, SessionInfo, SessionCloseReason}, session_count};
 
-/// Request for an entity to act as an onion route.
-/// Requires `Remote` and `Coordinates` components.
+/// Inserted on a remote to connect to it through a relay. Peers are asked to relay one at a time, removed once a session is established or no peer could relay.
 #[derive(Debug, Component)]
-pub struct RelayRequest;
+pub struct RelayRequest {
+	circuit_id: u64,
+	/// Peers that haven't been asked to relay yet.
+	candidates: Vec<Entity>,
+	/// Peer currently asked to relay and when it was asked.
+	asked: Option<(Entity, Instant)>,
+}
 
+/// Marks a remote whose session is carried over a circuit through another node.
 #[derive(Debug, Component)]
-pub struct Relay;
+pub struct Relay {
+	relay: Entity,
+	circuit_id: u64,
+}
 
 /// Request to find a relay at a specific coordinate. Applied as a component to existing entity to designate through which entity the relay should be searched.
 #[derive(Debug, Component)]
 pub struct RelaySearchRequest(NetworkCoord);
 
-#[derive(Debug, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Archive, Serialize, Deserialize, serde::Serialize, serde::Deserialize)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub enum RoutingSystemPacket {
-	RelayPacket(Vec<u8>),
+	/// Ask the receiver to relay a circuit to `target`, the circuit ID is chosen by the requester.
+	RequestRelay {
+		circuit_id: u64,
+		target: NodeID,
+	},
+	/// Sent by the relay to the target, `source` wants to connect through the relay.
+	RelayIncoming {
+		circuit_id: u64,
+		source: NodeID,
+	},
+	/// Sent by the target once it accepted the circuit, forwarded to the source by the relay.
+	RelayAccepted {
+		circuit_id: u64,
+	},
+	/// Circuit was refused or closed, sent by the relay or either end.
+	RelayClosed {
+		circuit_id: u64,
+	},
+	/// Data sent over a circuit.
+	RelayPacket {
+		circuit_id: u64,
+		data: Vec<u8>,
+	},
+	/// Sent by the relay to an end of a circuit, it may send `bytes` more bytes of data.
+	RelayCredit {
+		circuit_id: u64,
+		bytes: u64,
+	},
     // Traversal(TraversalPacket),
 }
+impl<Net: Network> From<RoutingSystemPacket> for NodePacket<Net> {
+	fn from(value: RoutingSystemPacket) -> Self {
+		NodePacket::RoutingSystemPacket(value)
+	}
+}
+
+/// Configures relaying.
+/// Warning: relayed sessions are only as private as the `Network` handshake makes them, with `TcpNoenc` the relay can read and alter everything sent over a circuit.
+#[derive(Debug, Clone, Resource, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+	/// Relay circuits for other nodes. Off by default because relaying uses this node's bandwidth, connecting through other relays is always possible.
+	/// This node can read and alter the circuits it relays unless the `Network` encrypts sessions.
+	pub enabled: bool,
+	/// Maximum number of circuits relayed at once.
+	pub max_circuits: usize,
+	/// Maximum number of bytes per second forwarded over a circuit, data over the limit is queued.
+	pub circuit_bandwidth: u64,
+	/// Maximum number of bytes per second forwarded over all circuits together.
+	pub relay_bandwidth: u64,
+	/// Credit granted to each end of a circuit, the maximum number of bytes queued per direction. Circuits are closed if an end sends more than its credit.
+	pub max_queued: usize,
+	/// Time to wait for a circuit to be accepted before giving up on the relay.
+	pub request_timeout: Duration,
+	/// Maximum number of peers asked to relay a connection to the same remote.
+	pub max_attempts: usize,
+}
+impl Default for RelayConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			max_circuits: 16,
+			circuit_bandwidth: 64 * 1024,
+			relay_bandwidth: 256 * 1024,
+			max_queued: 256 * 1024,
+			request_timeout: Duration::from_secs(10),
+			max_attempts: 3,
+		}
+	}
+}
+
+/// Maximum number of bytes sent in one `RelayPacket`.
+const MAX_RELAY_PACKET: usize = 16 * 1024;
+
+/// One end of a circuit, a byte stream carried in `RelayPacket`s over the session with the relay.
+/// Writes wait for credit from the relay. Reads return EOF once the circuit is closed, dropping the stream closes the circuit.
+pub struct RelayStream<Net: Network> {
+	circuit_id: u64,
+	relay: UnboundedSender<SessionAction<Net>>,
+	incoming: UnboundedReceiver<Vec<u8>>,
+	/// Received data that wasn't read yet.
+	buffer: Vec<u8>,
+	/// Credit granted by the relay.
+	credit_grants: UnboundedReceiver<usize>,
+	/// Number of bytes that may be sent before waiting for more credit.
+	credit: usize,
+	closed: bool,
+}
+impl<Net: Network> RelayStream<Net> {
+	fn send(&self, packet: RoutingSystemPacket) -> io::Result<()> {
+		self.relay.unbounded_send(SessionAction::Packet(packet.into())).map_err(|_|io::Error::new(io::ErrorKind::BrokenPipe, "session with relay closed"))
+	}
+}
+impl<Net: Network> AsyncRead for RelayStream<Net> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		while self.buffer.is_empty() {
+			match ready!(self.incoming.poll_next_unpin(cx)) {
+				Some(data) => self.buffer = data,
+				None => return Poll::Ready(Ok(0)),
+			}
+		}
+		let len = buf.len().min(self.buffer.len());
+		buf[..len].copy_from_slice(&self.buffer[..len]);
+		self.buffer.drain(..len);
+		Poll::Ready(Ok(len))
+	}
+}
+impl<Net: Network> AsyncWrite for RelayStream<Net> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		if self.closed { return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())) }
+		while self.credit == 0 {
+			match ready!(self.credit_grants.poll_next_unpin(cx)) {
+				Some(bytes) => self.credit += bytes,
+				None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+			}
+		}
+		let len = buf.len().min(MAX_RELAY_PACKET).min(self.credit);
+		self.send(RoutingSystemPacket::RelayPacket { circuit_id: self.circuit_id, data: buf[..len].to_vec() })?;
+		self.credit -= len;
+		Poll::Ready(Ok(len))
+	}
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+	fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		if !self.closed {
+			self.closed = true;
+			let _ = self.send(RoutingSystemPacket::RelayClosed { circuit_id: self.circuit_id });
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+impl<Net: Network> Drop for RelayStream<Net> {
+	fn drop(&mut self) {
+		if !self.closed {
+			let _ = self.send(RoutingSystemPacket::RelayClosed { circuit_id: self.circuit_id });
+		}
+	}
+}
+
+/// Circuit relayed by this node.
+struct Circuit {
+	source: Entity,
+	target: Entity,
+	accepted: bool,
+	created: Instant,
+	/// Data waiting to be forwarded because of the bandwidth limit, with the entity it is sent to.
+	queue: VecDeque<(Entity, Vec<u8>)>,
+	queued: usize,
+	/// Credit left to the source and target, starts at `RelayConfig::max_queued` and is returned as their data is forwarded.
+	source_credit: usize,
+	target_credit: usize,
+	/// Limited to `RelayConfig::circuit_bandwidth`.
+	limit: RateLimit,
+}
+impl Circuit {
+	fn new(source: Entity, target: Entity, config: &RelayConfig) -> Self {
+		Self {
+			source,
+			target,
+			accepted: false,
+			created: Instant::now(),
+			queue: VecDeque::new(),
+			queued: 0,
+			source_credit: config.max_queued,
+			target_credit: config.max_queued,
+			limit: RateLimit::new(config.circuit_bandwidth),
+		}
+	}
+	fn other(&self, entity: Entity) -> Option<Entity> {
+		if entity == self.source { Some(self.target) } else if entity == self.target { Some(self.source) } else { None }
+	}
+	fn credit_mut(&mut self, entity: Entity) -> Option<&mut usize> {
+		if entity == self.source { Some(&mut self.source_credit) } else if entity == self.target { Some(&mut self.target_credit) } else { None }
+	}
+	// Take the queued data that can be forwarded without exceeding the bandwidth limit of the circuit or of all circuits (`total`)
+	fn drain(&mut self, config: &RelayConfig, total: &mut RateLimit) -> Vec<(Entity, Vec<u8>)> {
+		self.limit.refill(config.circuit_bandwidth);
+		total.refill(config.relay_bandwidth);
+		let mut forward = Vec::new();
+		while let Some((_, data)) = self.queue.front() {
+			if !self.limit.available() || !total.available() { break }
+			self.limit.take(data.len());
+			total.take(data.len());
+			self.queued -= data.len();
+			forward.extend(self.queue.pop_front());
+		}
+		forward
+	}
+	// Packets for forwarding drained data, the sending ends are granted credit for their forwarded data
+	fn forward(&mut self, circuit_id: u64, drained: Vec<(Entity, Vec<u8>)>) -> Vec<(Entity, RoutingSystemPacket)> {
+		let mut credit = HashMap::<Entity, usize>::new();
+		let mut packets = Vec::new();
+		for (to, data) in drained {
+			if let Some(from) = self.other(to) { *credit.entry(from).or_default() += data.len() }
+			packets.push((to, RoutingSystemPacket::RelayPacket { circuit_id, data }));
+		}
+		for (from, bytes) in credit {
+			if let Some(credit) = self.credit_mut(from) { *credit += bytes }
+			packets.push((from, RoutingSystemPacket::RelayCredit { circuit_id, bytes: bytes as u64 }));
+		}
+		packets
+	}
+}
+
+/// Token bucket, the allowance is refilled at a number of bytes per second up to one second's worth.
+struct RateLimit {
+	allowance: f64,
+	last_refill: Instant,
+}
+impl RateLimit {
+	fn new(bandwidth: u64) -> Self {
+		Self { allowance: bandwidth as f64, last_refill: Instant::now() }
+	}
+	fn refill(&mut self, bandwidth: u64) {
+		self.allowance = (self.allowance + self.last_refill.elapsed().as_secs_f64() * bandwidth as f64).min(bandwidth as f64);
+		self.last_refill = Instant::now();
+	}
+	/// Data may be sent while any allowance is left. Sending can take the allowance below zero, so packets larger than one second's worth don't stall, the next packet waits until the debt is refilled.
+	fn available(&self) -> bool {
+		self.allowance > 0.0
+	}
+	fn take(&mut self, bytes: usize) {
+		self.allowance -= bytes as f64;
+	}
+}
+
+/// End of a circuit at this node.
+struct Endpoint {
+	remote_id: NodeID,
+	incoming: UnboundedSender<Vec<u8>>,
+	credit_grants: UnboundedSender<usize>,
+}
+
+#[derive(Resource)]
+struct RelayState {
+	/// Circuits relayed by this node.
+	circuits: HashMap<u64, Circuit>,
+	/// Limits all relayed circuits together to `RelayConfig::relay_bandwidth`.
+	total: RateLimit,
+	/// Circuits ending at this node by relay entity and circuit ID.
+	endpoints: HashMap<(Entity, u64), Endpoint>,
+}
 
 pub struct RoutingSystem<Net: Network> {
 	_net: PhantomData<Net::Address>,
 }
+impl<Net: Network> RoutingSystem<Net> {
+	/// Connect to `remote_id` through a peer both nodes have a session with, peers are asked one at a time until a circuit is accepted.
+	pub fn connect_relayed(world: &mut World, remote_id: NodeID) {
+		let entity = match world.resource::<RemoteIDMap>().map.get(&remote_id) {
+			Some(entity) => *entity,
+			None => {
+				let entity = world.spawn((Remote { id: remote_id.clone() }, Disconnected::now())).id();
+				world.resource_mut::<RemoteIDMap>().map.insert(remote_id.clone(), entity);
+				entity
+			}
+		};
+		if world.get::<Session<Net>>(entity).is_some() {
+			log::info!("relay: already connected to {remote_id:?}");
+			return;
+		}
+		if world.get::<RelayRequest>(entity).is_some() { return }
+
+		let max_attempts = world.resource::<RelayConfig>().max_attempts;
+		let mut query = world.query_filtered::<Entity, With<Session<Net>>>();
+		let mut candidates = query.iter(world).filter(|peer|*peer != entity).collect::<Vec<Entity>>();
+		candidates.shuffle(&mut rand::thread_rng());
+		candidates.truncate(max_attempts);
+		log::debug!("relay: connecting to {remote_id:?} through up to {} peers", candidates.len());
+		world.entity_mut(entity).insert(RelayRequest { circuit_id: 0, candidates, asked: None });
+		ask_next_relay::<Net>(world, entity);
+	}
+	/// Whether the session with `entity` carries circuits relayed by this node or relayed sessions of this node.
+	pub fn carries_circuits(world: &World, entity: Entity) -> bool {
+		let Some(state) = world.get_resource::<RelayState>() else { return false };
+		state.circuits.values().any(|circuit|circuit.source == entity || circuit.target == entity)
+			|| state.endpoints.keys().any(|(relay, _)|*relay == entity)
+	}
+}
+
 impl<Net: Network> NodeSystem for RoutingSystem<Net> {
-    fn register_resources(world: &mut World) {
-		
-    }
+	fn register_resources(world: &mut World) {
+		let config = world.resource::<NodeConfig<Net>>().relay.clone();
+		world.insert_resource(RelayState { circuits: HashMap::new(), total: RateLimit::new(config.relay_bandwidth), endpoints: HashMap::new() });
+		world.insert_resource(config);
+	}
+
+	// Mark sessions that run over a circuit, stop relay requests once connected
+	fn on_session_open(world: &mut World, entity: Entity) {
+		let remote_id = world.get::<Remote>(entity).unwrap().id.clone();
+		if world.entity_mut(entity).take::<RelayRequest>().is_some() {
+			log::info!("relay: connected to {remote_id:?}");
+		}
+		let relay = world.resource::<RelayState>().endpoints.iter()
+			.find(|(_, endpoint)|endpoint.remote_id == remote_id && !endpoint.incoming.is_closed())
+			.map(|((relay, circuit_id), _)|Relay { relay: *relay, circuit_id: *circuit_id });
+		if let Some(relay) = relay {
+			world.entity_mut(entity).insert(relay);
+		}
+	}
+
+	// Close circuits through the remote and circuits it relays for us
+	fn on_session_closed(world: &mut World, entity: Entity, _reason: &SessionCloseReason) {
+		world.entity_mut(entity).remove::<Relay>();
+		let mut state = world.resource_mut::<RelayState>();
+		state.endpoints.retain(|(relay, _), _|*relay != entity);
+		let mut closed = Vec::new();
+		state.circuits.retain(|circuit_id, circuit| match circuit.other(entity) {
+			Some(other) => { closed.push((other, RoutingSystemPacket::RelayClosed { circuit_id: *circuit_id })); false }
+			None => true,
+		});
+		for (other, packet) in closed {
+			send::<Net>(world, other, packet);
+		}
+	}
+
+	fn on_tick(world: &mut World) {
+		let config = world.resource::<RelayConfig>().clone();
+
+		// Ask the next peer if the current one didn't relay in time
+		let timed_out = world.query::<(Entity, &RelayRequest)>().iter(world)
+			.filter(|(_, request)|request.asked.map_or(false, |(_, asked)|asked.elapsed() >= config.request_timeout))
+			.map(|(entity, _)|entity)
+			.collect::<Vec<Entity>>();
+		for entity in timed_out {
+			ask_next_relay::<Net>(world, entity);
+		}
+
+		let mut state = world.resource_mut::<RelayState>();
+		// Forget endpoints whose stream was dropped
+		state.endpoints.retain(|_, endpoint|!endpoint.incoming.is_closed());
+		// Close circuits that weren't accepted in time, forward queued data
+		let mut expired = Vec::new();
+		state.circuits.retain(|circuit_id, circuit| {
+			let expired_circuit = !circuit.accepted && circuit.created.elapsed() >= config.request_timeout;
+			if expired_circuit { expired.push((circuit.source, RoutingSystemPacket::RelayClosed { circuit_id: *circuit_id })) }
+			!expired_circuit
+		});
+		let RelayState { circuits, total, .. } = &mut *state;
+		let forward = circuits.iter_mut()
+			.flat_map(|(circuit_id, circuit)| {
+				let drained = circuit.drain(&config, total);
+				circuit.forward(*circuit_id, drained)
+			})
+			.collect::<Vec<(Entity, RoutingSystemPacket)>>();
+		for (entity, packet) in expired.into_iter().chain(forward) {
+			send::<Net>(world, entity, packet);
+		}
+	}
+
+	type Packet = RoutingSystemPacket;
 
-    fn register_systems(schedule: &mut Schedule) {
-		
+	fn handle_packet(world: &mut World, entity: Entity, packet: Self::Packet) {
+		let Some(remote_id) = world.get::<Remote>(entity).map(|remote|remote.id.clone()) else { return };
+		match packet {
+			// Relay circuit to target if relaying is enabled
+			RoutingSystemPacket::RequestRelay { circuit_id, target } => {
+				let config = world.resource::<RelayConfig>().clone();
+				let target_entity = world.resource::<RemoteIDMap>().map.get(&target).cloned()
+					.filter(|target|*target != entity && world.get::<Session<Net>>(*target).is_some());
+				let state = world.resource::<RelayState>();
+				let target_entity = match target_entity {
+					Some(target_entity) if config.enabled && state.circuits.len() < config.max_circuits && !state.circuits.contains_key(&circuit_id) => target_entity,
+					_ => {
+						log::debug!("relay: refusing circuit {circuit_id} from {remote_id:?} to {target:?}");
+						return send::<Net>(world, entity, RoutingSystemPacket::RelayClosed { circuit_id });
+					}
+				};
+				log::debug!("relay: relaying circuit {circuit_id} from {remote_id:?} to {target:?}");
+				world.resource_mut::<RelayState>().circuits.insert(circuit_id, Circuit::new(entity, target_entity, &config));
+				send::<Net>(world, target_entity, RoutingSystemPacket::RelayIncoming { circuit_id, source: remote_id });
+			}
+			// Accept circuit unless already connected to the source
+			RoutingSystemPacket::RelayIncoming { circuit_id, source } => {
+				let config = world.resource::<NodeConfig<Net>>();
+				let max_sessions = config.max_sessions;
+				let connected = source == config.node_id || world.resource::<RemoteIDMap>().map.get(&source).map_or(false, |entity|world.get::<Session<Net>>(*entity).is_some());
+				if connected || session_count::<Net>(world) >= max_sessions {
+					return send::<Net>(world, entity, RoutingSystemPacket::RelayClosed { circuit_id });
+				}
+				log::debug!("relay: accepting circuit {circuit_id} from {source:?} through {remote_id:?}");
+				send::<Net>(world, entity, RoutingSystemPacket::RelayAccepted { circuit_id });
+				open_endpoint::<Net>(world, entity, circuit_id, source, false);
+			}
+			RoutingSystemPacket::RelayAccepted { circuit_id } => {
+				// Forward acceptance to the source of a circuit we relay, grant both ends their initial credit
+				let mut state = world.resource_mut::<RelayState>();
+				if let Some(circuit) = state.circuits.get_mut(&circuit_id).filter(|circuit|circuit.target == entity && !circuit.accepted) {
+					circuit.accepted = true;
+					let (source, target) = (circuit.source, circuit.target);
+					let (source_credit, target_credit) = (circuit.source_credit as u64, circuit.target_credit as u64);
+					send::<Net>(world, source, RoutingSystemPacket::RelayAccepted { circuit_id });
+					send::<Net>(world, source, RoutingSystemPacket::RelayCredit { circuit_id, bytes: source_credit });
+					return send::<Net>(world, target, RoutingSystemPacket::RelayCredit { circuit_id, bytes: target_credit });
+				}
+				// Circuit requested by us was accepted
+				let requester = world.query::<(Entity, &Remote, &RelayRequest)>().iter(world)
+					.find(|(_, _, request)|request.circuit_id == circuit_id && request.asked.map_or(false, |(relay, _)|relay == entity))
+					.map(|(_, remote, _)|remote.id.clone());
+				match requester {
+					Some(target) => open_endpoint::<Net>(world, entity, circuit_id, target, true),
+					None => send::<Net>(world, entity, RoutingSystemPacket::RelayClosed { circuit_id }),
+				}
+			}
+			RoutingSystemPacket::RelayClosed { circuit_id } => {
+				let mut state = world.resource_mut::<RelayState>();
+				if state.endpoints.remove(&(entity, circuit_id)).is_some() {
+					log::debug!("relay: circuit {circuit_id} through {remote_id:?} closed");
+					return;
+				}
+				if let Some(other) = state.circuits.get(&circuit_id).and_then(|circuit|circuit.other(entity)) {
+					state.circuits.remove(&circuit_id);
+					return send::<Net>(world, other, RoutingSystemPacket::RelayClosed { circuit_id });
+				}
+				// Relay refused our circuit
+				let refused = world.query::<(Entity, &RelayRequest)>().iter(world)
+					.find(|(_, request)|request.circuit_id == circuit_id && request.asked.map_or(false, |(relay, _)|relay == entity))
+					.map(|(requester, _)|requester);
+				if let Some(requester) = refused {
+					log::debug!("relay: {remote_id:?} refused circuit {circuit_id}");
+					ask_next_relay::<Net>(world, requester);
+				}
+			}
+			RoutingSystemPacket::RelayPacket { circuit_id, data } => {
+				let config = world.resource::<RelayConfig>().clone();
+				let mut state = world.resource_mut::<RelayState>();
+				if let Some(endpoint) = state.endpoints.get(&(entity, circuit_id)) {
+					let _ = endpoint.incoming.unbounded_send(data);
+					return;
+				}
+				let Some(circuit) = state.circuits.get_mut(&circuit_id).filter(|circuit|circuit.accepted) else { return };
+				let (Some(other), Some(credit)) = (circuit.other(entity), circuit.credit_mut(entity).map(|credit|*credit)) else { return };
+				// Ends only send data they were granted credit for
+				if data.len() > credit {
+					log::warn!("relay: closing circuit {circuit_id}, {remote_id:?} sent more than its credit");
+					let circuit = state.circuits.remove(&circuit_id).unwrap();
+					send::<Net>(world, circuit.source, RoutingSystemPacket::RelayClosed { circuit_id });
+					return send::<Net>(world, circuit.target, RoutingSystemPacket::RelayClosed { circuit_id });
+				}
+				*circuit.credit_mut(entity).unwrap() -= data.len();
+				circuit.queued += data.len();
+				circuit.queue.push_back((other, data));
+				let RelayState { circuits, total, .. } = &mut *state;
+				let Some(circuit) = circuits.get_mut(&circuit_id) else { return };
+				let drained = circuit.drain(&config, total);
+				for (to, packet) in circuit.forward(circuit_id, drained) {
+					send::<Net>(world, to, packet);
+				}
+			}
+			RoutingSystemPacket::RelayCredit { circuit_id, bytes } => {
+				if let Some(endpoint) = world.resource::<RelayState>().endpoints.get(&(entity, circuit_id)) {
+					let _ = endpoint.credit_grants.unbounded_send(bytes as usize);
+				}
+			}
+		}
 	}
+}
 
-	fn register_components(entity_mut: &mut bevy_ecs::world::EntityMut) {
-		
+fn send<Net: Network>(world: &World, entity: Entity, packet: RoutingSystemPacket) {
+	if let Some(session) = world.get::<Session<Net>>(entity) {
+		session.send_packet(packet.into());
 	}
 }
 
+// Ask the next candidate to relay a circuit to the remote, gives up once all candidates were asked
+fn ask_next_relay<Net: Network>(world: &mut World, entity: Entity) {
+	let Some(mut request) = world.entity_mut(entity).take::<RelayRequest>() else { return };
+	let remote_id = world.get::<Remote>(entity).unwrap().id.clone();
+	let candidates = std::mem::take(&mut request.candidates);
+	let mut candidates = candidates.into_iter();
+	let Some(relay) = candidates.by_ref().find(|peer|world.get::<Session<Net>>(*peer).is_some()) else {
+		log::warn!("relay: failed to connect to {remote_id:?}, no more peers to relay");
+		return;
+	};
+	request.candidates = candidates.collect();
+	request.circuit_id = rand::random();
+	request.asked = Some((relay, Instant::now()));
+	send::<Net>(world, relay, RoutingSystemPacket::RequestRelay { circuit_id: request.circuit_id, target: remote_id });
+	world.entity_mut(entity).insert(request);
+}
+
+// Create this node's end of a circuit and hand it to the network, which passes back a `Connection` once the handshake is done
+fn open_endpoint<Net: Network>(world: &mut World, relay: Entity, circuit_id: u64, remote_id: NodeID, requested: bool) {
+	let Some(session) = world.get::<Session<Net>>(relay) else { return };
+	let (incoming, receiver) = unbounded();
+	let (credit_grants, credit_receiver) = unbounded();
+	let stream = RelayStream { circuit_id, relay: session.action_sender.clone(), incoming: receiver, buffer: Vec::new(), credit_grants: credit_receiver, credit: 0, closed: false };
+	let relay_address = world.get::<SessionInfo<Net>>(relay).unwrap().net_address.clone();
+	world.resource_mut::<RelayState>().endpoints.insert((relay, circuit_id), Endpoint { remote_id: remote_id.clone(), incoming, credit_grants });
+	world.resource::<Net>().relayed(remote_id, relay_address, stream, requested);
+}
+
 /// Request to establish traversed encrypted session with remote entity.
 /// Traversed packets get passed along to peers closest to the remote.
 /// Requires entity to have `Remote` and `Coordinates` components
@@ -84,4 +570,77 @@ fn establish_traversal_session(mut commands: Commands, requests: Query<(Entity,
 #[derive(Resource)]
 pub struct TraversalPacketReceiver {
 	receiver: mpsc::Receiver<TraversalPacket>
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use std::{pin::Pin, task::{Context, Poll}};
+
+	use bevy_ecs::prelude::*;
+	use futures::{AsyncWrite, channel::mpsc::unbounded, task::noop_waker};
+
+	use crate::{session::SessionAction, test_net::TestNet};
+	use super::{Circuit, RateLimit, RelayConfig, RelayStream, RoutingSystemPacket, MAX_RELAY_PACKET};
+
+	// Packets larger than a second's worth of bandwidth are still forwarded, the next packet waits
+	#[test]
+	fn drain_large_packets() {
+		let config = RelayConfig { circuit_bandwidth: 1024, relay_bandwidth: 1024 * 1024, ..Default::default() };
+		let (source, target) = (Entity::from_raw(0), Entity::from_raw(1));
+		let mut circuit = Circuit::new(source, target, &config);
+		let mut total = RateLimit::new(config.relay_bandwidth);
+		for _ in 0..2 {
+			circuit.queue.push_back((target, vec![0; MAX_RELAY_PACKET]));
+			circuit.queued += MAX_RELAY_PACKET;
+		}
+		assert_eq!(circuit.drain(&config, &mut total).len(), 1);
+		assert_eq!(circuit.drain(&config, &mut total).len(), 0);
+		assert_eq!(circuit.queued, MAX_RELAY_PACKET);
+
+		// Bandwidth over all circuits is limited too
+		let config = RelayConfig { relay_bandwidth: 1, ..config };
+		let mut circuit = Circuit { limit: RateLimit::new(config.circuit_bandwidth), ..circuit };
+		let mut total = RateLimit::new(config.relay_bandwidth);
+		total.take(MAX_RELAY_PACKET);
+		assert_eq!(circuit.drain(&config, &mut total).len(), 0);
+	}
+
+	// Forwarded data is credited back to the end that sent it
+	#[test]
+	fn credit_forwarded_data() {
+		let config = RelayConfig { max_queued: 1024, ..Default::default() };
+		let (source, target) = (Entity::from_raw(0), Entity::from_raw(1));
+		let mut circuit = Circuit::new(source, target, &config);
+		circuit.source_credit -= 100;
+		circuit.queue.push_back((target, vec![0; 100]));
+		circuit.queued += 100;
+		let drained = circuit.drain(&config, &mut RateLimit::new(config.relay_bandwidth));
+		let packets = circuit.forward(7, drained);
+		assert!(matches!(packets.as_slice(), [
+			(to, RoutingSystemPacket::RelayPacket { circuit_id: 7, data }),
+			(from, RoutingSystemPacket::RelayCredit { circuit_id: 7, bytes: 100 }),
+		] if *to == target && data.len() == 100 && *from == source));
+		assert_eq!(circuit.source_credit, 1024);
+	}
+
+	// Writes wait for credit and send no more than was granted
+	#[test]
+	fn write_waits_for_credit() {
+		let (relay, mut sent) = unbounded::<SessionAction<TestNet>>();
+		let (_incoming, incoming_receiver) = unbounded();
+		let (credit_grants, credit_receiver) = unbounded();
+		let mut stream = RelayStream::<TestNet> { circuit_id: 7, relay, incoming: incoming_receiver, buffer: Vec::new(), credit_grants: credit_receiver, credit: 0, closed: false };
+		let waker = noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(Pin::new(&mut stream).poll_write(&mut cx, &[0; 100]).is_pending());
+		assert!(sent.try_next().is_err());
+
+		credit_grants.unbounded_send(60).unwrap();
+		assert!(matches!(Pin::new(&mut stream).poll_write(&mut cx, &[0; 100]), Poll::Ready(Ok(60))));
+		assert!(Pin::new(&mut stream).poll_write(&mut cx, &[0; 40]).is_pending());
+
+		// No more credit once the circuit is closed
+		drop(credit_grants);
+		assert!(matches!(Pin::new(&mut stream).poll_write(&mut cx, &[0; 40]), Poll::Ready(Err(_))));
+	}
+}
@@ -0,0 +1,140 @@
+//! In-memory `Network` for unit tests. It doesn't connect anywhere, requests made by systems are recorded so tests can check them.
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use bevy_ecs::prelude::*;
+use futures::{channel::{oneshot, mpsc::{unbounded, UnboundedReceiver}}, io, stream::FusedStream, Stream};
+
+use crate::{Network, NodeID, EncryptionKeys, Connection, RelayStream, SignedPeerRecord, NodeConfig, NodeBuilder, NodeSystem, NodeEvent, NodePacket, Remote, RemoteIDMap, session::{Session, SessionInfo, SessionAction}};
+
+/// Request made to `TestNet` by a system.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetRequest {
+	Connect(NodeID, SocketAddr),
+	Punch(NodeID, SocketAddr, Duration),
+	Probe(NodeID, SocketAddr),
+}
+
+#[derive(Debug, Clone, Default, Resource)]
+pub struct TestNet {
+	pub requests: Arc<Mutex<Vec<NetRequest>>>,
+}
+impl TestNet {
+	/// Remove and return the requests made so far.
+	pub fn take_requests(&self) -> Vec<NetRequest> {
+		std::mem::take(&mut *self.requests.lock().unwrap())
+	}
+}
+
+#[derive(Debug, Clone, Default, Resource)]
+pub struct TestListenerConfig;
+
+impl Network for TestNet {
+	type Address = SocketAddr;
+	type ArchivedAddress = <SocketAddr as rkyv::Archive>::Archived;
+	type NodePubKey = Vec<u8>;
+	type NodePrivKey = Vec<u8>;
+	type PersistentState = ();
+	type Read = io::Empty;
+	type Write = io::Sink;
+	type ConnectionError = std::io::Error;
+	type ListenerConfig = TestListenerConfig;
+
+	async fn init(_keys: EncryptionKeys<Self>, _listener_config: &TestListenerConfig) -> Result<(Self, impl Stream<Item = Result<Connection<Self>, Self::ConnectionError>> + Unpin + FusedStream), Self::ConnectionError> {
+		Ok((Self::default(), futures::stream::empty()))
+	}
+	fn connect(&self, remote_id: NodeID, net_address: SocketAddr, _remote_pub_key: Option<Vec<u8>>, _persistent_state: Option<()>) {
+		self.requests.lock().unwrap().push(NetRequest::Connect(remote_id, net_address));
+	}
+	fn punch(&self, remote_id: NodeID, net_address: SocketAddr, delay: Duration) {
+		self.requests.lock().unwrap().push(NetRequest::Punch(remote_id, net_address, delay));
+	}
+	fn relayed(&self, _remote_id: NodeID, _relay_address: SocketAddr, _stream: RelayStream<Self>, _requested: bool) {}
+	fn listen(&self, _addrs: impl Iterator<Item = SocketAddr>) {}
+	fn shutdown(&self) {}
+	// The reply is dropped, so the probe reads as failed
+	fn probe(&self, remote_id: NodeID, net_address: SocketAddr) -> oneshot::Receiver<bool> {
+		self.requests.lock().unwrap().push(NetRequest::Probe(remote_id, net_address));
+		oneshot::channel().1
+	}
+	fn same_host(a: &SocketAddr, b: &SocketAddr) -> bool {
+		a.ip() == b.ip()
+	}
+	fn predict_public_addresses<'a>(addr: &'a SocketAddr, _config: &'a TestListenerConfig) -> impl Iterator<Item = SocketAddr> + 'a {
+		std::iter::once(*addr)
+	}
+	// Not a real signature, anyone can compute it from the public key
+	fn sign(keys: &EncryptionKeys<Self>, data: &[u8]) -> Vec<u8> {
+		NodeID::hash(&[keys.public_key.as_slice(), data].concat()).as_bytes().to_vec()
+	}
+	fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+		NodeID::hash(&[public_key, data].concat()).as_bytes() == signature
+	}
+}
+
+pub fn keys(seed: u64) -> EncryptionKeys<TestNet> {
+	EncryptionKeys { private_key: seed.to_le_bytes().to_vec(), public_key: seed.to_le_bytes().to_vec() }
+}
+
+pub fn node_id(seed: u64) -> NodeID {
+	NodeID::hash(&keys(seed).public_key)
+}
+
+pub fn addr(port: u16) -> SocketAddr {
+	SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// Signed record of the test node with the given seed, reachable at `127.0.0.1:<port>`.
+pub fn record(seed: u64, port: u16) -> SignedPeerRecord<TestNet> {
+	SignedPeerRecord::new(&keys(seed), node_id(seed), vec![addr(port)])
+}
+
+/// Config of the test node with the given seed, every system uses its default config.
+pub fn node_config(seed: u64) -> NodeConfig<TestNet> {
+	NodeConfig {
+		keys: keys(seed),
+		node_id: node_id(seed),
+		listener_config: TestListenerConfig,
+		remote_timeout: crate::DEFAULT_REMOTE_TIMEOUT,
+		tick_interval: crate::DEFAULT_TICK_INTERVAL,
+		max_sessions: crate::DEFAULT_MAX_SESSIONS,
+		session: Default::default(),
+		latency_metrics: Default::default(),
+		nc: Default::default(),
+		bootstrap: Default::default(),
+		address_book: Default::default(),
+		discovery: Default::default(),
+		dht: Default::default(),
+		hole_punch: Default::default(),
+		relay: Default::default(),
+		gossip: Default::default(),
+	}
+}
+
+/// World of a node that only registered `S`, with a `TestNet` in place of a running network. Events sent by systems are returned by the receiver.
+pub fn world<S: NodeSystem + 'static>(config: NodeConfig<TestNet>) -> (World, UnboundedReceiver<NodeEvent<TestNet>>) {
+	let (sender, events) = unbounded();
+	let mut world = NodeBuilder::new(config, sender).with_system::<S>().world;
+	world.insert_resource(TestNet::default());
+	world.insert_resource(TestListenerConfig);
+	(world, events)
+}
+
+/// Add a remote with a session to the test node at `127.0.0.1:<port>`, packets sent over the session are returned by the receiver.
+/// `NodeSystem` hooks aren't run for the new session.
+pub fn open_session(world: &mut World, seed: u64, port: u16) -> (Entity, UnboundedReceiver<SessionAction<TestNet>>) {
+	let (action_sender, actions) = unbounded();
+	let info = SessionInfo::<TestNet> { net_address: addr(port), local_address: None, remote_pub_key: Some(keys(seed).public_key), persistent_state: None };
+	let entity = world.spawn((Remote { id: node_id(seed) }, Session { action_sender }, info)).id();
+	world.resource_mut::<RemoteIDMap>().map.insert(node_id(seed), entity);
+	(entity, actions)
+}
+
+/// Packets sent over a test session since the last call.
+pub fn sent_packets(actions: &mut UnboundedReceiver<SessionAction<TestNet>>) -> Vec<NodePacket<TestNet>> {
+	let mut packets = Vec::new();
+	while let Ok(Some(action)) = actions.try_next() {
+		if let SessionAction::Packet(packet) = action { packets.push(packet) }
+	}
+	packets
+}
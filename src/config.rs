@@ -0,0 +1,201 @@
+//! Configuration file shared by the `libdither` and `sim_bin` binaries.
+
+use std::{net::{SocketAddr, Ipv4Addr}, path::{PathBuf, Path}, time::Duration};
+
+use anyhow::{anyhow, Context};
+use serde::{Serialize, Deserialize};
+
+use node::{NodeID, NodeConfig, session::SessionConfig, LatencyMetricsConfig, NCConfig, BootstrapConfig, AddressBookConfig, DiscoveryConfig, DhtConfig, HolePunchConfig, RelayConfig, GossipConfig, DEFAULT_REMOTE_TIMEOUT, DEFAULT_TICK_INTERVAL, DEFAULT_MAX_SESSIONS};
+
+use crate::{net_tcp_noenc::{TcpNoenc, ListenerConfig}, lan_discovery::LanDiscoveryConfig};
+
+/// Node configuration, read from the JSON file passed with `--config <path>`. Missing fields are set to their defaults.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+	/// Addresses to listen for connections on.
+	pub listen_addrs: Vec<SocketAddr>,
+	/// File containing the seed of the node's ed25519 private key, a new key is generated if it doesn't exist. It must only be accessible by the current user (mode 0600 on unix).
+	/// If not set, the key is derived from the first listen address (so that NodeIDs in simulations are predictable).
+	pub identity_path: Option<PathBuf>,
+	/// Seed nodes to connect to on startup.
+	pub bootstrap: BootstrapConfig<TcpNoenc>,
+	/// Where known peers are remembered across restarts.
+	pub address_book: AddressBookConfig,
+	pub discovery: DiscoveryConfig,
+	pub dht: DhtConfig,
+	pub hole_punch: HolePunchConfig,
+	/// Relay connections for other nodes, disabled by default.
+	pub relay: RelayConfig,
+	pub gossip: GossipConfig,
+	/// Find nodes on the local network through UDP multicast.
+	pub lan_discovery: LanDiscoveryConfig,
+	/// Maximum number of active sessions.
+	pub max_sessions: usize,
+	/// How long to remember a remote that has no session.
+	pub remote_timeout: Duration,
+	/// Interval of the node timer.
+	pub tick_interval: Duration,
+	pub session: SessionConfig,
+	pub latency_metrics: LatencyMetricsConfig,
+	pub network_coordinates: NCConfig,
+}
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			listen_addrs: Vec::new(),
+			identity_path: None,
+			bootstrap: Default::default(),
+			address_book: Default::default(),
+			discovery: Default::default(),
+			dht: Default::default(),
+			hole_punch: Default::default(),
+			relay: Default::default(),
+			gossip: Default::default(),
+			lan_discovery: Default::default(),
+			max_sessions: DEFAULT_MAX_SESSIONS,
+			remote_timeout: DEFAULT_REMOTE_TIMEOUT,
+			tick_interval: DEFAULT_TICK_INTERVAL,
+			session: Default::default(),
+			latency_metrics: Default::default(),
+			network_coordinates: Default::default(),
+		}
+	}
+}
+
+impl Config {
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let file = std::fs::File::open(path).with_context(|| format!("failed to open config file {path:?}"))?;
+		serde_json::from_reader(file).with_context(|| format!("failed to parse config file {path:?}"))
+	}
+	/// Loads config from the path passed with `--config` (or the default config if not passed), returns it along with the remaining command line arguments.
+	pub fn from_args() -> anyhow::Result<(Self, Vec<String>)> {
+		let mut args = std::env::args().skip(1);
+		let mut config = None;
+		let mut positional = Vec::new();
+		while let Some(arg) = args.next() {
+			if arg == "--config" {
+				let path = args.next().ok_or(anyhow!("--config requires a path"))?;
+				config = Some(Self::load(path.as_ref())?);
+			} else {
+				positional.push(arg);
+			}
+		}
+		Ok((config.unwrap_or_default(), positional))
+	}
+	/// Listen on all interfaces on a given port instead of the configured addresses.
+	pub fn set_port(&mut self, port: u16) {
+		self.listen_addrs = vec![SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), port)];
+	}
+	// Seed of the node's ed25519 key, generated and saved at `identity_path` if it doesn't exist yet
+	fn key_seed(&self) -> anyhow::Result<[u8; 32]> {
+		match &self.identity_path {
+			Some(path) if path.exists() => {
+				check_identity_permissions(path)?;
+				let seed = std::fs::read(path).with_context(|| format!("failed to read identity file {path:?}"))?;
+				<[u8; 32]>::try_from(seed.as_slice()).map_err(|_|anyhow!("identity file {path:?} is not a 32 byte ed25519 seed"))
+			}
+			Some(path) => {
+				let seed = rand::random::<[u8; 32]>();
+				write_identity(path, &seed).with_context(|| format!("failed to write identity file {path:?}"))?;
+				Ok(seed)
+			}
+			// Without an identity file, derive the key from the listen address so that the NodeID is stable between runs (for testing).
+			// The seed is the SHA-256 digest of the address, the last 32 bytes of the hash.
+			None => {
+				let listen_addr = self.listen_addrs.first().ok_or(anyhow!("no listen address configured"))?.to_string();
+				let hash = NodeID::hash(listen_addr.as_bytes());
+				let digest = hash.as_bytes().len().checked_sub(32).map(|start|&hash.as_bytes()[start..]).ok_or(anyhow!("NodeID hash is shorter than an ed25519 seed"))?;
+				Ok(<[u8; 32]>::try_from(digest)?)
+			}
+		}
+	}
+	pub fn node_config(&self) -> anyhow::Result<NodeConfig<TcpNoenc>> {
+		if self.listen_addrs.is_empty() {
+			return Err(anyhow!("no listen address configured, pass a port or set listen_addrs in the config file"));
+		}
+		let gossip = &self.gossip;
+		if gossip.mesh_low > gossip.mesh_degree || gossip.mesh_degree > gossip.mesh_high {
+			return Err(anyhow!("gossip mesh sizes must satisfy mesh_low <= mesh_degree <= mesh_high"));
+		}
+		let keys = TcpNoenc::keys_from_seed(self.key_seed()?);
+		Ok(NodeConfig {
+			node_id: NodeID::hash(&keys.public_key),
+			keys,
+			listener_config: ListenerConfig::new(self.listen_addrs.clone()),
+			remote_timeout: self.remote_timeout,
+			tick_interval: self.tick_interval,
+			max_sessions: self.max_sessions,
+			session: self.session.clone(),
+			latency_metrics: self.latency_metrics.clone(),
+			nc: self.network_coordinates.clone(),
+			bootstrap: self.bootstrap.clone(),
+			address_book: self.address_book.clone(),
+			discovery: self.discovery.clone(),
+			dht: self.dht.clone(),
+			hole_punch: self.hole_punch.clone(),
+			relay: self.relay.clone(),
+			gossip: self.gossip.clone(),
+		})
+	}
+}
+
+// Create the identity file so that only the current user can read it, it contains the node's private key
+#[cfg(unix)]
+fn write_identity(path: &Path, seed: &[u8; 32]) -> std::io::Result<()> {
+	use std::{io::Write, os::unix::fs::OpenOptionsExt};
+	std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(seed)
+}
+#[cfg(not(unix))]
+fn write_identity(path: &Path, seed: &[u8; 32]) -> std::io::Result<()> {
+	std::fs::write(path, seed)
+}
+
+// Refuse identity files that other users can access, like ssh does for private keys
+#[cfg(unix)]
+fn check_identity_permissions(path: &Path) -> anyhow::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	let mode = std::fs::metadata(path).with_context(|| format!("failed to read identity file {path:?}"))?.permissions().mode();
+	if mode & 0o077 != 0 {
+		return Err(anyhow!("identity file {path:?} is accessible by other users (mode {:o}), restrict it with `chmod 600`", mode & 0o777));
+	}
+	Ok(())
+}
+#[cfg(not(unix))]
+fn check_identity_permissions(_path: &Path) -> anyhow::Result<()> {
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The scenarios in `tests/` connect to simulated nodes by these NodeIDs, they must be updated if the derivation changes
+	#[test]
+	fn derived_node_id() {
+		let mut config = Config::default();
+		config.set_port(8080);
+		assert_eq!(config.node_config().unwrap().node_id, "QmNyAZ44YwTLgAq3vJJ36mhGp37sgDFanyYXsxeokqVCFg".parse::<NodeID>().unwrap());
+		config.set_port(8081);
+		assert_eq!(config.node_config().unwrap().node_id, "QmTTspT1MEoxTec4UuCUV6fCNNxg6tvXY9tPAdPNQcUEH6".parse::<NodeID>().unwrap());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn identity_permissions() {
+		use std::os::unix::fs::PermissionsExt;
+		let path = std::env::temp_dir().join(format!("libdither-identity-{}", rand::random::<u64>()));
+		let mut config = Config::default();
+		config.set_port(8080);
+		config.identity_path = Some(path.clone());
+
+		// A new identity is only readable by the current user and is loaded again
+		let node_id = config.node_config().unwrap().node_id;
+		assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		assert_eq!(config.node_config().unwrap().node_id, node_id);
+
+		std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+		assert!(config.node_config().is_err());
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,166 @@
+//! Finds nodes on the local network by periodically announcing this node's NodeID and listen address on a UDP multicast group and connecting to the nodes heard there.
+//! Nodes heard are passed to the node as `NodeAction::AddPeer`, so they are only connected to while the node wants more peers.
+
+use std::{collections::HashMap, net::{SocketAddr, SocketAddrV4, Ipv4Addr}, time::{Duration, Instant}};
+
+use async_std::net::UdpSocket;
+use futures::{StreamExt, FutureExt, channel::mpsc::UnboundedSender};
+use serde::{Serialize, Deserialize};
+use socket2::{Socket, Domain, Type, Protocol};
+
+use node::{NodeID, NodeAction};
+
+use crate::net_tcp_noenc::TcpNoenc;
+
+/// Configures LAN discovery, disabled by default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LanDiscoveryConfig {
+	pub enabled: bool,
+	/// Announcements with a different network ID are ignored, keeps separate networks on the same LAN apart.
+	pub network_id: String,
+	/// Multicast group and port announcements are sent to.
+	pub multicast_addr: SocketAddrV4,
+	/// Interface to join the multicast group on, `0.0.0.0` lets the OS choose. Set to `127.0.0.1` to only find nodes on the same machine.
+	pub interface: Ipv4Addr,
+	/// How often this node announces itself.
+	pub announce_interval: Duration,
+	/// Minimum time between connection attempts to the same node.
+	pub reconnect_interval: Duration,
+	/// Maximum number of announcements handled per `announce_interval`, the rest are dropped.
+	pub max_announcements: usize,
+}
+impl Default for LanDiscoveryConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			network_id: "dither".to_owned(),
+			multicast_addr: SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), 7477),
+			interface: Ipv4Addr::UNSPECIFIED,
+			announce_interval: Duration::from_secs(5),
+			reconnect_interval: Duration::from_secs(60),
+			max_announcements: 32,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+	network_id: String,
+	node_id: NodeID,
+	/// Address the node listens on, an unspecified IP is replaced with the address the announcement came from.
+	addr: SocketAddr,
+}
+
+// Bind to the multicast port, several nodes on the same machine can share it
+fn bind(config: &LanDiscoveryConfig) -> std::io::Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_reuse_address(true)?;
+	socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.multicast_addr.port()).into())?;
+	socket.join_multicast_v4(config.multicast_addr.ip(), &config.interface)?;
+	socket.set_multicast_if_v4(&config.interface)?;
+	socket.set_multicast_loop_v4(true)?;
+	socket.set_nonblocking(true)?;
+	Ok(UdpSocket::from(std::net::UdpSocket::from(socket)))
+}
+
+async fn receive(socket: &UdpSocket) -> std::io::Result<(Vec<u8>, SocketAddr)> {
+	let mut buf = vec![0u8; 1024];
+	let (len, from) = socket.recv_from(&mut buf).await?;
+	buf.truncate(len);
+	Ok((buf, from))
+}
+
+/// Run LAN discovery on its own task, errors are logged.
+pub fn spawn(config: LanDiscoveryConfig, node_id: NodeID, listen_addr: SocketAddr, actions: UnboundedSender<NodeAction<TcpNoenc>>) {
+	async_std::task::spawn(async move {
+		if let Err(err) = run(config, node_id, listen_addr, actions).await {
+			log::error!("lan discovery: {err}");
+		}
+	});
+}
+
+/// Announce this node and send `NodeAction::AddPeer` for every new node heard on the multicast group. Returns once the node stops receiving actions.
+pub async fn run(config: LanDiscoveryConfig, node_id: NodeID, listen_addr: SocketAddr, actions: UnboundedSender<NodeAction<TcpNoenc>>) -> anyhow::Result<()> {
+	let socket = bind(&config)?;
+	let announcement = serde_json::to_vec(&Announcement { network_id: config.network_id.clone(), node_id: node_id.clone(), addr: listen_addr })?;
+	log::info!("lan discovery: announcing on {} as network {:?}", config.multicast_addr, config.network_id);
+
+	let mut announce_timer = async_std::stream::interval(config.announce_interval).fuse();
+	let mut last_connect = HashMap::<NodeID, Instant>::new();
+	let mut handled = 0;
+	loop {
+		futures::select! {
+			_ = announce_timer.next() => {
+				if let Err(err) = socket.send_to(&announcement, config.multicast_addr).await {
+					log::warn!("lan discovery: failed to announce: {err}");
+				}
+				handled = 0;
+				last_connect.retain(|_, at|at.elapsed() < config.reconnect_interval);
+			}
+			received = receive(&socket).fuse() => {
+				let (data, from) = match received {
+					Ok(received) => received,
+					Err(err) => {
+						log::warn!("lan discovery: failed to receive announcement: {err}");
+						continue
+					}
+				};
+				if handled >= config.max_announcements { continue }
+				handled += 1;
+
+				let Ok(Announcement { network_id, node_id: remote_id, mut addr }) = serde_json::from_slice(&data) else {
+					log::debug!("lan discovery: dropping invalid announcement from {from}");
+					continue
+				};
+				if network_id != config.network_id || remote_id == node_id { continue }
+				if last_connect.get(&remote_id).map_or(false, |at|at.elapsed() < config.reconnect_interval) { continue }
+				if addr.ip().is_unspecified() { addr.set_ip(from.ip()) }
+
+				log::info!("lan discovery: found {remote_id:?} at {addr}");
+				last_connect.insert(remote_id.clone(), Instant::now());
+				if actions.unbounded_send(NodeAction::AddPeer(remote_id, addr)).is_err() { break }
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::{SocketAddr, Ipv4Addr, SocketAddrV4}, time::Duration};
+
+	use futures::{channel::mpsc::unbounded, StreamExt};
+	use node::{NodeID, NodeAction};
+
+	use super::LanDiscoveryConfig;
+
+	// Two nodes on loopback announce themselves and each should be told to add the other
+	#[async_std::test]
+	async fn discover_loopback() {
+		// Pick a free port for the multicast group so that parallel test runs don't hear each other
+		let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+		let config = LanDiscoveryConfig {
+			enabled: true,
+			multicast_addr: SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 78), port),
+			interface: Ipv4Addr::LOCALHOST,
+			announce_interval: Duration::from_millis(100),
+			..Default::default()
+		};
+		let nodes = [(NodeID::hash(b"a"), SocketAddr::from(([127, 0, 0, 1], 1))), (NodeID::hash(b"b"), SocketAddr::from(([127, 0, 0, 1], 2)))];
+
+		let mut receivers = Vec::new();
+		for (node_id, addr) in &nodes {
+			let (sender, receiver) = unbounded();
+			super::spawn(config.clone(), node_id.clone(), *addr, sender);
+			receivers.push(receiver);
+		}
+		for (receiver, (other_id, other_addr)) in receivers.iter_mut().zip(nodes.iter().rev()) {
+			let action = async_std::future::timeout(Duration::from_secs(5), receiver.next()).await.expect("no announcement heard");
+			match action {
+				Some(NodeAction::AddPeer(id, addr)) => assert_eq!((&id, &addr), (other_id, other_addr)),
+				action => panic!("unexpected action: {action:?}"),
+			}
+		}
+	}
+}
@@ -6,19 +6,20 @@
 #![feature(type_alias_impl_trait)]
 #![feature(return_position_impl_trait_in_trait)]
 
-use std::{net::{Ipv4Addr, SocketAddr}, io::Write};
+use std::io::Write;
 
 use anyhow::anyhow;
 use async_std::task;
-use bevy_ecs::prelude::Entity;
-use futures::{SinkExt, StreamExt, FutureExt, channel::mpsc};
-use chumsky::prelude::*;
+use futures::{SinkExt, StreamExt, FutureExt, channel::mpsc, future::FusedFuture};
 
-use node::{NodeID, NodePacket, NodeAction, Node, NodeConfig, Network, EncryptionKeys};
+use node::{NodeID, NodeAction, Node, Network, NodeHandle};
 use rustyline_async::{Readline, ReadlineError, SharedWriter};
 
 mod net_tcp_noenc;
 use net_tcp_noenc::*;
+mod config;
+use config::Config;
+mod lan_discovery;
 
 type DitherNet = TcpNoenc;
 type Address = <DitherNet as Network>::Address;
@@ -27,32 +28,33 @@ type Address = <DitherNet as Network>::Address;
 async fn main() -> anyhow::Result<()> {
 	println!("Welcome to Dither (🖧 ), type help for command list");
 
-	// Parse listening addr
-	let listen_port: u16 = match std::env::args().nth(1).map(|s|s.parse()) {
-		Some(Ok(port)) => port,
-		None => return Ok(println!("Requires a port number as a command line argument")),
+	// Parse config file & listening port (port overrides the listen addresses in the config)
+	let (mut config, args) = Config::from_args()?;
+	match args.first().map(|s|s.parse::<u16>()) {
+		Some(Ok(port)) => config.set_port(port),
+		None if !config.listen_addrs.is_empty() => {},
+		None => return Ok(println!("Requires a port number as a command line argument or a config file with listen_addrs: libdither [port] [--config <path>]")),
 		Some(Err(err)) => return Ok(println!("Failed to parse port number: {err}"))
 	};
-	let listen_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), listen_port);
-
-	// Generate fake private key for testing because I haven't implemented encryption yet
-	let private_key = listen_addr.to_string().as_bytes().to_vec();
 
 	// Generate node_config
-	let node_config = NodeConfig::<DitherNet> {
-		keys: EncryptionKeys { private_key: private_key.clone(), public_key: private_key.clone() }, // WARN: Using private key as public key for testing purposes
-		node_id: NodeID::hash(&private_key),
-		listener_config: ListenerConfig::local(listen_port),
-	};
+	let node_config = config.node_config()?;
+	let node_id = node_config.node_id.clone();
 	// Create node & channels
 	let (event_sender, mut event_receiver) = mpsc::unbounded();
 	let node = Node::<DitherNet>::new(node_config, event_sender);
 	
 	let (mut action_sender, action_receiver) = mpsc::unbounded();
+	let handle = NodeHandle::new(action_sender.clone());
 
 	// Run node on separate task
 	let mut node_join = task::spawn(node.run(action_receiver)).fuse();
 
+	// Find nodes on the local network
+	if config.lan_discovery.enabled {
+		lan_discovery::spawn(config.lan_discovery.clone(), node_id, config.listen_addrs[0], action_sender.clone());
+	}
+
 	// Setup output through async_readline
 	let (mut rl, mut stdout) = Readline::new("> ".to_owned())?;
 	simplelog::WriteLogger::init(log::LevelFilter::Debug, simplelog::Config::default(), stdout.clone()).unwrap();
@@ -68,7 +70,7 @@ async fn main() -> anyhow::Result<()> {
 			command = rl.readline().fuse() => match command {
 				Ok(line) => {
 					rl.add_history_entry(line.clone());
-					if let Err(err) = handle_command(line, &mut action_sender, &mut stdout).await {
+					if let Err(err) = handle_command(line, &handle, &mut stdout).await {
 						writeln!(stdout, "Error: {}", err)?;
 					}
 				},
@@ -96,51 +98,90 @@ async fn main() -> anyhow::Result<()> {
 		}
     }
 
+	// Stop node if it is still running
+	if !node_join.is_terminated() {
+		println!("Shutting down node...");
+		handle.action(NodeAction::Shutdown)?;
+		if let Err(err) = node_join.await {
+			println!("node errored: {err}");
+		}
+	}
+
 	println!("Exiting...");
 	Ok(())
 }
 
 
 
-async fn handle_command(line: String, action_sender: &mut mpsc::UnboundedSender<NodeAction<DitherNet>>, stdout: &mut SharedWriter) -> anyhow::Result<()> {
+async fn handle_command(line: String, handle: &NodeHandle<DitherNet>, stdout: &mut SharedWriter) -> anyhow::Result<()> {
 	let mut split = line.split(" ");
 	let line = if let Some(split) = split.next() { split } else { return Ok(()) };
 	match line {
 		"connect" => {
 			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("Failed to parse NodeID"))??;
-			let addr = split.next().map(|s|s.parse::<Address>()).ok_or(anyhow!("Failed to parse Multiaddr"))??;
-			action_sender.send(NodeAction::Connect(node_id.clone(), addr, None)).await?;
-			writeln!(stdout, "Connecting to: {} ID: {:?}", addr, node_id)?;
+			// Remembered addresses are tried and then the DHT is queried if no address is passed
+			let addr = split.next().map(|s|s.parse::<Address>()).transpose()?;
+			handle.action(NodeAction::Connect(node_id.clone(), addr, None))?;
+			writeln!(stdout, "Connecting to: {:?} ID: {:?}", addr, node_id)?;
+		}
+		"lookup" => {
+			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("must pass a NodeID"))??;
+			handle.action(NodeAction::Lookup(node_id))?;
+		}
+		"punch" => {
+			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("must pass a NodeID"))??;
+			handle.action(NodeAction::HolePunch(node_id))?;
+		}
+		"relay" => {
+			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("must pass a NodeID"))??;
+			handle.action(NodeAction::ConnectRelayed(node_id))?;
+			// DitherNet sessions aren't encrypted, so neither are relayed ones
+			writeln!(stdout, "Warning: relayed sessions are not encrypted, the relay can read and alter everything sent over them")?;
+		}
+		"subscribe" => {
+			let topic = split.next().ok_or(anyhow!("must pass a topic"))?;
+			handle.action(NodeAction::Subscribe(topic.to_owned()))?;
+		}
+		"unsubscribe" => {
+			let topic = split.next().ok_or(anyhow!("must pass a topic"))?;
+			handle.action(NodeAction::Unsubscribe(topic.to_owned()))?;
+		}
+		"publish" => {
+			let topic = split.next().ok_or(anyhow!("must pass a topic"))?;
+			let data = split.remainder().ok_or(anyhow!("Data not passed"))?.as_bytes().to_vec();
+			handle.action(NodeAction::Publish(topic.to_owned(), data))?;
 		}
 		"list" => {
-			action_sender.send(NodeAction::GetInfo).await?;
+			let info = handle.info().await?;
+			writeln!(stdout, "{info:#?}")?;
 		}
 		"info" => {
-			let dec = text::int::<_, Simple<char>>(10).try_map(|s, span| s
-				.parse::<u32>()
-				.map_err(|e| Simple::custom(span, format!("not a valid u32: {e}"))));
-			let parser = dec.then_ignore(just('v')).then(dec).map(|(index, generation)| Entity::from_bits(index as u64 & ((generation as u64) << 32)));
-			
-			let to_parse = split.next().ok_or(anyhow!("must pass an Entity ID of format: <index>v<generation>"))?;
-			
-			let entity = parser.parse(to_parse).map_err(|err|anyhow!("parsing error: {}", err[0]))?;
-			log::debug!("Getting info of entity: {:?}", entity);
-			action_sender.send(NodeAction::GetRemoteInfo(entity)).await?;
+			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("must pass a NodeID"))??;
+			let info = handle.remote_info(node_id).await?;
+			writeln!(stdout, "{info:#?}")?;
 		}
 		"print" => {
-			action_sender.send(NodeAction::PrintNode).await?;
+			let snapshot = handle.snapshot().await?;
+			writeln!(stdout, "{}", serde_json::to_string_pretty(&snapshot)?)?;
 		}
 		"data" => {
 			let node_id = split.next().map(|s|s.parse::<NodeID>()).ok_or(anyhow!("Failed to parse NodeID"))??;
 			let data = split.remainder().ok_or(anyhow!("Data not passed"))?.as_bytes().to_vec();
 			
-			action_sender.send(NodeAction::ForwardPacket(node_id, NodePacket::Data(data))).await?;
+			handle.send(node_id, data).await?;
 		}
 		"help" => {
 			writeln!(stdout, r"
-connect <NodeID> <Address> - connect to remote device
-info - get info about this node
-action - wip, send node action
+connect <NodeID> [Address] - connect to remote device, remembered addresses and the DHT are tried if no address is passed
+lookup <NodeID> - look up the addresses of a node in the DHT
+punch <NodeID> - connect to a node behind a NAT through a peer both nodes are connected to
+relay <NodeID> - connect to a node through a peer that relays the connection (unencrypted, readable by the relay)
+subscribe <Topic> - receive messages published on a gossip topic
+unsubscribe <Topic> - stop receiving messages on a gossip topic
+publish <Topic> <String> - publish a message on a gossip topic
+list - get info about this node and list known remotes
+info <NodeID> - get info about a remote
+print - print node state
 data <NodeID> <String> - send arbitrary data to another node
 			")?
 		}
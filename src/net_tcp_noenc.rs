@@ -1,26 +1,25 @@
 //! Non-encrypted encryption TODO: Implement real encryption with noise protocol & perhaps https/tls
 
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::{net::SocketAddr, time::Duration, io::ErrorKind};
 use bevy_ecs::system::Resource;
 use rkyv::{AlignedVec, Infallible, Deserialize, to_bytes};
 use rkyv_codec::{RkyvCodecError, length_codec::U32Length};
+use socket2::{Socket, Domain, Type};
 use thiserror::Error;
 
 use async_std::{net::{TcpStream, TcpListener}, task};
-use futures::{StreamExt, channel::mpsc::{channel, self, unbounded, SendError, Sender}, SinkExt, FutureExt};
+use futures::{StreamExt, channel::{mpsc::{channel, self, unbounded, SendError, Sender}, oneshot}, SinkExt, FutureExt, AsyncRead, AsyncWrite, AsyncReadExt};
 
-use node::{NodeID, Connection, Network, EncryptionKeys};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use node::{NodeID, Connection, Network, EncryptionKeys, RelayStream};
 
 #[derive(Debug, Clone, Resource)]
 pub struct ListenerConfig {
 	listen_addrs: Vec<SocketAddr>,
 }
 impl ListenerConfig {
-	pub fn local(port: u16) -> Self {
-		let listen_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
-		Self {
-			listen_addrs: vec![listen_addr],
-		}
+	pub fn new(listen_addrs: Vec<SocketAddr>) -> Self {
+		Self { listen_addrs }
 	}
 }
 
@@ -32,9 +31,25 @@ enum NetRequest<Net: Network> {
 		remote_pub_key: Option<Net::NodePubKey>,
 		persistent_state: Option<Net::PersistentState>,
 	},
+	Punch {
+		remote_id: NodeID,
+		net_address: Net::Address,
+		delay: Duration,
+	},
+	Relayed {
+		remote_id: NodeID,
+		relay_address: Net::Address,
+		stream: RelayStream<Net>,
+		requested: bool,
+	},
 	Listen(Vec<SocketAddr>),
+	Shutdown,
 }
 
+/// Connections are either direct TCP streams or relayed circuits.
+type BoxedRead = Box<dyn AsyncRead + Unpin + Send + Sync>;
+type BoxedWrite = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
 #[derive(Clone, Debug, Resource)]
 pub struct TcpNoenc {
 	conn_req_sender: mpsc::UnboundedSender<NetRequest<Self>>, 
@@ -46,10 +61,85 @@ pub enum TcpNoencError {
 	IoError(#[from] std::io::Error),
 	#[error("codec error: {0}")]
 	CodecError(#[from] RkyvCodecError),
+	#[error("relayed connection to {expected:?} was answered by {found:?}")]
+	UnexpectedRemote { expected: NodeID, found: NodeID },
+}
+
+/// How long a probe waits for the remote to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a hole punch keeps trying to connect, SYNs are retransmitted in this time so the remote may dial a bit later.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sockets sharing the listening port must all allow address and port reuse
+fn reusable_socket(addr: SocketAddr) -> std::io::Result<Socket> {
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+	socket.set_reuse_address(true)?;
+	socket.set_reuse_port(true)?;
+	socket.bind(&addr.into())?;
+	Ok(socket)
+}
+
+// Bind listener to the first address that works, outgoing connections may reuse its port
+fn bind_listener(addrs: &[SocketAddr]) -> std::io::Result<TcpListener> {
+	let mut last_err = std::io::Error::new(ErrorKind::InvalidInput, "no listen address");
+	for addr in addrs {
+		let listener: std::io::Result<std::net::TcpListener> = try {
+			let socket = reusable_socket(*addr)?;
+			socket.listen(128)?;
+			socket.set_nonblocking(true)?;
+			socket.into()
+		};
+		match listener {
+			Ok(listener) => return Ok(TcpListener::from(listener)),
+			Err(err) => last_err = err,
+		}
+	}
+	Err(last_err)
+}
+
+// Connect from the given local address, which is shared with the listener
+async fn dial_from(local_addr: SocketAddr, net_address: SocketAddr, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+	let socket = reusable_socket(local_addr)?;
+	let tcp_stream = task::spawn_blocking(move || {
+		match timeout {
+			Some(timeout) => socket.connect_timeout(&net_address.into(), timeout)?,
+			None => socket.connect(&net_address.into())?,
+		}
+		socket.set_nonblocking(true)?;
+		Ok::<_, std::io::Error>(std::net::TcpStream::from(socket))
+	}).await?;
+	Ok(TcpStream::from(tcp_stream))
+}
+
+// Dial from the listening port so that remotes see the address a NAT maps to it, which is where hole punches are sent.
+// Falls back to any port if the listening port can't be used (i.e. there already is a connection to that address).
+async fn dial(listen_addr: Option<SocketAddr>, net_address: SocketAddr) -> std::io::Result<TcpStream> {
+	if let Some(listen_addr) = listen_addr.filter(|addr|addr.is_ipv4() == net_address.is_ipv4()) {
+		match dial_from(listen_addr, net_address, None).await {
+			Err(err) if matches!(err.kind(), ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable) => {
+				log::debug!("net: can't dial {net_address} from listening port: {err}");
+			}
+			result => return result,
+		}
+	}
+	TcpStream::connect(net_address).await
+}
+
+async fn send_public_key(stream: &mut (impl AsyncWrite + Unpin), public_key: &Vec<u8>) -> Result<(), TcpNoencError> {
+	let archived = to_bytes::<_, 64>(public_key).map_err(|_|RkyvCodecError::SerializeError)?;
+	rkyv_codec::archive_sink::<_, U32Length>(stream, &archived).await?;
+	Ok(())
+}
+async fn read_public_key(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, TcpNoencError> {
+	let mut buffer = AlignedVec::with_capacity(32);
+	let archive = rkyv_codec::archive_stream::<_, Vec<u8>, U32Length>(stream, &mut buffer).await?;
+	Ok(archive.deserialize(&mut Infallible).unwrap())
 }
 
 struct TcpNoencState {
 	conn_sender: Sender<Result<Connection<TcpNoenc>, TcpNoencError>>,
+	/// Connections opened by hole punches, handled by the listener task.
+	punched: mpsc::UnboundedSender<Result<(TcpStream, SocketAddr), TcpNoencError>>,
 	listener: TcpListener,
 	keys: EncryptionKeys<TcpNoenc>,
 }
@@ -59,13 +149,55 @@ impl TcpNoencState {
 			NetRequest::Connect { remote_id: _, net_address, remote_pub_key: _, persistent_state: _ } => {
 				// Connect to remote
 				let tcp_stream: Result<(TcpStream, SocketAddr), TcpNoencError> = try {
-					(TcpStream::connect(net_address).await?, net_address)
+					(dial(self.listener.local_addr().ok(), net_address).await?, net_address)
 				};
 				
 				self.handle_connection(tcp_stream, true).await?;
 			}
+			// Dial in the background so other requests aren't delayed
+			NetRequest::Punch { remote_id, net_address, delay } => {
+				let (punched, listen_addr) = (self.punched.clone(), self.listener.local_addr());
+				task::spawn(async move {
+					task::sleep(delay).await;
+					let tcp_stream = match listen_addr {
+						Ok(listen_addr) => dial_from(listen_addr, net_address, Some(PUNCH_TIMEOUT)).await,
+						Err(err) => Err(err),
+					};
+					match tcp_stream {
+						Ok(tcp_stream) => { let _ = punched.unbounded_send(Ok((tcp_stream, net_address))); }
+						// The remote's connection may have arrived at the listener first
+						Err(err) => log::info!("net: hole punch to {remote_id:?} at {net_address} failed: {err}"),
+					}
+				});
+			}
+			NetRequest::Shutdown => unreachable!("handled by listener task"),
+			// Exchange public keys over the circuit in the background, the relay is slower than a direct connection
+			NetRequest::Relayed { remote_id, relay_address, mut stream, requested } => {
+				let (mut conn_sender, public_key) = (self.conn_sender.clone(), self.keys.public_key.clone());
+				task::spawn(async move {
+					let conn_result: Result<Connection<TcpNoenc>, TcpNoencError> = try {
+						send_public_key(&mut stream, &public_key).await?;
+						let remote_pub_key = read_public_key(&mut stream).await?;
+						let found = NodeID::hash(&remote_pub_key);
+						if found != remote_id {
+							Err(TcpNoencError::UnexpectedRemote { expected: remote_id, found })?;
+						}
+						let (read, write) = stream.split();
+						Connection {
+							incoming_address: relay_address,
+							local_address: None,
+							remote_pub_key,
+							persistent_state: (),
+							read: Box::new(read) as BoxedRead,
+							write: Box::new(write) as BoxedWrite,
+							requested,
+						}
+					};
+					let _ = conn_sender.send(conn_result).await;
+				});
+			}
 			NetRequest::Listen(socket_addrs) => {
-				if let Ok(new_listener) = TcpListener::bind(&socket_addrs[..]).await {
+				if let Ok(new_listener) = bind_listener(&socket_addrs) {
 					log::info!("net: listening on new address: {socket_addrs:?}");
 					self.listener = new_listener;
 				} else {
@@ -80,20 +212,24 @@ impl TcpNoencState {
 			let (mut tcp_stream, net_address) = tcp_stream?;
 
 			// Send own public key to remote
-			let archived = to_bytes::<_, 64>(&self.keys.public_key).map_err(|_|RkyvCodecError::SerializeError)?;
-			rkyv_codec::archive_sink::<_, U32Length>(&mut tcp_stream, &archived).await?;
+			send_public_key(&mut tcp_stream, &self.keys.public_key).await?;
 
 			// Read remote public key from stream before passing back connection
-			let mut buffer = AlignedVec::with_capacity(32);
-			let archive = rkyv_codec::archive_stream::<_, Vec<u8>, U32Length>(&mut tcp_stream, &mut buffer).await?;
-			let remote_pub_key: Vec<u8> = archive.deserialize(&mut Infallible).unwrap();
+			let remote_pub_key = read_public_key(&mut tcp_stream).await?;
+
+			// Probes only check that this node can be connected to, don't start a session
+			if remote_pub_key.is_empty() && !requested {
+				log::debug!("net: answered probe from {net_address}");
+				return Ok(());
+			}
 
 			Connection {
 				incoming_address: net_address,
+				local_address: tcp_stream.local_addr().ok(),
 				remote_pub_key,
 				persistent_state: (),
-				read: tcp_stream.clone(),
-				write: tcp_stream,
+				read: Box::new(tcp_stream.clone()) as BoxedRead,
+				write: Box::new(tcp_stream) as BoxedWrite,
 				requested,
 			}
 		};
@@ -112,9 +248,9 @@ impl Network for TcpNoenc {
 
     type PersistentState = ();
 
-    type Read = TcpStream;
+    type Read = BoxedRead;
 
-    type Write = TcpStream;
+    type Write = BoxedWrite;
 
     type ConnectionError = TcpNoencError;
 
@@ -124,10 +260,12 @@ impl Network for TcpNoenc {
         let (request_sender, mut request_receiver) = unbounded::<NetRequest<Self>>();
 		
 		let (conn_sender, conn_stream) = channel::<Result<Connection<Self>, Self::ConnectionError>>(20);
+		let (punched, mut punched_receiver) = unbounded();
 
 		let mut state = TcpNoencState {
-			listener: TcpListener::bind(&listener_config.listen_addrs[..]).await?, // Bind listener to all listening addresses
+			listener: bind_listener(&listener_config.listen_addrs)?, // Bind listener to the first listening address that works
 			conn_sender,
+			punched,
 			keys,
 		};
 
@@ -136,11 +274,15 @@ impl Network for TcpNoenc {
 			loop {
 				let result: Result<(), Self::ConnectionError> = try {
 					futures::select! {
-						request = request_receiver.next().fuse() => if let Some(request) = request {
-							if let Err(err) = state.handle_request(request).await {
-								log::error!("net: connection sender closed: {err}");
+						request = request_receiver.next().fuse() => match request {
+							Some(NetRequest::Shutdown) | None => {
+								log::info!("net: shutting down");
 								break
 							}
+							Some(request) => if let Err(err) = state.handle_request(request).await {
+								log::error!("net: connection sender closed: {err}");
+								break
+							},
 						},
 						tcp_stream = state.listener.accept().fuse() => {
 							if let Err(err) = state.handle_connection(tcp_stream.map_err(TcpNoencError::from), false).await {
@@ -148,6 +290,12 @@ impl Network for TcpNoenc {
 								break
 							}
 						}
+						tcp_stream = punched_receiver.next() => if let Some(tcp_stream) = tcp_stream {
+							if let Err(err) = state.handle_connection(tcp_stream, true).await {
+								log::error!("net: connection sender closed: {err}");
+								break
+							}
+						},
 					}
 				};
 				
@@ -181,16 +329,119 @@ impl Network for TcpNoenc {
 		});
     }
 
+	fn punch(&self, remote_id: NodeID, net_address: Self::Address, delay: Duration) {
+		let _ = self.conn_req_sender.unbounded_send(NetRequest::Punch { remote_id, net_address, delay });
+	}
+
+	fn relayed(&self, remote_id: NodeID, relay_address: Self::Address, stream: RelayStream<Self>, requested: bool) {
+		let _ = self.conn_req_sender.unbounded_send(NetRequest::Relayed { remote_id, relay_address, stream, requested });
+	}
+
     fn listen(&self, addrs: impl Iterator<Item = Self::Address>) {
         let _ = self.conn_req_sender.unbounded_send(NetRequest::Listen(addrs.collect::<Vec<Self::Address>>()));
     }
 
+	fn shutdown(&self) {
+		let _ = self.conn_req_sender.unbounded_send(NetRequest::Shutdown);
+	}
+
+	// Sends an empty public key, the remote answers with its own public key and closes the connection
+	fn probe(&self, remote_id: NodeID, net_address: Self::Address) -> oneshot::Receiver<bool> {
+		let (reply, receiver) = oneshot::channel();
+		task::spawn(async move {
+			let probe = async {
+				let mut tcp_stream = TcpStream::connect(net_address).await?;
+				send_public_key(&mut tcp_stream, &Vec::new()).await?;
+				let remote_pub_key = read_public_key(&mut tcp_stream).await?;
+				Ok::<bool, TcpNoencError>(NodeID::hash(&remote_pub_key) == remote_id)
+			};
+			let reachable = match async_std::future::timeout(PROBE_TIMEOUT, probe).await {
+				Ok(Ok(reachable)) => reachable,
+				Ok(Err(err)) => { log::debug!("net: probe of {net_address} failed: {err}"); false }
+				Err(_) => { log::debug!("net: probe of {net_address} timed out"); false }
+			};
+			let _ = reply.send(reachable);
+		});
+		receiver
+	}
+
+	fn same_host(a: &Self::Address, b: &Self::Address) -> bool {
+		a.ip() == b.ip()
+	}
+
     fn predict_public_addresses<'a>(addr: &'a Self::Address, config: &'a Self::ListenerConfig) -> impl Iterator<Item = Self::Address> + 'a {
         config.listen_addrs.iter().map(|listen_addr| {
 			let mut addr = addr.clone();
 			addr.set_port(listen_addr.port());
 			addr
 		})
-    }	
+    }
+
+	// Ed25519 signature, the private key is the 32 byte seed of the signing key
+	fn sign(keys: &EncryptionKeys<Self>, data: &[u8]) -> Vec<u8> {
+		let Ok(seed) = <[u8; 32]>::try_from(keys.private_key.as_slice()) else {
+			log::error!("can't sign, private key is not an ed25519 seed");
+			return Vec::new();
+		};
+		SigningKey::from_bytes(&seed).sign(data).to_bytes().to_vec()
+	}
+
+	fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+		let Ok(public_key) = <[u8; 32]>::try_from(public_key) else { return false };
+		let (Ok(public_key), Ok(signature)) = (VerifyingKey::from_bytes(&public_key), Signature::from_slice(signature)) else { return false };
+		public_key.verify_strict(data, &signature).is_ok()
+	}
 }
 
+impl TcpNoenc {
+	/// Keys for the ed25519 signing key with the given seed, the NodeID is the hash of the public key.
+	pub fn keys_from_seed(seed: [u8; 32]) -> EncryptionKeys<TcpNoenc> {
+		let public_key = SigningKey::from_bytes(&seed).verifying_key().to_bytes().to_vec();
+		EncryptionKeys { private_key: seed.to_vec(), public_key }
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn keys(seed: u8) -> EncryptionKeys<TcpNoenc> {
+		TcpNoenc::keys_from_seed([seed; 32])
+	}
+
+	#[test]
+	fn sign_verify() {
+		let (keys_a, keys_b) = (keys(1), keys(2));
+		let signature = TcpNoenc::sign(&keys_a, b"record");
+		assert!(TcpNoenc::verify(&keys_a.public_key, b"record", &signature));
+		assert!(!TcpNoenc::verify(&keys_a.public_key, b"altered", &signature));
+		// A signature made with another key doesn't verify, even if the data and claimed public key are right
+		assert!(!TcpNoenc::verify(&keys_a.public_key, b"record", &TcpNoenc::sign(&keys_b, b"record")));
+	}
+
+	// Both nodes dial each other at the same time on loopback, each should end up with one connection to the other
+	#[async_std::test]
+	async fn punch_simultaneous_open() {
+		// Let the OS pick free ports, the listeners are bound to them again with address reuse
+		let free_addr = ||std::net::TcpListener::bind("127.0.0.1:0").and_then(|listener|listener.local_addr()).unwrap();
+		let (addr_a, addr_b) = (free_addr(), free_addr());
+		let (config_a, config_b) = (ListenerConfig::new(vec![addr_a]), ListenerConfig::new(vec![addr_b]));
+		let (keys_a, keys_b) = (keys(1), keys(2));
+		let (id_a, id_b) = (NodeID::hash(&keys_a.public_key), NodeID::hash(&keys_b.public_key));
+		let (pub_a, pub_b) = (keys_a.public_key.clone(), keys_b.public_key.clone());
+		let (net_a, mut conns_a) = TcpNoenc::init(keys_a, &config_a).await.unwrap();
+		let (net_b, mut conns_b) = TcpNoenc::init(keys_b, &config_b).await.unwrap();
+
+		net_a.punch(id_b, addr_b, Duration::ZERO);
+		net_b.punch(id_a, addr_a, Duration::ZERO);
+
+		let (conn_a, conn_b) = async_std::future::timeout(PUNCH_TIMEOUT, futures::future::join(conns_a.next(), conns_b.next())).await.unwrap();
+		let (conn_a, conn_b) = (conn_a.unwrap().unwrap(), conn_b.unwrap().unwrap());
+		assert_eq!(conn_a.remote_pub_key, pub_b);
+		assert_eq!(conn_b.remote_pub_key, pub_a);
+		// Outgoing connections use the listening port
+		assert_eq!(conn_a.incoming_address, addr_b);
+		assert_eq!(conn_b.incoming_address, addr_a);
+	}
+}
@@ -6,7 +6,7 @@
 #![feature(type_alias_impl_trait)]
 #![feature(return_position_impl_trait_in_trait)]
 
-use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+use std::time::Duration;
 use futures_delay_queue::delay_queue;
 use log::LevelFilter;
 use serde::{Serialize, Deserialize};
@@ -15,11 +15,14 @@ use anyhow::anyhow;
 use async_std::{task};
 use futures::{SinkExt, StreamExt, FutureExt, channel::mpsc::{self, UnboundedSender}};
 
-use node::{NodeID, NodeAction, Node, NodeConfig, Network, NodeEvent, EncryptionKeys};
+use node::{NodeAction, Node, Network, NodeEvent};
 
 mod net_tcp_noenc;
 use net_tcp_noenc::*;
-use simplelog::{Config, TerminalMode, TermLogger, ColorChoice};
+mod config;
+use config::Config;
+mod lan_discovery;
+use simplelog::{TerminalMode, TermLogger, ColorChoice};
 
 type DitherNet = TcpNoenc;
 type Address = <DitherNet as Network>::Address;
@@ -34,24 +37,25 @@ struct Command {
 async fn main() -> anyhow::Result<()> {
 	TermLogger::init(
         LevelFilter::Debug,
-        Config::default(),
+        simplelog::Config::default(),
         TerminalMode::Stdout,
         ColorChoice::Never
     )?;
 
-    let mut args = std::env::args();
-    let commands_path = args.nth(1).ok_or(anyhow!("requires command file"))?;
+    let (mut config, args) = Config::from_args()?;
+    let mut args = args.into_iter();
+    let commands_path = args.next().ok_or(anyhow!("requires command file"))?;
 
 	log::info!("This version of Dither is run in a simulator. Reading {commands_path:?} for commands");
 
-	// Parse listening addr
-	let port_string = args.next().clone();
-	let listen_port: u16 = match port_string.clone().map(|s|s.parse()) {
-		Some(Ok(port)) => port,
-		None => return Err(anyhow!("Requires a port number as a second command line argument")),
+	// Parse listening port (overrides the listen addresses in the config)
+	let port_string = args.next();
+	match port_string.clone().map(|s|s.parse::<u16>()) {
+		Some(Ok(port)) => config.set_port(port),
+		None if !config.listen_addrs.is_empty() => {},
+		None => return Err(anyhow!("Requires a port number as a second command line argument or a config file with listen_addrs")),
 		Some(Err(err)) => return Err(anyhow!("Failed to parse port number {:?}: {err}", port_string.clone()))
 	};
-	let listen_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), listen_port);
 
     // Open file & deserialize commands.
     let commands_file = std::fs::File::open(commands_path)?;
@@ -62,16 +66,9 @@ async fn main() -> anyhow::Result<()> {
         delay_queue.insert(command.action, command.time);
     }
 
-	// Generate fake private key for testing because I haven't implemented encryption yet
-	let private_key = listen_addr.to_string().as_bytes().to_vec();
-
 	// Generate node_config
-	let node_config = NodeConfig::<DitherNet> {
-		// WARN: Using private key as public key for testing purposes
-		keys: EncryptionKeys { private_key: private_key.clone(), public_key: private_key.clone() },
-		node_id: NodeID::hash(&private_key),
-		listener_config: net_tcp_noenc::ListenerConfig::local(listen_port),
-	};
+	let node_config = config.node_config()?;
+	let node_id = node_config.node_id.clone();
 	// Create node & channels
 	let (event_sender, mut event_receiver) = mpsc::unbounded();
 	let node = Node::<DitherNet>::new(node_config, event_sender);
@@ -81,6 +78,11 @@ async fn main() -> anyhow::Result<()> {
 	// Run node on separate task
 	let mut node_join = task::spawn(node.run(action_receiver)).fuse();
 
+	// Find nodes on the local network
+	if config.lan_discovery.enabled {
+		lan_discovery::spawn(config.lan_discovery.clone(), node_id, config.listen_addrs[0], action_sender.clone());
+	}
+
 	// Send NodeAction to check for errors in the bevy schedule
 	action_sender.send(NodeAction::GetInfo).await?;
 
@@ -110,10 +112,10 @@ async fn main() -> anyhow::Result<()> {
 
 fn handle_node_event<Net: Network>(action_sender: &UnboundedSender<NodeAction<Net>>, event: NodeEvent<Net>) {
 	match event {
-		NodeEvent::Info(id, addrs, coords, entities) => {
-			log::info!("Received Node Info: \nID: {id:?}\nAddrs: {addrs:?}\nCoords: {coords:?}\nEntities: {entities:?}");
-			for (_, entity) in entities {
-				action_sender.unbounded_send(NodeAction::GetRemoteInfo(entity)).unwrap();
+		NodeEvent::Info(info) => {
+			log::info!("Received Node Info: {info:#?}");
+			for remote_id in info.remotes {
+				action_sender.unbounded_send(NodeAction::GetRemoteInfo(remote_id)).unwrap();
 			}
 		}
 		event => log::info!("Received Node Event: {:#?}", event),